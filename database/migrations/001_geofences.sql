@@ -0,0 +1,49 @@
+CREATE TABLE IF NOT EXISTS geofences (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  name TEXT NOT NULL,
+  shape JSONB NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Last known inside/outside state of each device for each geofence.
+CREATE TABLE IF NOT EXISTS geofence_states (
+  geofence_id INT NOT NULL,
+  device_id VARCHAR ( 50 ) NOT NULL,
+  inside BOOLEAN NOT NULL,
+  PRIMARY KEY (geofence_id, device_id),
+  CONSTRAINT geofence_cst FOREIGN KEY(geofence_id) REFERENCES geofences(id) ON DELETE CASCADE
+);
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'geofence_transition') THEN
+        CREATE TYPE GEOFENCE_TRANSITION AS ENUM ('enter', 'exit');
+    END IF;
+END$$;
+
+CREATE TABLE IF NOT EXISTS geofence_events (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  geofence_id INT,
+  geofence_name TEXT NOT NULL,
+  device_id VARCHAR ( 50 ) NOT NULL,
+  transition GEOFENCE_TRANSITION NOT NULL,
+  time_id TIMESTAMP NOT NULL,
+  coords_x FLOAT NOT NULL,
+  coords_y FLOAT NOT NULL,
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE,
+  CONSTRAINT geofence_cst FOREIGN KEY(geofence_id) REFERENCES geofences(id) ON DELETE SET NULL
+);
+
+CREATE INDEX IF NOT EXISTS geofence_events_time_idx ON geofence_events (user_identifier, time_id);
+
+CREATE TABLE IF NOT EXISTS webhooks (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  url TEXT NOT NULL,
+  secret VARCHAR ( 64 ) NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,12 @@
+-- Materialized trips and stays computed from the raw points.
+CREATE TABLE IF NOT EXISTS segments (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  device_id VARCHAR ( 50 ) NOT NULL,
+  start_time TIMESTAMP NOT NULL,
+  end_time TIMESTAMP NOT NULL,
+  details JSONB NOT NULL,
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS segments_time_idx ON segments (user_identifier, start_time);
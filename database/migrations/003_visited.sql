@@ -0,0 +1,18 @@
+-- Countries and regions visited each day, computed from the points and the
+-- configured boundaries.
+CREATE TABLE IF NOT EXISTS visited_days (
+  user_identifier INT NOT NULL,
+  day DATE NOT NULL,
+  country TEXT NOT NULL,
+  region TEXT NOT NULL DEFAULT '',
+  PRIMARY KEY (user_identifier, day, country, region),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
+
+-- Days for which `visited_days` is up to date.
+CREATE TABLE IF NOT EXISTS visited_cache (
+  user_identifier INT NOT NULL,
+  day DATE NOT NULL,
+  PRIMARY KEY (user_identifier, day),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Speeds up the per-device queries, e.g. the last known location of each
+-- device.
+CREATE INDEX IF NOT EXISTS points_device_time ON points (user_identifier, user_id, time_id DESC);
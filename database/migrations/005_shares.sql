@@ -0,0 +1,22 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'share_scope') THEN
+        CREATE TYPE SHARE_SCOPE AS ENUM ('live', 'range');
+    END IF;
+END$$;
+
+-- Public links giving read-only access to a device's live position or to a
+-- fixed time range, until they expire or are revoked.
+CREATE TABLE IF NOT EXISTS shares (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  token VARCHAR(64) UNIQUE NOT NULL,
+  scope SHARE_SCOPE NOT NULL,
+  device_id VARCHAR(50),
+  start_time TIMESTAMP,
+  end_time TIMESTAMP,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  expires_at TIMESTAMP NOT NULL,
+  revoked BOOLEAN NOT NULL DEFAULT false,
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
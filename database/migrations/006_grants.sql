@@ -0,0 +1,21 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'grant_scope') THEN
+        CREATE TYPE GRANT_SCOPE AS ENUM ('live', 'history');
+    END IF;
+END$$;
+
+-- Permissions given by a user (the owner) to another user (the grantee) to
+-- see their locations.
+CREATE TABLE IF NOT EXISTS grants (
+  id SERIAL PRIMARY KEY,
+  owner_id INT NOT NULL,
+  grantee_id INT NOT NULL,
+  scope GRANT_SCOPE NOT NULL,
+  start_time TIMESTAMP,
+  end_time TIMESTAMP,
+  precision SMALLINT,
+  UNIQUE (owner_id, grantee_id),
+  CONSTRAINT owner_cst FOREIGN KEY(owner_id) REFERENCES users(id) ON DELETE CASCADE,
+  CONSTRAINT grantee_cst FOREIGN KEY(grantee_id) REFERENCES users(id) ON DELETE CASCADE
+);
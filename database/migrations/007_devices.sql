@@ -0,0 +1,35 @@
+-- The devices of each user. `device_id` is the identifier sent by Overland,
+-- `name` is a display name that can be changed.
+CREATE TABLE IF NOT EXISTS devices (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  device_id VARCHAR(50) NOT NULL,
+  name TEXT NOT NULL,
+  description TEXT,
+  color VARCHAR(32),
+  input_token VARCHAR(64) UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  last_seen TIMESTAMP,
+  retired_at TIMESTAMP,
+  UNIQUE (user_identifier, device_id),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
+
+ALTER TABLE points ADD COLUMN IF NOT EXISTS device INT;
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'device_cst') THEN
+        ALTER TABLE points ADD CONSTRAINT device_cst FOREIGN KEY(device) REFERENCES devices(id) ON DELETE CASCADE;
+    END IF;
+END$$;
+CREATE INDEX IF NOT EXISTS points_device ON points (device);
+
+-- Create the devices of the existing points.
+INSERT INTO devices (user_identifier, device_id, name, last_seen)
+  SELECT user_identifier, user_id, user_id, MAX(time_id) FROM points
+  WHERE user_identifier IS NOT NULL AND user_id IS NOT NULL
+  GROUP BY user_identifier, user_id
+  ON CONFLICT DO NOTHING;
+UPDATE points SET device=devices.id FROM devices
+  WHERE points.device IS NULL AND devices.user_identifier=points.user_identifier
+  AND devices.device_id=points.user_id;
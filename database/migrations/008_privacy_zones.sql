@@ -0,0 +1,18 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'privacy_action') THEN
+        CREATE TYPE PRIVACY_ACTION AS ENUM ('drop', 'snap', 'hide');
+    END IF;
+END$$;
+
+-- Areas where the points of a user are dropped, snapped to the center of the
+-- area, or hidden from the other users.
+CREATE TABLE IF NOT EXISTS privacy_zones (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  name TEXT NOT NULL,
+  shape JSONB NOT NULL,
+  action PRIVACY_ACTION NOT NULL,
+  created TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,26 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'retention_mode') THEN
+        CREATE TYPE RETENTION_MODE AS ENUM ('delete', 'summarize', 'simplify');
+    END IF;
+END$$;
+
+-- Per user retention policy, the server settings are used when NULL.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS retention_days INT;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS retention_mode RETENTION_MODE;
+
+-- Points already kept by the simplification of an expired track.
+ALTER TABLE points ADD COLUMN IF NOT EXISTS simplified BOOLEAN NOT NULL DEFAULT false;
+
+-- What is left of the expired days of a device once their points are removed.
+CREATE TABLE IF NOT EXISTS daily_summaries (
+  user_identifier INT NOT NULL,
+  device_id VARCHAR ( 50 ) NOT NULL,
+  day DATE NOT NULL,
+  points BIGINT NOT NULL,
+  first TIMESTAMP NOT NULL,
+  last TIMESTAMP NOT NULL,
+  distance FLOAT8 NOT NULL,
+  PRIMARY KEY (user_identifier, device_id, day),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
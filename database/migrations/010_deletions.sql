@@ -0,0 +1,17 @@
+-- Points deleted by a user are only flagged with their deletion, which can be
+-- undone until it expires. Removing the deletion removes its points.
+CREATE TABLE IF NOT EXISTS deletions (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
+
+ALTER TABLE points ADD COLUMN IF NOT EXISTS deletion INT;
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'deletion_cst') THEN
+        ALTER TABLE points ADD CONSTRAINT deletion_cst FOREIGN KEY(deletion) REFERENCES deletions(id) ON DELETE CASCADE;
+    END IF;
+END$$;
+CREATE INDEX IF NOT EXISTS points_deletion ON points (deletion) WHERE deletion IS NOT NULL;
@@ -0,0 +1,19 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'takeout_status') THEN
+        CREATE TYPE TAKEOUT_STATUS AS ENUM ('pending', 'ready', 'failed');
+    END IF;
+END$$;
+
+-- Personal data archives, generated in the background.
+CREATE TABLE IF NOT EXISTS takeouts (
+  id SERIAL PRIMARY KEY,
+  user_identifier INT NOT NULL,
+  status TAKEOUT_STATUS NOT NULL DEFAULT 'pending',
+  file TEXT,
+  error TEXT,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  finished_at TIMESTAMP,
+  expires_at TIMESTAMP NOT NULL,
+  CONSTRAINT user_cst FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,8 @@
+-- Deleting a user removes all their data. The first tables were created
+-- without cascading foreign keys.
+ALTER TABLE points DROP CONSTRAINT IF EXISTS user_cst;
+ALTER TABLE points ADD CONSTRAINT user_cst
+  FOREIGN KEY(user_identifier) REFERENCES users(id) ON DELETE CASCADE;
+ALTER TABLE input_tokens DROP CONSTRAINT IF EXISTS user_cst;
+ALTER TABLE input_tokens ADD CONSTRAINT user_cst
+  FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE;
@@ -0,0 +1,10 @@
+-- Login sessions. Only a hash of the cookie is stored.
+CREATE TABLE IF NOT EXISTS sessions (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  last_used TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  expires_at TIMESTAMP NOT NULL,
+  CONSTRAINT user_cst FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- Details shown in the list of the sessions of a user.
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN IF NOT EXISTS ip TEXT;
@@ -0,0 +1,7 @@
+-- Input tokens can be labelled and revoked, and their use is tracked.
+UPDATE input_tokens SET valid=true WHERE valid IS NULL;
+ALTER TABLE input_tokens ALTER COLUMN valid SET DEFAULT true;
+ALTER TABLE input_tokens ALTER COLUMN valid SET NOT NULL;
+ALTER TABLE input_tokens ADD COLUMN IF NOT EXISTS label TEXT;
+ALTER TABLE input_tokens ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
+ALTER TABLE input_tokens ADD COLUMN IF NOT EXISTS last_used TIMESTAMP;
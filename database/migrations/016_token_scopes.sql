@@ -0,0 +1,10 @@
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'token_scope') THEN
+        CREATE TYPE TOKEN_SCOPE AS ENUM ('ingest', 'read', 'admin');
+    END IF;
+END$$;
+
+-- The existing tokens are the ones of the Overland app: they can only send
+-- points from now on, like the new ones unless asked otherwise.
+ALTER TABLE input_tokens ADD COLUMN IF NOT EXISTS scope TOKEN_SCOPE NOT NULL DEFAULT 'ingest';
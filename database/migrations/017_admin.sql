@@ -0,0 +1,6 @@
+-- Administrators can disable users, who can then neither log in nor use their
+-- tokens.
+UPDATE users SET is_admin=false WHERE is_admin IS NULL;
+ALTER TABLE users ALTER COLUMN is_admin SET DEFAULT false;
+ALTER TABLE users ALTER COLUMN is_admin SET NOT NULL;
+ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT false;
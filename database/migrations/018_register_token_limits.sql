@@ -0,0 +1,21 @@
+-- Registration tokens can expire, be used several times, be reserved to a
+-- username, and remember who issued them.
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS expires_at TIMESTAMP;
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS max_uses INT NOT NULL DEFAULT 1;
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS uses INT NOT NULL DEFAULT 0;
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS username TEXT;
+ALTER TABLE register_tokens ADD COLUMN IF NOT EXISTS created_by INT;
+
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM information_schema.columns
+               WHERE table_name = 'register_tokens' AND column_name = 'used') THEN
+        UPDATE register_tokens SET uses=1 WHERE used;
+        ALTER TABLE register_tokens DROP COLUMN used;
+    END IF;
+    IF NOT EXISTS (SELECT 1 FROM pg_constraint WHERE conname = 'register_token_creator_cst') THEN
+        ALTER TABLE register_tokens ADD CONSTRAINT register_token_creator_cst
+          FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL;
+    END IF;
+END$$;
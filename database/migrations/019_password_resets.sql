@@ -0,0 +1,12 @@
+-- One-time password reset links issued by the administrators. Only a hash of
+-- the token is stored.
+CREATE TABLE IF NOT EXISTS password_resets (
+  id SERIAL PRIMARY KEY,
+  user_id INT NOT NULL,
+  token_hash VARCHAR(64) NOT NULL UNIQUE,
+  created_by INT,
+  created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+  expires_at TIMESTAMP NOT NULL,
+  CONSTRAINT user_cst FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE,
+  CONSTRAINT creator_cst FOREIGN KEY(created_by) REFERENCES users(id) ON DELETE SET NULL
+);
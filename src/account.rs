@@ -0,0 +1,193 @@
+/// Account deletion. Deleting a user removes all their data: the foreign keys
+/// cascade from the users table, and the files stored outside of the database
+/// are removed alongside.
+use crate::auth::{client_ip, expired_cookie, CurrentUser, SharedPdb};
+use crate::settings::{self, Settings};
+use crate::HtmlTemplate;
+use askama::Template;
+use axum::extract::{ConnectInfo, Form, Path};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::Deserialize;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::net::SocketAddr;
+
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    username: String,
+    is_admin: bool,
+}
+
+#[derive(Template)]
+#[template(path = "account_deleted.html")]
+struct AccountDeletedTemplate {}
+
+/// The account deletion form. The username must be typed again.
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccount {
+    confirm: String,
+    password: String,
+}
+
+/// Deletes a user and all their data. Returns `false` if there is no such
+/// user.
+pub(crate) async fn remove_user(
+    pool: &PgPool,
+    takeout: &settings::Takeout,
+    user_id: i32,
+) -> sqlx::Result<bool> {
+    let mut tx = pool.begin().await?;
+    let files: Vec<String> = sqlx::query_scalar!(
+        r#"SELECT file AS "file!" FROM takeouts WHERE user_identifier=$1 AND file IS NOT NULL"#,
+        user_id
+    )
+    .fetch_all(&mut tx)
+    .await?;
+    let res = sqlx::query!(r#"DELETE FROM users WHERE id=$1"#, user_id)
+        .execute(&mut tx)
+        .await?;
+    tx.commit().await?;
+    for file in files {
+        let path = std::path::Path::new(&takeout.dir).join(file);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            tracing::warn!("error removing {}: {e}", path.display());
+        }
+    }
+    Ok(res.rows_affected() > 0)
+}
+
+/// The account page of the current user.
+pub async fn serve_account(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let username = sqlx::query_scalar!(
+        r#"SELECT username FROM users WHERE id=$1"#,
+        current_user.user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(HtmlTemplate(AccountTemplate {
+        username,
+        is_admin: current_user.is_admin,
+    }))
+}
+
+/// The method called by the account deletion form. Deletes the current user
+/// once their username and password are confirmed, and logs them out.
+pub async fn delete_account(
+    Form(form): Form<DeleteAccount>,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(pdb): Extension<SharedPdb>,
+    Extension(takeout): Extension<settings::Takeout>,
+    Extension(current_user): Extension<CurrentUser>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let username = sqlx::query_scalar!(
+        r#"SELECT username FROM users WHERE id=$1"#,
+        current_user.user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(internal_error)?;
+    if form.confirm != username {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The username does not match".to_string(),
+        ));
+    }
+    let ip = client_ip(&headers, peer.map(|ConnectInfo(peer)| peer));
+    pdb.confirm_password(&username, &form.password, ip.as_deref())
+        .await?;
+    remove_user(&pool, &takeout, current_user.user_id)
+        .await
+        .map_err(internal_error)?;
+    tracing::info!("user {username} deleted their account");
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::SET_COOKIE,
+        header::HeaderValue::from_str(&expired_cookie()).unwrap(),
+    );
+    Ok((headers, HtmlTemplate(AccountDeletedTemplate {})))
+}
+
+/// The body of the administrator deletion request. The username of the
+/// deleted user must be typed again.
+#[derive(Deserialize, Debug)]
+pub struct AdminDeleteUser {
+    confirm: String,
+}
+
+/// API method for an administrator to delete a user and all their data.
+/// Administrators cannot delete themselves, so that one always remains.
+pub async fn admin_delete_user(
+    Path(user_id): Path<i32>,
+    Json(body): Json<AdminDeleteUser>,
+    Extension(pool): Extension<PgPool>,
+    Extension(takeout): Extension<settings::Takeout>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !current_user.is_admin {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only administrators can delete users".to_string(),
+        ));
+    }
+    if user_id == current_user.user_id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "You cannot delete yourself from the administration page".to_string(),
+        ));
+    }
+    let username = sqlx::query_scalar!(r#"SELECT username FROM users WHERE id=$1"#, user_id)
+        .fetch_optional(&pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "No such user".to_string()))?;
+    if body.confirm != username {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The username does not match".to_string(),
+        ));
+    }
+    let deleted = remove_user(&pool, &takeout, user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if !deleted {
+        return Err((StatusCode::NOT_FOUND, "No such user".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes a user and all their data from the command line. Asks to type the
+/// username again unless `yes` is set.
+pub async fn delete_user(username: &str, yes: bool) -> Result<(), sqlx::Error> {
+    let settings = Settings::new().unwrap();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.");
+
+    let user_id = sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_one(&pool)
+        .await?;
+    if !yes {
+        let mut confirm = String::new();
+        println!("This deletes {username} and all their data. Type the username to confirm:");
+        std::io::stdin().read_line(&mut confirm).unwrap();
+        if confirm.trim_end() != username {
+            println!("Aborted");
+            return Ok(());
+        }
+    }
+    remove_user(&pool, &settings.takeout, user_id).await?;
+    println!("Deleted {username}");
+    Ok(())
+}
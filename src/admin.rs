@@ -0,0 +1,357 @@
+/// The administration console: the users of the instance, their creation,
+/// disabling and password resets, the registration tokens and the health of
+/// the server. Everything here is restricted to administrators.
+use crate::api::serialize_optional_timestamp;
+use crate::auth::{end_sessions, CurrentUser, SharedPdb};
+use crate::register_token::{
+    insert_register_token, load_register_tokens, remove_register_token, NewRegisterToken,
+    RegisterToken,
+};
+use crate::HtmlTemplate;
+use askama::Template;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use std::time::Instant;
+
+#[derive(Template)]
+#[template(path = "admin.html")]
+struct AdminTemplate {}
+
+/// When the server started, to report its uptime.
+#[derive(Clone, Copy, Debug)]
+pub struct ServerStart(pub Instant);
+
+/// A user as seen by the administrators.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct AdminUser {
+    id: i32,
+    username: String,
+    is_admin: bool,
+    /// Disabled users can neither log in nor use their tokens.
+    disabled: bool,
+    points: i64,
+    /// The time of the latest point sent by one of their devices.
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    last_ingest: Option<PrimitiveDateTime>,
+}
+
+/// The body of a user creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewUser {
+    username: String,
+    password: String,
+    #[serde(default)]
+    is_admin: bool,
+}
+
+/// The body of a user update request. Missing fields are left unchanged.
+#[derive(Deserialize, Debug)]
+pub struct UserUpdate {
+    is_admin: Option<bool>,
+    disabled: Option<bool>,
+}
+
+/// The body of a password reset request.
+#[derive(Deserialize, Debug)]
+pub struct PasswordReset {
+    password: String,
+}
+
+/// Counters about the content of the database.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct DatabaseStats {
+    users: i64,
+    points: i64,
+    active_sessions: i64,
+    pending_takeouts: i64,
+    /// The size of the database, in bytes.
+    size: i64,
+}
+
+/// The health of the server.
+#[derive(Serialize, Debug)]
+pub struct Health {
+    version: &'static str,
+    uptime_seconds: u64,
+    /// Whether the database answers.
+    database: bool,
+    stats: Option<DatabaseStats>,
+}
+
+fn require_admin(current_user: &CurrentUser) -> Result<(), (StatusCode, String)> {
+    if current_user.is_admin {
+        Ok(())
+    } else {
+        Err((
+            StatusCode::FORBIDDEN,
+            "Only administrators can do this".to_string(),
+        ))
+    }
+}
+
+async fn load_users(pool: &PgPool, user_id: Option<i32>) -> sqlx::Result<Vec<AdminUser>> {
+    sqlx::query_as!(
+        AdminUser,
+        r#"SELECT id, username, is_admin, disabled,
+           (SELECT COUNT(*) FROM points WHERE user_identifier=users.id) AS "points!",
+           (SELECT MAX(last_seen) FROM devices WHERE user_identifier=users.id) AS last_ingest
+           FROM users WHERE $1::INT IS NULL OR id=$1 ORDER BY id"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// The administration console.
+pub async fn serve_admin(
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    require_admin(&current_user)?;
+    Ok(HtmlTemplate(AdminTemplate {}))
+}
+
+/// API method to list all the users, with their number of points and their
+/// last ingest.
+pub async fn list_users(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<AdminUser>>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let users = load_users(&pool, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(users)))
+}
+
+/// API method to create a user without a registration token.
+pub async fn create_user(
+    Json(new_user): Json<NewUser>,
+    Extension(pool): Extension<PgPool>,
+    Extension(pdb): Extension<SharedPdb>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<AdminUser>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    if new_user.username.is_empty() || new_user.password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The username and password cannot be empty".to_string(),
+        ));
+    }
+    let taken = sqlx::query_scalar!(
+        r#"SELECT id FROM users WHERE username=$1"#,
+        new_user.username
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(internal_error)?;
+    if taken.is_some() {
+        return Err((StatusCode::CONFLICT, "Username already taken".to_string()));
+    }
+    let user_id = pdb
+        .create_user(
+            new_user.username.clone(),
+            new_user.password,
+            new_user.is_admin,
+        )
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error creating the user".to_string(),
+            )
+        })?;
+    tracing::info!("user {} created by an administrator", new_user.username);
+    let user = load_users(&pool, Some(user_id))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or((StatusCode::NOT_FOUND, "No such user".to_string()))?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+/// API method to disable or enable a user, or to change their role. Disabling
+/// a user ends their sessions. Administrators cannot lock themselves out.
+pub async fn update_user(
+    Path(user_id): Path<i32>,
+    Json(update): Json<UserUpdate>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<AdminUser>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    if user_id == current_user.user_id
+        && (update.disabled == Some(true) || update.is_admin == Some(false))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "You cannot disable yourself or drop your own administrator role".to_string(),
+        ));
+    }
+    let res = sqlx::query!(
+        r#"UPDATE users SET is_admin=COALESCE($2, is_admin), disabled=COALESCE($3, disabled)
+           WHERE id=$1"#,
+        user_id,
+        update.is_admin,
+        update.disabled
+    )
+    .execute(&pool)
+    .await
+    .map_err(internal_error)?;
+    if res.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "No such user".to_string()));
+    }
+    if update.disabled == Some(true) {
+        end_sessions(&pool, user_id, None)
+            .await
+            .map_err(internal_error)?;
+    }
+    let user = load_users(&pool, Some(user_id))
+        .await
+        .map_err(internal_error)?
+        .pop()
+        .ok_or((StatusCode::NOT_FOUND, "No such user".to_string()))?;
+    Ok((StatusCode::OK, Json(user)))
+}
+
+/// API method to set a new password for a user. Their sessions are ended.
+pub async fn reset_password(
+    Path(user_id): Path<i32>,
+    Json(reset): Json<PasswordReset>,
+    Extension(pool): Extension<PgPool>,
+    Extension(pdb): Extension<SharedPdb>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&current_user)?;
+    if reset.password.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The password cannot be empty".to_string(),
+        ));
+    }
+    let updated = pdb
+        .set_password(user_id, reset.password)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error changing the password".to_string(),
+            )
+        })?;
+    if !updated {
+        return Err((StatusCode::NOT_FOUND, "No such user".to_string()));
+    }
+    end_sessions(&pool, user_id, None)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    tracing::info!("password of user {user_id} reset by an administrator");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// API method to list the registration tokens.
+pub async fn list_register_tokens(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<RegisterToken>>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let tokens = load_register_tokens(&pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+/// API method to issue a registration token, shared as an invite link.
+pub async fn create_register_token(
+    Json(new_token): Json<NewRegisterToken>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<RegisterToken>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    new_token
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let token = insert_register_token(&pool, new_token, Some(current_user.user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+/// API method to remove a registration token, so that its invite link stops
+/// working.
+pub async fn delete_register_token(
+    Path(id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let deleted = remove_register_token(&pool, id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if deleted {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((
+            StatusCode::NOT_FOUND,
+            "No such registration token".to_string(),
+        ))
+    }
+}
+
+/// API method reporting the health of the server. It answers even when the
+/// database does not.
+pub async fn health(
+    Extension(pool): Extension<PgPool>,
+    Extension(start): Extension<ServerStart>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Health>), (StatusCode, String)> {
+    require_admin(&current_user)?;
+    let stats = sqlx::query_as!(
+        DatabaseStats,
+        r#"SELECT (SELECT COUNT(*) FROM users) AS "users!",
+           (SELECT COUNT(*) FROM points) AS "points!",
+           (SELECT COUNT(*) FROM sessions
+            WHERE expires_at > (NOW() AT TIME ZONE 'utc')) AS "active_sessions!",
+           (SELECT COUNT(*) FROM takeouts WHERE status='pending') AS "pending_takeouts!",
+           pg_database_size(current_database()) AS "size!""#
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| tracing::error!("error checking the database: {e}"))
+    .ok();
+    Ok((
+        StatusCode::OK,
+        Json(Health {
+            version: env!("CARGO_PKG_VERSION"),
+            uptime_seconds: start.0.elapsed().as_secs(),
+            database: stats.is_some(),
+            stats,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+
+    #[test]
+    fn should_restrict_to_administrators() {
+        let mut current_user = CurrentUser {
+            user_id: 1,
+            is_admin: false,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        assert_eq!(
+            require_admin(&current_user).unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+        current_user.is_admin = true;
+        assert!(require_admin(&current_user).is_ok());
+    }
+}
@@ -1,13 +1,21 @@
 /// The API module contains all the API method implementations for the REST
 /// server.
 use super::auth::CurrentUser;
+use crate::devices::{resolve_device, touch_device};
+use crate::geocoder::{Place, SharedGeocoder};
+use crate::geofence::GeofenceTracker;
+use crate::grants::{resolve_access, GrantScope, UserQuery};
+use crate::live::{LiveFeed, LivePoint};
+use crate::privacy::PrivacyZones;
+use crate::visited::invalidate_days;
+use crate::webhook::WebhookSender;
 use axum::extract::Query;
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
 use axum::{Extension, Json};
 use serde::{
     de::{self, Deserializer},
-    {Deserialize, Serialize},
+    Serializer, {Deserialize, Serialize},
 };
 use serde_json::Value;
 use sqlx::Row;
@@ -15,10 +23,49 @@ use sqlx::{
     postgres::{PgPool, PgRow},
     types::time::{Date, PrimitiveDateTime},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::FromStr;
 use std::time::Duration;
+use time::format_description::FormatItem;
 use time::macros::format_description;
+use time::OffsetDateTime;
+
+const TIMESTAMP_FORMAT: &[FormatItem<'static>] =
+    format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]Z");
+
+/// Formats a timestamp the same way Overland does, e.g. `2022-05-01T12:00:00Z`.
+pub(crate) fn format_timestamp(ts: &PrimitiveDateTime) -> String {
+    ts.format(TIMESTAMP_FORMAT).unwrap()
+}
+
+/// Serde helper to serialize database timestamps with `format_timestamp`.
+pub(crate) fn serialize_timestamp<S: Serializer>(
+    ts: &PrimitiveDateTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format_timestamp(ts))
+}
+
+/// Serde helper to serialize optional database timestamps.
+pub(crate) fn serialize_optional_timestamp<S: Serializer>(
+    ts: &Option<PrimitiveDateTime>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match ts {
+        Some(ts) => serialize_timestamp(ts, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Parses a query parameter that is either a date (`2022-05-01`) or an
+/// Overland timestamp (`2022-05-01T12:00:00Z`).
+pub(crate) fn parse_datetime(input: &str) -> Option<PrimitiveDateTime> {
+    PrimitiveDateTime::parse(input, TIMESTAMP_FORMAT)
+        .or_else(|_| {
+            Date::parse(input, format_description!("[year]-[month]-[day]")).map(Date::midnight)
+        })
+        .ok()
+}
 
 /// The DataObj enum represents the objects received by a client containing the
 /// locations of the user.
@@ -97,6 +144,10 @@ pub struct TripProps {
 #[derive(sqlx::FromRow, Serialize, Deserialize, Debug, Default)]
 #[serde(rename = "position")]
 pub struct LocProps {
+    /// Identifier of the stored point, only filled in query results.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    id: Option<i32>,
     #[serde(rename = "device_id")]
     user_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -117,6 +168,10 @@ pub struct LocProps {
     #[serde(skip_serializing_if = "Option::is_none")]
     vertical_accuracy: Option<i32>,
     wifi: String,
+    /// Nearest locality, only filled in query results when requested.
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    locality: Option<sqlx::types::Json<Place>>,
 }
 
 /// The `BatteryState` returned from the Overland app can take a few values.
@@ -135,14 +190,20 @@ pub enum BatteryState {
     Unplugged,
 }
 
-#[derive(Serialize, Deserialize, Debug, sqlx::Type)]
+/// The motion types detected by the Overland app.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, sqlx::Type)]
 #[serde(rename_all = "lowercase")]
 #[sqlx(rename_all = "lowercase")]
-enum Motion {
+pub enum Motion {
+    /// In a car or another motorized vehicle.
     Driving,
+    /// Not moving.
     Stationary,
+    /// Walking.
     Walking,
+    /// Running.
     Running,
+    /// Cycling.
     Cycling,
 }
 
@@ -161,6 +222,15 @@ impl FromStr for Motion {
     }
 }
 
+/// Parses the `motion` column of the points table. Motions are stored as a
+/// comma separated list of JSON strings, e.g. `"walking","running"`.
+pub(crate) fn parse_motions(motion_string: &str) -> Vec<Motion> {
+    motion_string
+        .split(',')
+        .filter_map(|x| Motion::from_str(x.trim_matches('"')).ok())
+        .collect()
+}
+
 /// A geometry object that contains geometric properties of a GeoJSON object.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
@@ -185,10 +255,11 @@ pub struct OverlandResponse {
 }
 
 /// A time period interval for the `GeoQuery::Interval` object.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 #[serde(rename_all = "lowercase")]
 pub enum TimePeriod {
     /// One day time period.
+    #[default]
     Day,
     /// One week time period.
     Week,
@@ -196,12 +267,6 @@ pub enum TimePeriod {
     Month,
 }
 
-impl Default for TimePeriod {
-    fn default() -> Self {
-        Self::Day
-    }
-}
-
 /// An enum representing the required result type for the returned data from a
 /// query.
 #[derive(Deserialize, Debug, Default)]
@@ -241,6 +306,9 @@ pub enum GeoQuery {
         /// Results can be required to be in any of the `ResultType`.
         #[serde(default)]
         result_type: ResultType,
+        /// Annotate GeoJSON results with the nearest locality of each point.
+        #[serde(default, deserialize_with = "deserialize_flag")]
+        geocode: bool,
     },
     /// An single day geo query type.
     Date {
@@ -252,6 +320,12 @@ pub enum GeoQuery {
     },
 }
 
+/// Query strings inside untagged enums are only seen as strings by serde, so
+/// booleans have to be parsed by hand.
+fn deserialize_flag<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    bool::from_str(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
 fn geoquery_to_primitive_datetime(
     geo_query: GeoQuery,
 ) -> (PrimitiveDateTime, PrimitiveDateTime, ResultType) {
@@ -271,6 +345,7 @@ fn geoquery_to_primitive_datetime(
             start,
             end,
             result_type,
+            ..
         } => {
             let start_dt = Date::parse(&start, formatter).unwrap().midnight();
             let end_dt = Date::parse(&end, formatter).unwrap().midnight();
@@ -368,73 +443,332 @@ impl IntoResponse for QueryPointResponse {
     }
 }
 
-fn filter_results(current_user: CurrentUser, first: bool) -> String {
-    if current_user.is_admin {
-        "".to_string()
-    } else if first {
-        format!("WHERE user_identifier={}", current_user.user_id)
-    } else {
-        format!("AND user_identifier={}", current_user.user_id)
-    }
+/// Query parameters of the available dates API.
+#[derive(Deserialize, Debug, Default)]
+pub struct AvailableQuery {
+    /// Return a summary of each day instead of the bare dates.
+    #[serde(default)]
+    details: bool,
+    /// Only consider the points of this device.
+    device: Option<String>,
+    /// Only consider the points of this year.
+    year: Option<i32>,
+}
+
+/// Summary of the points recorded on a single day.
+#[derive(Serialize, Debug)]
+pub struct DaySummary {
+    /// The day, e.g. `2022-05-01`.
+    day: String,
+    /// Number of points recorded on that day.
+    points: i64,
+    /// Timestamp of the first point of the day.
+    #[serde(serialize_with = "serialize_timestamp")]
+    first: PrimitiveDateTime,
+    /// Timestamp of the last point of the day.
+    #[serde(serialize_with = "serialize_timestamp")]
+    last: PrimitiveDateTime,
+    /// Devices that sent points on that day.
+    devices: Vec<String>,
+    /// Approximate distance travelled in meters, summed over all the devices.
+    distance: f64,
+}
+
+/// The response of the available dates API, either the bare dates or a
+/// summary of each day.
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub enum Available {
+    /// The days with at least one point.
+    Dates(Vec<String>),
+    /// A summary of the days with at least one point.
+    Details(Vec<DaySummary>),
 }
 
 /// API method to get the dates with existing data for a specific user.
 pub async fn available(
+    Query(query): Query<AvailableQuery>,
+    Query(user_query): Query<UserQuery>,
     Extension(pool): Extension<PgPool>,
     Extension(current_user): Extension<CurrentUser>,
-) -> Result<(StatusCode, Json<Vec<String>>), (StatusCode, String)> {
+) -> Result<(StatusCode, Json<Available>), (StatusCode, String)> {
+    let access = resolve_access(
+        &pool,
+        &current_user,
+        user_query.user.as_deref(),
+        GrantScope::History,
+    )
+    .await?;
     let formatter = format_description!("[year]-[month]-[day]");
-    let res: Vec<Date> = sqlx::query(&format!(
-        r#"SELECT DISTINCT DATE(time_id) AS single_day FROM points {};"#,
-        filter_results(current_user, true)
+    let filters = format!(
+        r#"WHERE time_id IS NOT NULL AND ($1::TEXT IS NULL OR user_id=$1)
+           AND ($2::INT IS NULL OR EXTRACT(YEAR FROM time_id)=$2) {}"#,
+        access.filter(false)
+    );
+    if !query.details {
+        let res: Vec<Date> = sqlx::query(&format!(
+            r#"SELECT DISTINCT DATE(time_id) AS single_day FROM (
+                SELECT user_identifier, user_id, time_id FROM points WHERE deletion IS NULL
+                UNION ALL
+                SELECT user_identifier, device_id, first FROM daily_summaries
+               ) AS days {filters}
+               ORDER BY single_day;"#,
+        ))
+        .bind(&query.device)
+        .bind(query.year)
+        .map(|row: PgRow| -> sqlx::Result<sqlx::types::time::Date> { row.try_get("single_day") })
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .into_iter()
+        .collect::<sqlx::Result<Vec<Date>>>()
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+        let formatted_dates = res
+            .iter()
+            .map(|dt| (*dt).format(&formatter))
+            .collect::<Result<Vec<String>, time::error::Format>>()
+            .unwrap();
+        return Ok((StatusCode::OK, Json(Available::Dates(formatted_dates))));
+    }
+
+    let to_summary = |row: PgRow| -> sqlx::Result<DaySummary> {
+        let day: Date = row.try_get("single_day")?;
+        Ok(DaySummary {
+            day: day.format(&formatter).unwrap(),
+            points: row.try_get("points")?,
+            first: row.try_get("first")?,
+            last: row.try_get("last")?,
+            devices: row.try_get("devices")?,
+            distance: row.try_get("distance")?,
+        })
+    };
+    // The distance is the sum of the haversine distances between consecutive
+    // points of each device, computed in the database to avoid loading every
+    // point.
+    let summaries = sqlx::query(&format!(
+        r#"WITH ordered AS (
+            SELECT user_id, time_id, coords_x, coords_y,
+                LAG(coords_x) OVER w AS prev_x, LAG(coords_y) OVER w AS prev_y
+            FROM points {filters} AND deletion IS NULL
+            WINDOW w AS (PARTITION BY user_identifier, user_id, DATE(time_id) ORDER BY time_id)
+        )
+        SELECT DATE(time_id) AS single_day, COUNT(*) AS points, MIN(time_id) AS first,
+            MAX(time_id) AS last,
+            ARRAY_REMOVE(ARRAY_AGG(DISTINCT user_id), NULL) AS devices,
+            COALESCE(SUM(2 * 6371008.8 * ASIN(LEAST(1, SQRT(
+                POWER(SIN(RADIANS(coords_y - prev_y) / 2), 2)
+                + COS(RADIANS(prev_y)) * COS(RADIANS(coords_y))
+                * POWER(SIN(RADIANS(coords_x - prev_x) / 2), 2)))))
+                FILTER (WHERE prev_x IS NOT NULL AND prev_y IS NOT NULL), 0)::FLOAT8 AS distance
+        FROM ordered GROUP BY single_day ORDER BY single_day;"#,
     ))
-    .map(|row: PgRow| -> sqlx::Result<sqlx::types::time::Date> {
-        let tget = row.try_get("single_day");
-        tget
-    })
+    .bind(&query.device)
+    .bind(query.year)
+    .map(to_summary)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .collect::<sqlx::Result<Vec<DaySummary>>>()
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    // Days whose points expired only have a summary left.
+    let archived = sqlx::query(&format!(
+        r#"SELECT DATE(time_id) AS single_day, SUM(points)::BIGINT AS points,
+            MIN(time_id) AS first, MAX(last) AS last, ARRAY_AGG(DISTINCT user_id) AS devices,
+            SUM(distance)::FLOAT8 AS distance
+        FROM (
+            SELECT user_identifier, device_id AS user_id, first AS time_id, last, points, distance
+            FROM daily_summaries
+        ) AS summaries {filters} GROUP BY single_day;"#,
+    ))
+    .bind(&query.device)
+    .bind(query.year)
+    .map(to_summary)
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .collect::<sqlx::Result<Vec<DaySummary>>>()
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mut days: BTreeMap<String, DaySummary> = BTreeMap::new();
+    for summary in summaries.into_iter().chain(archived) {
+        match days.get_mut(&summary.day) {
+            Some(day) => {
+                day.points += summary.points;
+                day.first = day.first.min(summary.first);
+                day.last = day.last.max(summary.last);
+                day.distance += summary.distance;
+                for device in summary.devices {
+                    if !day.devices.contains(&device) {
+                        day.devices.push(device);
+                    }
+                }
+            }
+            None => {
+                days.insert(summary.day.clone(), summary);
+            }
+        }
+    }
+    Ok((
+        StatusCode::OK,
+        Json(Available::Details(days.into_values().collect())),
+    ))
+}
+
+/// Query parameters of the latest location API.
+#[derive(Deserialize, Debug)]
+pub struct LatestQuery {
+    /// Devices without a point in this many seconds are flagged as stale.
+    max_age: Option<i64>,
+}
+
+/// The last known location of a device.
+#[derive(Serialize, Debug)]
+pub struct LatestPoint {
+    /// The device identifier.
+    device: String,
+    /// Timestamp of the fix.
+    #[serde(serialize_with = "serialize_timestamp")]
+    time: PrimitiveDateTime,
+    /// Coordinates of the fix as `[longitude, latitude]`.
+    coordinates: [f64; 2],
+    /// Altitude in meters.
+    altitude: Option<i16>,
+    /// Speed in meters per second.
+    speed: Option<i32>,
+    /// Battery level between 0 and 1.
+    battery_level: Option<f32>,
+    /// Battery state.
+    battery_state: Option<BatteryState>,
+    /// Name of the wifi network the device was connected to.
+    wifi: Option<String>,
+    /// Motion types detected by the device.
+    motion: Vec<Motion>,
+    /// Age of the fix in seconds.
+    age: i64,
+    /// Whether the fix is older than the requested `max_age`.
+    stale: bool,
+}
+
+/// API method to get the most recent point of each device of the current
+/// user.
+pub async fn latest(
+    Query(query): Query<LatestQuery>,
+    Query(user_query): Query<UserQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<LatestPoint>>), (StatusCode, String)> {
+    let access = resolve_access(
+        &pool,
+        &current_user,
+        user_query.user.as_deref(),
+        GrantScope::Live,
+    )
+    .await?;
+    let user_id = access.user_id.unwrap_or(current_user.user_id);
+    let zones = PrivacyZones::load(&pool, Some(user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let now = OffsetDateTime::now_utc();
+    let now = PrimitiveDateTime::new(now.date(), now.time());
+    let points = sqlx::query!(
+        r#"SELECT DISTINCT ON (user_id) user_id, time_id AS "time_id!", altitude, speed,
+           motion, battery AS "battery: BatteryState", battery_level, wifi,
+           coords_x AS "coords_x!", coords_y AS "coords_y!"
+           FROM points WHERE user_identifier=$1 AND time_id IS NOT NULL AND deletion IS NULL
+           AND coords_x IS NOT NULL AND coords_y IS NOT NULL
+           AND ($2::TIMESTAMP IS NULL OR time_id >= $2)
+           AND ($3::TIMESTAMP IS NULL OR time_id < $3)
+           ORDER BY user_id, time_id DESC"#,
+        user_id,
+        access.start,
+        access.end
+    )
     .fetch_all(&pool)
     .await
-    .unwrap()
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
     .into_iter()
-    .collect::<sqlx::Result<Vec<Date>>>()
-    .unwrap();
-    let formatted_dates = res
-        .iter()
-        .map(|dt| (*dt).format(&formatter))
-        .collect::<Result<Vec<String>, time::error::Format>>()
-        .unwrap();
-    Ok((StatusCode::OK, Json(formatted_dates)))
+    .filter_map(|row| {
+        let coordinates = zones.apply(
+            user_id,
+            [row.coords_x, row.coords_y],
+            user_id == current_user.user_id,
+        )?;
+        let age = (now - row.time_id).whole_seconds();
+        Some(LatestPoint {
+            device: row.user_id,
+            time: row.time_id,
+            coordinates: access.round(coordinates),
+            altitude: row.altitude,
+            speed: row.speed,
+            battery_level: row.battery_level,
+            battery_state: row.battery,
+            wifi: row
+                .wifi
+                .map(|wifi| wifi.trim_end().to_string())
+                .filter(|wifi| !wifi.is_empty()),
+            motion: row.motion.as_deref().map(parse_motions).unwrap_or_default(),
+            age,
+            stale: query.max_age.is_some_and(|max_age| age > max_age),
+        })
+    })
+    .collect();
+    Ok((StatusCode::OK, Json(points)))
 }
 
 /// API method to query positions from the database for a specific user.
 pub async fn query_points(
     Query(geo_query): Query<GeoQuery>,
+    Query(user_query): Query<UserQuery>,
     Extension(pool): Extension<PgPool>,
+    Extension(geocoder): Extension<SharedGeocoder>,
     Extension(current_user): Extension<CurrentUser>,
 ) -> Result<(StatusCode, QueryPointResponse), (StatusCode, String)> {
+    let access = resolve_access(
+        &pool,
+        &current_user,
+        user_query.user.as_deref(),
+        GrantScope::History,
+    )
+    .await?;
+    let geocoder = match geo_query {
+        GeoQuery::Interval { geocode: true, .. } => geocoder,
+        _ => None,
+    };
+    let zones = PrivacyZones::load(&pool, access.user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     let (t_start, t_end, result_type) = geoquery_to_primitive_datetime(geo_query);
     let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
     let request = format!(
-        r#"SELECT user_id, time_id, altitude, speed, motion, battery, battery_level,
-            wifi, coords_x, coords_y FROM points WHERE time_id BETWEEN TO_TIMESTAMP('{}',
+        r#"SELECT pt_id, user_identifier, user_id, time_id, altitude, speed, motion, battery,
+            battery_level, wifi, coords_x, coords_y FROM points WHERE deletion IS NULL
+            AND time_id BETWEEN TO_TIMESTAMP('{}',
             'YYYY-MM-DD HH24:MI:SS') AND TO_TIMESTAMP('{}',
             'YYYY-MM-DD HH24:MI:SS') {};"#,
         t_start.format(&format).unwrap(),
         t_end.format(&format).unwrap(),
-        filter_results(current_user, false)
+        access.filter(false)
     );
     let format_date = format_description!("[year]-[month]-[day]");
     let res: Vec<DataObj> = sqlx::query(&request)
-        .map(|row: PgRow| -> sqlx::Result<DataObj> {
+        .map(|row: PgRow| -> sqlx::Result<Option<DataObj>> {
             let ts: PrimitiveDateTime = row.try_get("time_id")?;
             let wifi_name: String = row.try_get("wifi")?;
             let motion_string: String = row.try_get("motion")?;
-            let motions = motion_string
-                .split(',')
-                .filter_map(|x| Motion::from_str(x).ok())
-                .collect();
-            Ok(DataObj::Feature {
+            let motions = parse_motions(&motion_string);
+            let owner: i32 = row.try_get("user_identifier")?;
+            let Some(coordinates) = zones.apply(
+                owner,
+                [row.try_get("coords_x")?, row.try_get("coords_y")?],
+                owner == current_user.user_id,
+            ) else {
+                return Ok(None);
+            };
+            let [coords_x, coords_y] = access.round(coordinates);
+            Ok(Some(DataObj::Feature {
                 properties: Props::LocProps(LocProps {
+                    id: row.try_get("pt_id")?,
                     user_id: row.try_get("user_id")?,
                     timestamp: format!(
                         "{}T{:02}:{:02}:{:02}Z",
@@ -451,17 +785,21 @@ pub async fn query_points(
                     wifi: wifi_name.trim().to_string(),
                     horizontal_accuracy: None,
                     vertical_accuracy: None,
+                    locality: geocoder
+                        .as_ref()
+                        .and_then(|g| g.nearest([coords_x, coords_y]))
+                        .map(sqlx::types::Json),
                 }),
                 geometry: Geom::Point {
-                    coordinates: [row.try_get("coords_x")?, row.try_get("coords_y")?],
+                    coordinates: [coords_x, coords_y],
                 },
-            })
+            }))
         })
         .fetch_all(&pool)
         .await
         .unwrap()
         .into_iter()
-        .filter_map(|x| if let Ok(c) = x { Some(c) } else { None })
+        .filter_map(|x| x.ok().flatten())
         .collect();
     match result_type {
         ResultType::GeoJSON => Ok((StatusCode::OK, QueryPointResponse::GeoJSON(Json(res)))),
@@ -477,20 +815,19 @@ async fn insert_item(
     props: &LocProps,
     pool: &PgPool,
     current_user: &CurrentUser,
+    (device, device_id): (i32, &str),
 ) -> sqlx::Result<sqlx::postgres::PgQueryResult> {
     let point = match geometry {
         Geom::Point { coordinates } => coordinates,
     };
-    let format = format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]Z");
-    let offsetdt = PrimitiveDateTime::parse(&props.timestamp, format).unwrap();
-    let user_id = props.user_id.clone().unwrap_or_default();
+    let offsetdt = PrimitiveDateTime::parse(&props.timestamp, TIMESTAMP_FORMAT).unwrap();
 
     sqlx::query!(
         r#"INSERT INTO points (
             user_id, time_id, altitude, speed, motion,
-            battery, battery_level, wifi, coords_x, coords_y, user_identifier)
-            VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11 )"#,
-        user_id,
+            battery, battery_level, wifi, coords_x, coords_y, user_identifier, device)
+            VALUES ( $1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12 )"#,
+        device_id,
         offsetdt,
         props.altitude,
         props.speed,
@@ -505,7 +842,8 @@ async fn insert_item(
         props.wifi,
         point[0],
         point[1],
-        current_user.user_id
+        current_user.user_id,
+        device
     )
     .execute(pool)
     .await
@@ -516,6 +854,8 @@ async fn insert_item(
 pub async fn add_points(
     body: String,
     Extension(pool): Extension<PgPool>,
+    Extension(webhooks): Extension<WebhookSender>,
+    Extension(live_feed): Extension<LiveFeed>,
     Extension(current_user): Extension<CurrentUser>,
 ) -> Result<(StatusCode, Json<OverlandResponse>), (StatusCode, String)> {
     let p: Locations = serde_json::from_str(&body).map_err(|e| {
@@ -525,7 +865,18 @@ pub async fn add_points(
             format!("Error parsing data {e}\n Request content: {body}"),
         )
     })?;
+    let mut tracker = GeofenceTracker::load(&pool, current_user.user_id)
+        .await
+        .map_err(|e| tracing::error!("error loading geofences: {e}"))
+        .ok();
+    // Never store points that should have been dropped.
+    let zones = PrivacyZones::load(&pool, Some(current_user.user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
     let mut inserted = 0;
+    let mut days = HashSet::new();
+    let mut devices = HashMap::new();
+    let mut last_seen: HashMap<i32, PrimitiveDateTime> = HashMap::new();
     for data_obj in p.locations.iter() {
         match data_obj {
             DataObj::Feature {
@@ -533,14 +884,74 @@ pub async fn add_points(
                 properties,
             } => {
                 if let Props::LocProps(props) = properties {
-                    match insert_item(geometry, props, &pool, &current_user).await {
+                    let device_id = props.user_id.clone().unwrap_or_default();
+                    if !devices.contains_key(&device_id) {
+                        let device = resolve_device(&pool, &current_user, &device_id)
+                            .await
+                            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+                        devices.insert(device_id.clone(), device);
+                    }
+                    let Some((device, device_id)) = devices[&device_id].clone() else {
+                        tracing::debug!("ignoring point of retired device {device_id}");
+                        continue;
+                    };
+                    let Geom::Point { coordinates } = geometry;
+                    let Some(coordinates) = zones.apply(current_user.user_id, *coordinates, true)
+                    else {
+                        continue;
+                    };
+                    let geometry = Geom::Point { coordinates };
+                    match insert_item(&geometry, props, &pool, &current_user, (device, &device_id))
+                        .await
+                    {
                         Ok(_) => inserted += 1,
-                        Err(e) => tracing::debug!("error inserting item: {e}"),
+                        Err(e) => {
+                            tracing::debug!("error inserting item: {e}");
+                            continue;
+                        }
+                    }
+                    let time =
+                        PrimitiveDateTime::parse(&props.timestamp, TIMESTAMP_FORMAT).unwrap();
+                    days.insert(time.date());
+                    let seen = last_seen.entry(device).or_insert(time);
+                    *seen = (*seen).max(time);
+                    live_feed.publish(LivePoint {
+                        user_id: current_user.user_id,
+                        hidden: zones
+                            .apply(current_user.user_id, coordinates, false)
+                            .is_none(),
+                        device: device_id.clone(),
+                        time,
+                        coordinates,
+                        altitude: props.altitude,
+                        speed: props.speed,
+                        battery_level: props.battery_level,
+                        battery_state: props.battery_state,
+                        motion: props.motion.clone(),
+                    });
+                    if let Some(tracker) = tracker.as_mut() {
+                        if let Err(e) = tracker
+                            .record(&pool, &webhooks, &device_id, coordinates, time)
+                            .await
+                        {
+                            tracing::error!("error updating geofences: {e}");
+                        }
                     }
                 }
             }
         }
     }
+    for (device, time) in last_seen {
+        if let Err(e) = touch_device(&pool, device, time).await {
+            tracing::error!("error updating the device last seen time: {e}");
+        }
+    }
+    if !days.is_empty() {
+        let days: Vec<_> = days.into_iter().collect();
+        if let Err(e) = invalidate_days(&pool, current_user.user_id, &days).await {
+            tracing::error!("error invalidating the visited places: {e}");
+        }
+    }
     Ok((
         StatusCode::OK,
         Json(OverlandResponse {
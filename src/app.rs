@@ -1,9 +1,37 @@
-use crate::api::{add_points, available, query_points};
+use crate::account::{admin_delete_user, delete_account, serve_account};
+use crate::admin::{
+    create_register_token, create_user, delete_register_token, health, list_register_tokens,
+    list_users, reset_password, serve_admin, update_user, ServerStart,
+};
+use crate::api::{add_points, available, latest, query_points};
+use crate::auth::{
+    auth_middleware, check_username_password, insert_username_password, list_sessions, logout,
+    revoke_session, revoke_sessions, serve_login, serve_register,
+};
 use crate::auth::{
-    auth_middleware, check_username_password, insert_username_password, serve_login,
+    spawn_session_cleanup, spawn_throttle_cleanup, PasswordDatabase, SessionTimeouts, SharedPdb,
+    ThrottleSettings,
+};
+use crate::deletion::{delete_point, delete_points, list_deletions, restore_deletion};
+use crate::devices::{
+    create_device, list_devices, regenerate_device_token, retire_device, update_device,
 };
-use crate::auth::{new_shared_db, SharedPdb};
-use crate::settings::Settings;
+use crate::geocoder::{reverse_geocode, Geocoder, SharedGeocoder};
+use crate::geofence::{create_geofence, delete_geofence, list_events, list_geofences};
+use crate::grants::{create_grant, delete_grant, list_grants};
+use crate::live::{live, LiveFeed};
+use crate::password::{
+    change_password, create_reset_link, reset_password_with_link, serve_reset_password,
+};
+use crate::privacy::{create_privacy_zone, delete_privacy_zone, list_privacy_zones};
+use crate::retention::{get_retention, set_retention, spawn_purge_task};
+use crate::segments::query_segments;
+use crate::settings::{self, Retention, Settings};
+use crate::share::{create_share, list_shares, revoke_share, serve_share, share_feed};
+use crate::takeout::{create_takeout, download_takeout, list_takeouts, spawn_takeout_cleanup};
+use crate::tokens::{create_token, list_tokens, revoke_token, rotate_token, update_token};
+use crate::visited::{visited, Boundaries, SharedBoundaries};
+use crate::webhook::{create_webhook, delete_webhook, list_webhooks, WebhookSender};
 use crate::{handle_static_error, HtmlTemplate};
 use askama::Template;
 use axum::{
@@ -11,12 +39,14 @@ use axum::{
     http::StatusCode,
     middleware,
     response::IntoResponse,
-    routing::{get, get_service, post},
+    routing::{delete, get, get_service, patch, post},
     Extension, Router,
 };
 use sqlx::postgres::PgPoolOptions;
 use sqlx::PgPool;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
 use tower::ServiceBuilder;
 use tower_http::{services::ServeDir, trace::TraceLayer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
@@ -43,19 +73,44 @@ pub async fn run_server() -> Result<(), sqlx::Error> {
 
     sqlx::migrate!("database/migrations").run(&pool).await?;
 
-    let shared_pdb = new_shared_db(&pool);
+    let webhooks = WebhookSender::new(&pool, &settings.webhooks);
+    let geocoder: SharedGeocoder = settings.geocoder.as_ref().map(|geocoder_settings| {
+        Arc::new(Geocoder::load(geocoder_settings).expect("Cannot load the geocoder files."))
+    });
+    let boundaries: SharedBoundaries = settings.boundaries.as_ref().map(|boundaries_settings| {
+        Arc::new(Boundaries::load(boundaries_settings).expect("Cannot load the boundary files."))
+    });
+    let session_timeouts = SessionTimeouts::from(&settings.auth);
+    let mut pdb = PasswordDatabase::new(&pool);
+    pdb.set_session_timeouts(session_timeouts);
+    pdb.set_query_tokens(settings.auth.query_tokens);
+    pdb.set_throttle_settings(ThrottleSettings::from(&settings.auth));
     if settings.auth.develop {
         tracing::warn!("Development mode should not be used in production!");
-        let pdb_clone = shared_pdb.clone();
-        let mut pdb_lock = pdb_clone.lock().await;
-        pdb_lock.set_develop();
+        pdb.set_develop();
     }
+    let shared_pdb: SharedPdb = Arc::new(pdb);
+    spawn_session_cleanup(pool.clone(), session_timeouts);
+    spawn_throttle_cleanup(shared_pdb.clone());
+    spawn_purge_task(pool.clone(), settings.retention.clone());
+    spawn_takeout_cleanup(pool.clone(), settings.takeout.clone());
 
     let addr = SocketAddr::from(([127, 0, 0, 1], 18032));
     tracing::debug!("listening on {}", addr);
 
     axum::Server::bind(&addr)
-        .serve(app(pool, shared_pdb).into_make_service())
+        .serve(
+            app(
+                pool,
+                shared_pdb,
+                webhooks,
+                geocoder,
+                boundaries,
+                settings.retention,
+                settings.takeout,
+            )
+            .into_make_service_with_connect_info::<SocketAddr>(),
+        )
         .await
         .unwrap();
     Ok(())
@@ -69,21 +124,101 @@ struct WelcomTemplate {}
 #[template(path = "map.html")]
 struct MapTemplate {}
 
-#[derive(Template)]
-#[template(path = "register.html")]
-struct RegisterTemplate {}
-
-fn app(pool: PgPool, shared_pdb: SharedPdb) -> Router {
+fn app(
+    pool: PgPool,
+    shared_pdb: SharedPdb,
+    webhooks: WebhookSender,
+    geocoder: SharedGeocoder,
+    boundaries: SharedBoundaries,
+    retention: Retention,
+    takeout: settings::Takeout,
+) -> Router {
+    // The app is built once, when the server starts.
+    let start = ServerStart(Instant::now());
     let api_routes = Router::new()
         .route("/query", get(query_points))
         .route("/input", post(add_points))
+        .route("/points", delete(delete_points))
+        .route("/points/:id", delete(delete_point))
+        .route("/deletions", get(list_deletions))
+        .route("/deletions/:id/restore", post(restore_deletion))
         .route("/available", get(available))
+        .route("/latest", get(latest))
+        .route("/live", get(live))
+        .route("/geofences", get(list_geofences).post(create_geofence))
+        .route("/geofences/:id", delete(delete_geofence))
+        .route("/events", get(list_events))
+        .route("/segments", get(query_segments))
+        .route("/geocode", get(reverse_geocode))
+        .route("/visited", get(visited))
+        .route("/webhooks", get(list_webhooks).post(create_webhook))
+        .route("/webhooks/:id", delete(delete_webhook))
+        .route("/devices", get(list_devices).post(create_device))
+        .route("/devices/:id", patch(update_device).delete(retire_device))
+        .route("/devices/:id/token", post(regenerate_device_token))
+        .route(
+            "/privacy-zones",
+            get(list_privacy_zones).post(create_privacy_zone),
+        )
+        .route("/privacy-zones/:id", delete(delete_privacy_zone))
+        .route("/retention", get(get_retention).put(set_retention))
+        .route("/grants", get(list_grants).post(create_grant))
+        .route("/grants/:id", delete(delete_grant))
+        .route("/shares", get(list_shares).post(create_share))
+        .route("/shares/:id", delete(revoke_share))
+        .route("/takeout", get(list_takeouts).post(create_takeout))
+        .route("/takeout/:id/download", get(download_takeout))
+        .route("/users", get(list_users).post(create_user))
+        .route("/users/:id", patch(update_user).delete(admin_delete_user))
+        .route("/users/:id/password", post(reset_password))
+        .route("/users/:id/reset-link", post(create_reset_link))
+        .route(
+            "/register-tokens",
+            get(list_register_tokens).post(create_register_token),
+        )
+        .route("/register-tokens/:id", delete(delete_register_token))
+        .route("/health", get(health))
+        .route("/sessions", get(list_sessions).delete(revoke_sessions))
+        .route("/sessions/:id", delete(revoke_session))
+        .route("/tokens", get(list_tokens).post(create_token))
+        .route("/tokens/:id", patch(update_token).delete(revoke_token))
+        .route("/tokens/:id/rotate", post(rotate_token))
+        .layer(Extension(webhooks))
+        .layer(Extension(LiveFeed::new()))
+        .layer(Extension(geocoder))
+        .layer(Extension(boundaries))
+        .layer(Extension(retention))
+        .layer(Extension(takeout.clone()))
+        .layer(Extension(start))
+        .layer(Extension(shared_pdb.clone()))
+        .layer(Extension(pool.clone()));
+    let account_routes = Router::new()
+        .route("/", get(serve_account))
+        .route("/delete", post(delete_account))
+        .route("/password", post(change_password))
+        .layer(Extension(takeout))
+        .layer(Extension(shared_pdb.clone()))
+        .layer(Extension(pool.clone()));
+    // Share links are public, the handlers check the token themselves.
+    let share_routes = Router::new()
+        .route("/:token", get(serve_share))
+        .route("/:token/feed", get(share_feed))
+        .layer(Extension(pool.clone()));
+    let logout_routes = Router::new()
+        .route("/", post(logout))
         .layer(Extension(pool));
     let login_routes = Router::new()
         .route("/", get(serve_login).post(check_username_password))
         .layer(Extension(shared_pdb.clone()));
-    let register_routes =
-        Router::new().nest("/", get(|| async { HtmlTemplate(RegisterTemplate {}) }));
+    let register_routes = Router::new()
+        .route("/", get(serve_register))
+        .layer(Extension(shared_pdb.clone()));
+    let reset_password_routes = Router::new()
+        .route(
+            "/",
+            get(serve_reset_password).post(reset_password_with_link),
+        )
+        .layer(Extension(shared_pdb.clone()));
     let add_user_routes = Router::new()
         .route("/", post(insert_username_password))
         .layer(Extension(shared_pdb.clone()));
@@ -93,13 +228,18 @@ fn app(pool: PgPool, shared_pdb: SharedPdb) -> Router {
     Router::new()
         .nest("/map", map_routes)
         .nest("/api", api_routes)
+        .nest("/account", account_routes)
+        .route("/admin", get(serve_admin))
         .route_layer(middleware::from_fn(move |req, next| {
             auth_middleware(req, next, shared_pdb.clone())
         }))
         .route("/", get(|| async { HtmlTemplate(WelcomTemplate {}) }))
         .nest("/login", login_routes)
+        .nest("/logout", logout_routes)
         .nest("/register", register_routes)
         .nest("/add_user", add_user_routes)
+        .nest("/reset-password", reset_password_routes)
+        .nest("/share", share_routes)
         .nest(
             "/public",
             get_service(ServeDir::new("./static")).handle_error(handle_static_error),
@@ -1,12 +1,13 @@
 use crate::{
     auth::{
-        middleware::{random_cookie, CookieSession},
+        middleware::{client_ip, random_cookie},
+        throttle::retry_after_secs,
         SharedPdb, COOKIE_NAME,
     }, HtmlTemplate,
 };
 use askama::Template;
 use axum::{
-    extract::{Form, Query},
+    extract::{ConnectInfo, Form, Query},
     http::{
         header::{self, HeaderMap},
         StatusCode,
@@ -15,6 +16,7 @@ use axum::{
     Extension,
 };
 use serde::Deserialize;
+use std::net::SocketAddr;
 
 #[derive(Debug)]
 pub enum LoginError {
@@ -57,25 +59,53 @@ pub async fn check_username_password(
     form: Form<LogIn>,
     Extension(pdb): Extension<SharedPdb>,
     Query(url_query): Query<RedirectUrlQuery>,
+    request_headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
 ) -> impl IntoResponse {
     let log_in: LogIn = form.0;
+    let user_agent = request_headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok());
+    let ip = client_ip(&request_headers, peer.map(|ConnectInfo(peer)| peer));
     let mut headers = HeaderMap::new();
-    let mut pdb = pdb.lock().await;
+    if let Err(wait) = pdb.login_attempt(&log_in.username, ip.as_deref()) {
+        let secs = retry_after_secs(wait);
+        tracing::warn!(
+            "login for {} from {} refused after too many failures",
+            log_in.username,
+            ip.as_deref().unwrap_or("an unknown address")
+        );
+        headers.insert(header::RETRY_AFTER, header::HeaderValue::from(secs));
+        return Err((
+            StatusCode::TOO_MANY_REQUESTS,
+            headers,
+            format!("Too many failed attempts, retry in {secs} seconds"),
+        ));
+    }
     match pdb
         .verify_password(&log_in.username, &log_in.password)
         .await
     {
         Ok(user_id) => {
+            pdb.login_succeeded(&log_in.username, ip.as_deref());
             let cookie = random_cookie();
-            pdb.sessions.push(CookieSession { cookie, user_id });
-            tracing::debug!("adding cookie {:?}", pdb.sessions[pdb.sessions.len() - 1]);
-            let mut header_value_str = format!(
-                "{}={}; Secure; SameSite=Strict",
-                COOKIE_NAME,
-                std::str::from_utf8(&cookie).unwrap()
-            );
+            let cookie = std::str::from_utf8(&cookie).unwrap();
+            if let Err(e) = pdb
+                .new_session(user_id, cookie, user_agent, ip.as_deref())
+                .await
+            {
+                tracing::error!("error storing session: {e}");
+                return Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    headers,
+                    "Error storing session".to_string(),
+                ));
+            }
+            tracing::debug!("new session for user {user_id}");
+            let mut header_value_str =
+                format!("{}={}; Secure; SameSite=Strict", COOKIE_NAME, cookie);
             if log_in.remember == "true" {
-                header_value_str.push_str("; Max-Age=172800");
+                header_value_str.push_str(&format!("; Max-Age={}", pdb.session_max_age()));
             }
             headers.insert(
                 header::SET_COOKIE,
@@ -87,16 +117,22 @@ pub async fn check_username_password(
             Ok((StatusCode::OK, headers, HtmlTemplate(template)))
         }
         Err(e) => match e {
-            LoginError::PasswordError => Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                headers,
-                "Error fetching info".to_string(),
-            )),
-            LoginError::WrongUsernameOrPassword => Err((
-                StatusCode::UNAUTHORIZED,
-                headers,
-                "Wrong username/password".to_string(),
-            )),
+            LoginError::PasswordError => {
+                pdb.login_aborted(&log_in.username, ip.as_deref());
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    headers,
+                    "Error fetching info".to_string(),
+                ))
+            }
+            LoginError::WrongUsernameOrPassword => {
+                pdb.login_failed(&log_in.username, ip.as_deref());
+                Err((
+                    StatusCode::UNAUTHORIZED,
+                    headers,
+                    "Wrong username/password".to_string(),
+                ))
+            }
         },
     }
 }
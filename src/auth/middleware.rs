@@ -1,20 +1,49 @@
 use crate::{
-    auth::{CurrentUser, SharedPdb, COOKIE_AUTH_LEN, COOKIE_NAME},
+    auth::{
+        throttle::retry_after_secs, CurrentUser, PasswordDatabase, Scope, SharedPdb,
+        COOKIE_AUTH_LEN, COOKIE_NAME,
+    },
+    devices::DEVICE_TOKEN_SCOPE,
     HtmlTemplate,
 };
 use askama::Template;
 use axum::{
-    http::{header, Request, StatusCode},
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Method, Request, StatusCode},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 use rand::Rng;
+use sqlx::types::time::PrimitiveDateTime;
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+use time::OffsetDateTime;
 
-#[derive(Clone, Debug)]
-pub struct CookieSession {
-    pub cookie: [u8; COOKIE_AUTH_LEN],
-    pub user_id: i32,
+/// The only path ingest tokens are valid for.
+const INPUT_PATH: &str = "/api/input";
+
+/// The paths read tokens are valid for. The other ones can reveal secrets,
+/// like webhook keys, share links or invite tokens.
+const READ_PATHS: &[&str] = &[
+    "/api/query",
+    "/api/available",
+    "/api/latest",
+    "/api/live",
+    "/api/segments",
+    "/api/visited",
+    "/api/geocode",
+    "/api/events",
+    "/api/deletions",
+];
+
+/// Credentials sent in the `Authorization` header.
+#[derive(Debug, PartialEq, Eq)]
+enum Credentials {
+    /// A token.
+    Bearer(String),
+    /// A username with one of their tokens or their password.
+    Basic(String, String),
 }
 
 #[derive(Template)]
@@ -35,8 +64,8 @@ pub fn random_cookie() -> [u8; COOKIE_AUTH_LEN] {
 }
 
 /// Create a HashMap with the content of the cookie header.
-fn get_cookie_map<B>(req: &Request<B>) -> HashMap<String, String> {
-    req.headers()
+fn get_cookie_map(headers: &HeaderMap) -> HashMap<String, String> {
+    headers
         .get(header::COOKIE)
         .and_then(|header| header.to_str().ok())
         .map(|cookie_str| {
@@ -51,49 +80,124 @@ fn get_cookie_map<B>(req: &Request<B>) -> HashMap<String, String> {
         .unwrap_or_default()
 }
 
+/// The session cookie sent with a request, if any.
+pub fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    get_cookie_map(headers).remove(COOKIE_NAME)
+}
+
+/// The address of the client of a request. The server only listens on the
+/// loopback interface behind a reverse proxy, so the last address added to
+/// `X-Forwarded-For` by the proxy is used when present.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<String> {
+    headers
+        .get("x-forwarded-for")
+        .and_then(|header| header.to_str().ok())
+        .and_then(|forwarded| forwarded.rsplit(',').next())
+        .map(|ip| ip.trim().to_string())
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| peer.map(|peer| peer.ip().to_string()))
+}
+
+/// Parses the `Authorization` header.
+fn get_credentials(headers: &HeaderMap) -> Option<Credentials> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, value) = value.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Bearer(value.trim().to_string()))
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(base64::decode(value.trim()).ok()?).ok()?;
+        let (username, secret) = decoded.split_once(':')?;
+        Some(Credentials::Basic(username.to_string(), secret.to_string()))
+    } else {
+        None
+    }
+}
+
 /// Authentification middleware function. Checks if a user is identified before
 /// serving response.
 pub async fn auth<B>(mut req: Request<B>, next: Next<B>, pdb: SharedPdb) -> impl IntoResponse {
-    {
-        let lock = pdb.lock().await;
-        if lock.is_develop() {
-            let current_user = CurrentUser {
-                user_id: 1,
-                is_admin: true,
-            };
-            req.extensions_mut().insert(current_user);
-            return Ok(next.run(req).await);
-        }
+    if pdb.is_develop() {
+        let current_user = CurrentUser {
+            user_id: 1,
+            is_admin: true,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        req.extensions_mut().insert(current_user);
+        return Ok(next.run(req).await);
     }
 
-    let cookies: HashMap<String, String> = get_cookie_map(&req);
+    let cookies: HashMap<String, String> = get_cookie_map(req.headers());
 
     // Check for cookies first and then for the valid token.
     let mut user_id_auth = if let Some(val) = cookies.get(COOKIE_NAME) {
         tracing::debug!("found a cookie");
-        pdb.lock()
+        pdb.session_user(val)
             .await
-            .sessions
-            .iter()
-            .enumerate()
-            .find(|(_, ck)| {
-                ck.cookie
-                    .into_iter()
-                    .zip(val.as_bytes())
-                    .all(|(a, &b)| a == b)
-            })
-            .map(|(_idx, cookie_sess): (usize, &CookieSession)| cookie_sess.user_id)
+            .map_err(|e| tracing::error!("error checking session: {e}"))
+            .ok()
+            .flatten()
+            .map(|user_id| (user_id, None, Scope::Admin))
     } else {
         None
     };
     if user_id_auth.is_none() {
-        user_id_auth = get_token_from_uri(req.uri().query().unwrap_or(""), pdb.clone()).await;
+        let credentials = get_credentials(req.headers());
+        let query_token = pdb
+            .query_tokens()
+            .then(|| get_token_from_uri(req.uri().query().unwrap_or("")))
+            .flatten();
+        if credentials.is_some() || query_token.is_some() {
+            let peer = req
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(peer)| *peer);
+            let ip = client_ip(req.headers(), peer);
+            // Each check counts as a failed attempt until it succeeds, so
+            // that concurrent guesses are throttled like sequential ones.
+            let token = match credentials {
+                Some(Credentials::Basic(username, secret)) => {
+                    user_id_auth = check_basic(&username, &secret, &pdb, ip.as_deref())
+                        .await
+                        .map_err(too_many_attempts)?;
+                    query_token
+                }
+                Some(Credentials::Bearer(token)) => Some(token),
+                None => query_token,
+            };
+            if let (None, Some(token)) = (user_id_auth, token) {
+                user_id_auth = check_token(&token, &pdb, ip.as_deref())
+                    .await
+                    .map_err(too_many_attempts)?;
+            }
+        }
     }
 
-    if let Some(user_id) = user_id_auth {
+    if let Some((_, _, scope)) = user_id_auth {
+        if !allows(scope, req.method(), req.uri().path()) {
+            tracing::debug!(
+                "{scope:?} token used for {} {}",
+                req.method(),
+                req.uri().path()
+            );
+            user_id_auth = None;
+        }
+    }
+
+    // The user may have been disabled since their login.
+    let user_auth = match user_id_auth {
+        Some((user_id, device_id, scope)) => user_is_admin(user_id, &pdb)
+            .await
+            .map(|is_admin| (user_id, is_admin, device_id, scope)),
+        None => None,
+    };
+
+    if let Some((user_id, is_admin, device_id, scope)) = user_auth {
         let current_user = CurrentUser {
             user_id,
-            is_admin: false,
+            is_admin,
+            device_id,
+            scope,
         };
         req.extensions_mut().insert(current_user);
         let response = next.run(req).await;
@@ -103,37 +207,134 @@ pub async fn auth<B>(mut req: Request<B>, next: Next<B>, pdb: SharedPdb) -> impl
         let template = UnauthorizedTemplate {
             url: req.uri().to_string(),
         };
-        Err((StatusCode::UNAUTHORIZED, HtmlTemplate(template)))
+        Err((StatusCode::UNAUTHORIZED, HtmlTemplate(template)).into_response())
     }
 }
 
-async fn get_token_from_uri(query: &str, pdb: SharedPdb) -> Option<i32> {
-    // Parse the query parameters for the token and check it.
-    let mut user_id = None;
-    for x in query.split('&') {
-        let split: Vec<String> = x.split('=').map(|x| x.to_string()).collect();
-        if (split.len() == 2) && (split[0] == "token") {
-            user_id = token_is_valid(&split[1], pdb.clone()).await;
-            if user_id.is_some() {
-                tracing::debug!("valid token found");
-                break;
-            }
+/// The response to a request refused after too many failed attempts.
+fn too_many_attempts(wait: Duration) -> Response {
+    let secs = retry_after_secs(wait);
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, secs.to_string())],
+        format!("Too many failed attempts, retry in {secs} seconds"),
+    )
+        .into_response()
+}
+
+/// Returns whether a user is an administrator, or `None` if they are disabled.
+async fn user_is_admin(user_id: i32, pdb: &PasswordDatabase) -> Option<bool> {
+    sqlx::query_scalar!(
+        r#"SELECT is_admin FROM users WHERE id=$1 AND NOT disabled"#,
+        user_id
+    )
+    .fetch_optional(pdb.pool())
+    .await
+    .map_err(|e| tracing::error!("error checking user: {e}"))
+    .ok()
+    .flatten()
+}
+
+/// Whether a request is within a scope. Ingest tokens can only send points,
+/// read tokens can only read the location data.
+pub(crate) fn allows(scope: Scope, method: &Method, path: &str) -> bool {
+    match scope {
+        Scope::Ingest => path == INPUT_PATH,
+        Scope::Read => {
+            (method == Method::GET || method == Method::HEAD) && READ_PATHS.contains(&path)
         }
+        Scope::Admin => true,
     }
-    user_id
 }
 
-async fn token_is_valid(token: &str, pdb: SharedPdb) -> Option<i32> {
-    let pdb = pdb.lock().await;
+/// Checks HTTP Basic credentials. The secret can be a token of the user or
+/// their password, and the attempt is throttled like a login. Returns how long
+/// to wait instead after too many failures.
+async fn check_basic(
+    username: &str,
+    secret: &str,
+    pdb: &PasswordDatabase,
+    ip: Option<&str>,
+) -> Result<Option<(i32, Option<i32>, Scope)>, Duration> {
+    pdb.login_attempt(username, ip)?;
+    let user_id = sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_optional(pdb.pool())
+        .await
+        .map_err(|e| tracing::error!("error checking user: {e}"))
+        .ok()
+        .flatten();
+    let token_auth = match user_id {
+        Some(user_id) => token_is_valid(secret, pdb)
+            .await
+            .filter(|auth| auth.0 == user_id),
+        None => None,
+    };
+    let auth = match token_auth {
+        Some(auth) => Some(auth),
+        None => pdb
+            .verify_password(username, secret)
+            .await
+            .ok()
+            .map(|user_id| (user_id, None, Scope::Admin)),
+    };
+    if auth.is_some() {
+        pdb.login_succeeded(username, ip);
+    } else {
+        pdb.login_failed(username, ip);
+    }
+    Ok(auth)
+}
+
+/// Checks a token, throttled per address. Returns how long to wait instead
+/// after too many invalid tokens.
+async fn check_token(
+    token: &str,
+    pdb: &PasswordDatabase,
+    ip: Option<&str>,
+) -> Result<Option<(i32, Option<i32>, Scope)>, Duration> {
+    pdb.token_attempt(ip)?;
+    let auth = token_is_valid(token, pdb).await;
+    if auth.is_some() {
+        pdb.token_succeeded(ip);
+    } else {
+        pdb.token_failed(ip);
+    }
+    Ok(auth)
+}
+
+/// The first `token` query parameter. Only one token is checked per request.
+fn get_token_from_uri(query: &str) -> Option<String> {
+    query.split('&').find_map(|x| {
+        let split: Vec<&str> = x.split('=').collect();
+        (split.len() == 2 && split[0] == "token").then(|| split[1].to_string())
+    })
+}
+
+/// Looks up a valid user input token or a device token, and records the use
+/// of input tokens. Device tokens also return the id of their device, and
+/// always have the ingest scope.
+async fn token_is_valid(token: &str, pdb: &PasswordDatabase) -> Option<(i32, Option<i32>, Scope)> {
+    let now = OffsetDateTime::now_utc();
     sqlx::query!(
-        r#"SELECT user_id, is_admin FROM input_tokens JOIN users ON
-           input_tokens.user_id=users.id WHERE input_token=$1"#,
-        token
+        r#"WITH input_token AS (
+               UPDATE input_tokens SET last_used=$2
+               WHERE input_token=$1 AND valid AND user_id IS NOT NULL
+               RETURNING user_id, scope)
+           SELECT user_id AS "user_id!", NULL::INT AS device_id,
+           scope AS "scope!: Scope" FROM input_token
+           UNION ALL
+           SELECT user_identifier, id, $3::TOKEN_SCOPE FROM devices
+           WHERE input_token=$1 AND retired_at IS NULL"#,
+        token,
+        PrimitiveDateTime::new(now.date(), now.time()),
+        DEVICE_TOKEN_SCOPE as Scope
     )
-    .fetch_one(pdb.pool())
+    .fetch_optional(pdb.pool())
     .await
-    .unwrap()
-    .user_id
+    .map_err(|e| tracing::error!("error checking token: {e}"))
+    .ok()
+    .flatten()
+    .map(|row| (row.user_id, row.device_id, row.scope))
 }
 
 #[cfg(test)]
@@ -153,6 +354,75 @@ mod tests {
         assert_ne!(cookie1, cookie2);
     }
 
+    #[test]
+    fn should_parse_authorization_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(get_credentials(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Bearer abc".parse().unwrap());
+        assert_eq!(
+            get_credentials(&headers),
+            Some(Credentials::Bearer("abc".to_string()))
+        );
+        // alice:pass:word
+        headers.insert(
+            header::AUTHORIZATION,
+            "Basic YWxpY2U6cGFzczp3b3Jk".parse().unwrap(),
+        );
+        assert_eq!(
+            get_credentials(&headers),
+            Some(Credentials::Basic(
+                "alice".to_string(),
+                "pass:word".to_string()
+            ))
+        );
+        headers.insert(header::AUTHORIZATION, "Basic !!!".parse().unwrap());
+        assert_eq!(get_credentials(&headers), None);
+        headers.insert(header::AUTHORIZATION, "Digest abc".parse().unwrap());
+        assert_eq!(get_credentials(&headers), None);
+    }
+
+    #[test]
+    fn should_restrict_token_scopes() {
+        assert!(allows(Scope::Ingest, &Method::POST, INPUT_PATH));
+        assert!(!allows(Scope::Ingest, &Method::GET, "/api/query"));
+        assert!(allows(Scope::Read, &Method::GET, "/api/query"));
+        assert!(!allows(Scope::Read, &Method::POST, INPUT_PATH));
+        assert!(!allows(Scope::Read, &Method::DELETE, "/api/tokens/1"));
+        for path in [
+            "/api/webhooks",
+            "/api/shares",
+            "/api/register-tokens",
+            "/api/users",
+            "/api/health",
+            "/api/sessions",
+            "/api/tokens",
+        ] {
+            assert!(!allows(Scope::Read, &Method::GET, path));
+        }
+        assert!(allows(Scope::Admin, &Method::DELETE, "/api/tokens/1"));
+        assert!(allows(Scope::Admin, &Method::POST, INPUT_PATH));
+    }
+
+    #[test]
+    fn should_parse_query_tokens() {
+        assert_eq!(get_token_from_uri(""), None);
+        assert_eq!(get_token_from_uri("from=1&to=2"), None);
+        assert_eq!(
+            get_token_from_uri("from=1&token=abc&token=def"),
+            Some("abc".to_string())
+        );
+    }
+
+    #[test]
+    fn should_find_client_ips() {
+        let peer = Some(SocketAddr::from(([127, 0, 0, 1], 4000)));
+        let mut headers = HeaderMap::new();
+        assert_eq!(client_ip(&headers, peer), Some("127.0.0.1".to_string()));
+        headers.insert("x-forwarded-for", "10.0.0.1, 192.0.2.7".parse().unwrap());
+        assert_eq!(client_ip(&headers, peer), Some("192.0.2.7".to_string()));
+        assert_eq!(client_ip(&HeaderMap::new(), None), None);
+    }
+
     #[test]
     fn should_parse_cookies_from_requests() {
         // No cookies header
@@ -162,7 +432,7 @@ mod tests {
             .header("X-Custom-Foo", "Bar")
             .body(())
             .unwrap();
-        let cookie_map = get_cookie_map(&request);
+        let cookie_map = get_cookie_map(request.headers());
         assert!(cookie_map.is_empty());
 
         // Multiple cookies in header
@@ -182,7 +452,7 @@ mod tests {
             .body(())
             .unwrap();
 
-        let cookie_map = get_cookie_map(&request);
+        let cookie_map = get_cookie_map(request.headers());
         assert_eq!(
             cookie_map.get("guest_id"),
             Some(&"5356763797944027".to_string())
@@ -1,14 +1,52 @@
-/// Module containing all the authentication, registration, cookies, etc. logic.
+//! Module containing all the authentication, registration, cookies, etc. logic.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 mod login;
 mod middleware;
 mod password_db;
 mod register;
+mod session;
+mod throttle;
 
 pub use login::{check_username_password, serve_login};
 pub use middleware::auth as auth_middleware;
-pub use password_db::{new_shared_db, PasswordDatabase, SharedPdb};
-pub use register::{insert_username_password, SignUp};
+#[cfg(test)]
+pub(crate) use middleware::allows;
+pub(crate) use middleware::{client_ip, session_cookie};
+pub use password_db::{PasswordDatabase, SharedPdb};
+pub use register::{insert_username_password, serve_register, SignUp};
+pub use session::{list_sessions, logout, revoke_session, revoke_sessions};
+pub(crate) use session::{end_sessions, expired_cookie, spawn_session_cleanup, SessionTimeouts};
+pub(crate) use throttle::{spawn_throttle_cleanup, ThrottleSettings};
+
+/// What a request is allowed to do. Login sessions have the `Admin` scope,
+/// tokens can be restricted.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TOKEN_SCOPE", rename_all = "lowercase")]
+pub enum Scope {
+    /// Only sending points.
+    Ingest,
+    /// Only reading data.
+    Read,
+    /// Everything the user can do.
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "ingest" => Ok(Scope::Ingest),
+            "read" => Ok(Scope::Read),
+            "admin" => Ok(Scope::Admin),
+            s => Err(format!("unknown scope {s}")),
+        }
+    }
+}
 
 /// A structure representing the user currently logged in.
 #[derive(Clone)]
@@ -17,7 +55,11 @@ pub struct CurrentUser {
     pub user_id: i32,
     /// A bool representing wether the user is an administrator.
     pub is_admin: bool,
+    /// The device of the token used to authenticate, if it is a device token.
+    pub device_id: Option<i32>,
+    /// The scope of the session or token used to authenticate.
+    pub scope: Scope,
 }
 
 const COOKIE_AUTH_LEN: usize = 64;
-const COOKIE_NAME: &str = "__Secure-roverland-auth";
+pub(crate) const COOKIE_NAME: &str = "__Secure-roverland-auth";
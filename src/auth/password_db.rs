@@ -1,38 +1,51 @@
 use crate::auth::{
     login::LoginError,
-    middleware::CookieSession,
     register::{RegisterError, SignUp},
+    session::{create_session, session_user, SessionTimeouts},
+    throttle::{retry_after_secs, Throttle, ThrottleSettings},
+    Scope,
 };
+use crate::register_token::{release_register_token, use_register_token};
+use crate::tokens::insert_token;
 use argon2::{
     password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
     Argon2,
 };
-use rand::Rng;
+use axum::http::StatusCode;
 use sqlx::postgres::PgPool;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant};
 
-const INPUT_TOKEN_LEN: usize = 64;
-
-/// An `Arc<Mutex<>>` of the `PasswordDatabase`. It is an in-memory inventory of
-/// authentified users, cookie sessions, etc.
-pub type SharedPdb = Arc<Mutex<PasswordDatabase>>;
-
-/// Constructor for a `SharePdb` from a database pool connection.
-pub fn new_shared_db(db_pool: &PgPool) -> SharedPdb {
-    Arc::new(Mutex::new(PasswordDatabase::new(db_pool)))
+/// Hashes a password. Hashing is slow on purpose, it must not block the other
+/// requests.
+async fn hash_password(password: String) -> Result<String, RegisterError> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|_| RegisterError::Password)?
+    .map_err(|_| RegisterError::Password)
 }
 
+/// An `Arc<>` of the `PasswordDatabase`. It gives access to the authentified
+/// users, cookie sessions, etc. It holds no mutable state, the sessions are
+/// stored in the database.
+pub type SharedPdb = Arc<PasswordDatabase>;
+
 #[derive(Clone)]
 struct PasswordStorage {
     pub pool: PgPool,
 }
 
 impl PasswordStorage {
-    // Retreive the user id and password hash from a username
+    // Retreive the user id and password hash from a username, unless the user
+    // is disabled.
     pub async fn get(&self, username: &str) -> sqlx::Result<(i32, String)> {
         sqlx::query!(
-            r#"SELECT id, password from users where username=$1"#,
+            r#"SELECT id, password from users where username=$1 AND NOT disabled"#,
             username
         )
         .fetch_one(&self.pool)
@@ -45,7 +58,7 @@ impl PasswordStorage {
         username: String,
         password: String,
         is_admin: bool,
-    ) -> sqlx::Result<()> {
+    ) -> sqlx::Result<i32> {
         let user = sqlx::query!(
             r#"INSERT INTO users (username, password, is_admin) VALUES ( $1, $2, $3 ) RETURNING users.id"#,
             username,
@@ -55,40 +68,8 @@ impl PasswordStorage {
         .fetch_one(&self.pool)
         .await?;
         // Create an input token on user creation
-        self.create_input_token(user.id).await?;
-        Ok(())
-    }
-
-    pub async fn create_input_token(&self, user_id: i32) -> sqlx::Result<()> {
-        let input_token: String = {
-            let mut rng = rand::thread_rng();
-            (&mut rng)
-                .sample_iter(rand::distributions::Alphanumeric)
-                .take(INPUT_TOKEN_LEN)
-                .map(char::from)
-                .collect()
-        };
-        sqlx::query!(
-            r#"INSERT INTO input_tokens (input_token, valid, user_id) VALUES ( $1, $2, $3 )"#,
-            input_token,
-            true,
-            user_id,
-        )
-        .execute(&self.pool)
-        .await?;
-        Ok(())
-    }
-
-    pub async fn check_token(&self, token: &str) -> bool {
-        sqlx::query!(
-            r#"UPDATE register_tokens SET used=TRUE
-               WHERE register_tokens.register_token=$1 RETURNING
-               register_tokens.register_token"#,
-            token
-        )
-        .fetch_one(&self.pool)
-        .await
-        .is_ok()
+        insert_token(&self.pool, user.id, None, Scope::Ingest).await?;
+        Ok(user.id)
     }
 }
 
@@ -96,24 +77,158 @@ impl PasswordStorage {
 #[derive(Clone)]
 pub struct PasswordDatabase {
     storage: PasswordStorage,
-    /// User sessions.
-    pub sessions: Vec<CookieSession>,
+    /// How long the user sessions last.
+    session_timeouts: SessionTimeouts,
     /// Development mode flag for bypassing authentication when developing.
     develop_mode: bool,
+    /// Whether tokens are accepted as a query parameter.
+    query_tokens: bool,
+    /// Failed logins per username.
+    user_throttle: Arc<Throttle>,
+    /// Failed logins and invalid tokens per address.
+    ip_throttle: Arc<Throttle>,
 }
 
 impl PasswordDatabase {
     /// Constructs a new `PasswordDatabase` object from a database connection.
     pub fn new(db_pool: &PgPool) -> PasswordDatabase {
+        let throttles = ThrottleSettings::default();
         PasswordDatabase {
             storage: PasswordStorage {
                 pool: db_pool.clone(),
             },
-            sessions: vec![],
+            session_timeouts: SessionTimeouts::default(),
             develop_mode: false,
+            query_tokens: true,
+            user_throttle: Arc::new(Throttle::new(throttles.users)),
+            ip_throttle: Arc::new(Throttle::new(throttles.ips)),
         }
     }
 
+    /// Sets the limits of the failed logins and invalid tokens.
+    pub(crate) fn set_throttle_settings(&mut self, throttles: ThrottleSettings) {
+        self.user_throttle = Arc::new(Throttle::new(throttles.users));
+        self.ip_throttle = Arc::new(Throttle::new(throttles.ips));
+    }
+
+    /// Reserves a login attempt for a username from an address, or returns how
+    /// long it must wait after too many failures. The attempt counts as a
+    /// failure until it succeeds, so that concurrent guesses are throttled too.
+    pub fn login_attempt(&self, username: &str, ip: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.user_throttle.attempt(username, now)?;
+        if let Some(ip) = ip {
+            if let Err(wait) = self.ip_throttle.attempt(ip, now) {
+                self.user_throttle.release(username);
+                return Err(wait);
+            }
+        }
+        Ok(())
+    }
+
+    /// Logs a failed login. It was already counted by `login_attempt`.
+    pub fn login_failed(&self, username: &str, ip: Option<&str>) {
+        let now = Instant::now();
+        tracing::warn!(
+            "failed login for {username} from {}",
+            ip.unwrap_or("an unknown address")
+        );
+        if let Some(wait) = self.user_throttle.retry_after(username, now) {
+            tracing::warn!("delaying the logins of {username} by {}s", wait.as_secs());
+        }
+        if let Some(ip) = ip {
+            self.address_failed(ip, now);
+        }
+    }
+
+    /// Forgets the failed logins of a username after a successful one, and
+    /// gives back the attempt of the address.
+    pub fn login_succeeded(&self, username: &str, ip: Option<&str>) {
+        self.user_throttle.succeed(username);
+        if let Some(ip) = ip {
+            self.ip_throttle.release(ip);
+        }
+    }
+
+    /// Gives back a login attempt which could not be checked.
+    pub fn login_aborted(&self, username: &str, ip: Option<&str>) {
+        self.user_throttle.release(username);
+        if let Some(ip) = ip {
+            self.ip_throttle.release(ip);
+        }
+    }
+
+    /// Reserves the check of a token sent from an address, or returns how long
+    /// the tokens of the address are ignored after too many invalid ones.
+    pub fn token_attempt(&self, ip: Option<&str>) -> Result<(), Duration> {
+        match ip {
+            Some(ip) => self.ip_throttle.attempt(ip, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    /// Logs an invalid token. It was already counted by `token_attempt`.
+    pub fn token_failed(&self, ip: Option<&str>) {
+        tracing::debug!("invalid token from {}", ip.unwrap_or("an unknown address"));
+        if let Some(ip) = ip {
+            self.address_failed(ip, Instant::now());
+        }
+    }
+
+    /// Gives back the attempt of an address after a valid token.
+    pub fn token_succeeded(&self, ip: Option<&str>) {
+        if let Some(ip) = ip {
+            self.ip_throttle.release(ip);
+        }
+    }
+
+    fn address_failed(&self, ip: &str, now: Instant) {
+        if let Some(wait) = self.ip_throttle.retry_after(ip, now) {
+            tracing::warn!("delaying the attempts from {ip} by {}s", wait.as_secs());
+        }
+    }
+
+    /// Forgets the old failed attempts.
+    pub(crate) fn purge_throttles(&self) {
+        let now = Instant::now();
+        self.user_throttle.purge(now);
+        self.ip_throttle.purge(now);
+    }
+
+    /// Sets how long the user sessions last.
+    pub(crate) fn set_session_timeouts(&mut self, timeouts: SessionTimeouts) {
+        self.session_timeouts = timeouts;
+    }
+
+    /// Number of seconds a session cookie should be kept by the browser.
+    pub fn session_max_age(&self) -> i64 {
+        self.session_timeouts.absolute.whole_seconds()
+    }
+
+    /// Opens a new session for a user with a cookie. The user agent and
+    /// address of the client are kept to tell the sessions apart.
+    pub async fn new_session(
+        &self,
+        user_id: i32,
+        cookie: &str,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> sqlx::Result<()> {
+        create_session(
+            self.pool(),
+            user_id,
+            cookie,
+            (user_agent, ip),
+            self.session_timeouts,
+        )
+        .await
+    }
+
+    /// Returns the user of a session cookie, if the session is still valid.
+    pub async fn session_user(&self, cookie: &str) -> sqlx::Result<Option<i32>> {
+        session_user(self.pool(), cookie, self.session_timeouts).await
+    }
+
     /// Activate the development mode.
     pub fn set_develop(&mut self) {
         self.develop_mode = true;
@@ -124,6 +239,16 @@ impl PasswordDatabase {
         self.develop_mode
     }
 
+    /// Sets whether tokens are accepted as a query parameter.
+    pub fn set_query_tokens(&mut self, enabled: bool) {
+        self.query_tokens = enabled;
+    }
+
+    /// Whether tokens are accepted as a query parameter.
+    pub fn query_tokens(&self) -> bool {
+        self.query_tokens
+    }
+
     /// Get a reference to this `PasswordDatabase` database pool connection.
     pub fn pool(&self) -> &PgPool {
         &self.storage.pool
@@ -135,25 +260,64 @@ impl PasswordDatabase {
         sign_up: SignUp,
         is_admin: bool,
     ) -> Result<(), RegisterError> {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-        let password_hash = argon2
-            .hash_password(sign_up.password.as_bytes(), &salt)
-            .map_err(|_| RegisterError::Password)?
-            .to_string();
-        if is_admin
-            || self
-                .storage
-                .check_token(&sign_up.token.unwrap_or_else(|| "".to_string()))
-                .await
-        {
-            self.storage
-                .insert(sign_up.username, password_hash, is_admin)
+        if is_admin {
+            return self
+                .create_user(sign_up.username, sign_up.password, is_admin)
                 .await
-                .map_err(|_| RegisterError::DB)
-        } else {
-            Err(RegisterError::Token)
+                .map(|_| ());
+        }
+        let token_id = use_register_token(
+            self.pool(),
+            &sign_up.token.unwrap_or_default(),
+            &sign_up.username,
+        )
+        .await
+        .map_err(|_| RegisterError::DB)?
+        .ok_or(RegisterError::Token)?;
+        let res = self
+            .create_user(sign_up.username, sign_up.password, is_admin)
+            .await;
+        // A failed registration, with a taken username for instance, does not
+        // count as a use of the token.
+        if res.is_err() {
+            if let Err(e) = release_register_token(self.pool(), token_id).await {
+                tracing::error!("error releasing registration token: {e}");
+            }
         }
+        res.map(|_| ())
+    }
+
+    /// Creates a user without a registration token, and returns their id.
+    pub async fn create_user(
+        &self,
+        username: String,
+        password: String,
+        is_admin: bool,
+    ) -> Result<i32, RegisterError> {
+        let password_hash = hash_password(password).await?;
+        self.storage
+            .insert(username, password_hash, is_admin)
+            .await
+            .map_err(|_| RegisterError::DB)
+    }
+
+    /// Replaces the password of a user. Returns `false` if there is no such
+    /// user.
+    pub async fn set_password(
+        &self,
+        user_id: i32,
+        password: String,
+    ) -> Result<bool, RegisterError> {
+        let password_hash = hash_password(password).await?;
+        sqlx::query!(
+            r#"UPDATE users SET password=$2 WHERE id=$1"#,
+            user_id,
+            password_hash
+        )
+        .execute(self.pool())
+        .await
+        .map(|res| res.rows_affected() > 0)
+        .map_err(|_| RegisterError::DB)
     }
 
     /// This will verify the password and username in the database.
@@ -164,14 +328,56 @@ impl PasswordDatabase {
     ) -> Result<i32, LoginError> {
         match self.storage.get(username).await.ok() {
             Some((id, actual_password)) => {
-                let parsed_hash =
-                    PasswordHash::new(&actual_password).map_err(|_| LoginError::PasswordError)?;
-                Argon2::default()
-                    .verify_password(attempted_password.as_bytes(), &parsed_hash)
-                    .map_err(|_| LoginError::WrongUsernameOrPassword)?;
+                let attempted_password = attempted_password.to_string();
+                tokio::task::spawn_blocking(move || {
+                    let parsed_hash = PasswordHash::new(&actual_password)
+                        .map_err(|_| LoginError::PasswordError)?;
+                    Argon2::default()
+                        .verify_password(attempted_password.as_bytes(), &parsed_hash)
+                        .map_err(|_| LoginError::WrongUsernameOrPassword)
+                })
+                .await
+                .map_err(|_| LoginError::PasswordError)??;
                 Ok(id)
             }
             None => Err(LoginError::WrongUsernameOrPassword),
         }
     }
+
+    /// Checks the password of a logged in user again before a sensitive
+    /// change. The check is throttled like a login, so that a stolen session
+    /// cannot be used to guess the password.
+    pub async fn confirm_password(
+        &self,
+        username: &str,
+        password: &str,
+        ip: Option<&str>,
+    ) -> Result<(), (StatusCode, String)> {
+        if let Err(wait) = self.login_attempt(username, ip) {
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Too many failed attempts, retry in {} seconds",
+                    retry_after_secs(wait)
+                ),
+            ));
+        }
+        match self.verify_password(username, password).await {
+            Ok(_) => {
+                self.login_succeeded(username, ip);
+                Ok(())
+            }
+            Err(LoginError::WrongUsernameOrPassword) => {
+                self.login_failed(username, ip);
+                Err((StatusCode::UNAUTHORIZED, "Wrong password".to_string()))
+            }
+            Err(LoginError::PasswordError) => {
+                self.login_aborted(username, ip);
+                Err((
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Error checking the password".to_string(),
+                ))
+            }
+        }
+    }
 }
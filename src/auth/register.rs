@@ -1,5 +1,13 @@
 use crate::auth::SharedPdb;
-use axum::{extract::Form, http::StatusCode, response::IntoResponse, Extension};
+use crate::register_token::usable_register_token;
+use crate::HtmlTemplate;
+use askama::Template;
+use axum::{
+    extract::{Form, Query},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension,
+};
 use serde::Deserialize;
 
 #[derive(Debug)]
@@ -9,6 +17,21 @@ pub enum RegisterError {
     Password,
 }
 
+#[derive(Template)]
+#[template(path = "register.html")]
+struct RegisterTemplate {
+    token: Option<String>,
+    username: Option<String>,
+    /// The token of the invite link cannot be used anymore.
+    expired: bool,
+}
+
+/// The query of an invite link.
+#[derive(Deserialize, Debug)]
+pub struct InviteQuery {
+    token: Option<String>,
+}
+
 /// A registration request object, with a username, password and token.
 #[derive(Deserialize)]
 pub struct SignUp {
@@ -31,12 +54,32 @@ impl SignUp {
     }
 }
 
+/// The registration page. Invite links prefill the token, and the username
+/// the token is reserved to.
+pub async fn serve_register(
+    Query(invite): Query<InviteQuery>,
+    Extension(pdb): Extension<SharedPdb>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let usable = match &invite.token {
+        Some(token) => Some(
+            usable_register_token(pdb.pool(), token)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?,
+        ),
+        None => None,
+    };
+    Ok(HtmlTemplate(RegisterTemplate {
+        expired: matches!(usable, Some(None)),
+        username: usable.flatten().flatten(),
+        token: invite.token,
+    }))
+}
+
 /// This function inserts a new user in the database.
 pub async fn insert_username_password(
     form: Form<SignUp>,
     Extension(pdb): Extension<SharedPdb>,
 ) -> impl IntoResponse {
-    let pdb = pdb.lock().await;
     let sign_up: SignUp = form.0;
     let store_password_res = pdb.store_password(sign_up, false).await;
     match store_password_res {
@@ -0,0 +1,247 @@
+use crate::api::serialize_timestamp;
+use crate::auth::{middleware::session_cookie, CurrentUser, COOKIE_NAME};
+use crate::settings;
+use crate::util::{hash_token, now};
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use time::Duration;
+
+/// How long a session lasts.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionTimeouts {
+    /// A session expires when it is not used for this long.
+    pub idle: Duration,
+    /// A session expires this long after the login, even when it is used.
+    pub absolute: Duration,
+}
+
+impl Default for SessionTimeouts {
+    fn default() -> Self {
+        SessionTimeouts {
+            idle: Duration::hours(48),
+            absolute: Duration::days(30),
+        }
+    }
+}
+
+impl From<&settings::Auth> for SessionTimeouts {
+    fn from(auth: &settings::Auth) -> Self {
+        SessionTimeouts {
+            idle: Duration::hours(auth.session_idle_hours),
+            absolute: Duration::days(auth.session_max_days),
+        }
+    }
+}
+
+/// A login session of a user.
+#[derive(Serialize, Debug)]
+pub struct Session {
+    id: i32,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_timestamp")]
+    last_used: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+    user_agent: Option<String>,
+    ip: Option<String>,
+    /// Whether this is the session of the request.
+    current: bool,
+}
+
+/// Query parameters of the revocation of all the sessions of a user.
+#[derive(Deserialize, Debug)]
+pub struct RevokeSessionsQuery {
+    /// Keep the session of the request.
+    #[serde(default)]
+    keep_current: bool,
+}
+
+/// A cookie clearing the session cookie of the browser.
+pub(crate) fn expired_cookie() -> String {
+    format!("{COOKIE_NAME}=; Secure; SameSite=Strict; Max-Age=0")
+}
+
+/// Stores a new session for a cookie, with the user agent and address of the
+/// client.
+pub async fn create_session(
+    pool: &PgPool,
+    user_id: i32,
+    cookie: &str,
+    client: (Option<&str>, Option<&str>),
+    timeouts: SessionTimeouts,
+) -> sqlx::Result<()> {
+    let now = now();
+    sqlx::query!(
+        r#"INSERT INTO sessions (user_id, token_hash, created_at, last_used, expires_at,
+           user_agent, ip) VALUES ( $1, $2, $3, $3, $4, $5, $6 )"#,
+        user_id,
+        hash_token(cookie),
+        now,
+        now + timeouts.absolute,
+        client.0,
+        client.1
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Returns the user of a session cookie if the session has not expired, and
+/// records its use.
+pub async fn session_user(
+    pool: &PgPool,
+    cookie: &str,
+    timeouts: SessionTimeouts,
+) -> sqlx::Result<Option<i32>> {
+    let now = now();
+    sqlx::query_scalar!(
+        r#"UPDATE sessions SET last_used=$2
+           WHERE token_hash=$1 AND expires_at > $2 AND last_used > $3
+           RETURNING user_id"#,
+        hash_token(cookie),
+        now,
+        now - timeouts.idle
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Ends all the sessions of a user, except the one of the `kept` cookie.
+pub(crate) async fn end_sessions(
+    pool: &PgPool,
+    user_id: i32,
+    kept: Option<&str>,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"DELETE FROM sessions WHERE user_id=$1 AND token_hash IS DISTINCT FROM $2"#,
+        user_id,
+        kept.map(hash_token)
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// API method to list the active sessions of the current user.
+pub async fn list_sessions(
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Session>>), (StatusCode, String)> {
+    let current = session_cookie(&headers).map(|cookie| hash_token(&cookie));
+    let sessions = sqlx::query!(
+        r#"SELECT id, token_hash, created_at, last_used, expires_at, user_agent, ip
+           FROM sessions WHERE user_id=$1 AND expires_at > $2 ORDER BY last_used DESC"#,
+        current_user.user_id,
+        now()
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .map(|row| Session {
+        id: row.id,
+        created_at: row.created_at,
+        last_used: row.last_used,
+        expires_at: row.expires_at,
+        user_agent: row.user_agent,
+        ip: row.ip,
+        current: current.as_ref() == Some(&row.token_hash),
+    })
+    .collect();
+    Ok((StatusCode::OK, Json(sessions)))
+}
+
+/// API method to revoke a session of the current user.
+pub async fn revoke_session(
+    Path(session_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"DELETE FROM sessions WHERE id=$1 AND user_id=$2"#,
+        session_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such session".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// API method to revoke all the sessions of the current user, except the one
+/// of the request with `keep_current`.
+pub async fn revoke_sessions(
+    Query(query): Query<RevokeSessionsQuery>,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let kept = session_cookie(&headers).filter(|_| query.keep_current);
+    end_sessions(&pool, current_user.user_id, kept.as_deref())
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Ends the session of the request, clears the session cookie and redirects
+/// to the home page.
+pub async fn logout(
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if let Some(cookie) = session_cookie(&headers) {
+        sqlx::query!(
+            r#"DELETE FROM sessions WHERE token_hash=$1"#,
+            hash_token(&cookie)
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    Ok((
+        StatusCode::SEE_OTHER,
+        [
+            (header::LOCATION, "/".to_string()),
+            (header::SET_COOKIE, expired_cookie()),
+        ],
+    ))
+}
+
+/// Removes the expired sessions.
+async fn purge_sessions(pool: &PgPool, timeouts: SessionTimeouts) -> sqlx::Result<u64> {
+    let now = now();
+    let res = sqlx::query!(
+        r#"DELETE FROM sessions WHERE expires_at <= $1 OR last_used <= $2"#,
+        now,
+        now - timeouts.idle
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+/// Spawns the hourly removal of the expired sessions.
+pub fn spawn_session_cleanup(pool: PgPool, timeouts: SessionTimeouts) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            match purge_sessions(&pool, timeouts).await {
+                Ok(0) => (),
+                Ok(sessions) => tracing::debug!("removed {sessions} expired sessions"),
+                Err(e) => tracing::error!("error removing the expired sessions: {e}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,300 @@
+use crate::auth::SharedPdb;
+use crate::settings;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many keys a throttle tracks at most. Made-up usernames must not grow
+/// the memory of the server without bound.
+const MAX_KEYS: usize = 10_000;
+
+/// How many failed attempts are allowed before the backoff starts, and how
+/// long the backoff can grow.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// Failed attempts allowed without delay.
+    pub free_attempts: u32,
+    /// The longest delay imposed after a failure. The failures are forgotten
+    /// after this long without new ones.
+    pub max_lockout: Duration,
+}
+
+/// The limits of the logins per username, of the logins per address and of
+/// the token guesses per address.
+#[derive(Clone, Copy, Debug)]
+pub struct ThrottleSettings {
+    /// Limits of the failed logins of a username.
+    pub users: ThrottleLimits,
+    /// Limits of the failed logins and token guesses from an address.
+    pub ips: ThrottleLimits,
+}
+
+impl Default for ThrottleSettings {
+    fn default() -> Self {
+        let max_lockout = Duration::from_secs(15 * 60);
+        ThrottleSettings {
+            users: ThrottleLimits {
+                free_attempts: 5,
+                max_lockout,
+            },
+            ips: ThrottleLimits {
+                free_attempts: 20,
+                max_lockout,
+            },
+        }
+    }
+}
+
+impl From<&settings::Auth> for ThrottleSettings {
+    fn from(auth: &settings::Auth) -> Self {
+        let max_lockout = Duration::from_secs(auth.lockout_minutes * 60);
+        ThrottleSettings {
+            users: ThrottleLimits {
+                free_attempts: auth.login_attempts,
+                max_lockout,
+            },
+            ips: ThrottleLimits {
+                free_attempts: auth.ip_attempts,
+                max_lockout,
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    blocked_until: Instant,
+}
+
+/// Failed attempts per key, a username or an address. After the free
+/// attempts, each failure blocks the key for twice as long as the previous
+/// one, up to the maximum lockout.
+#[derive(Debug)]
+pub struct Throttle {
+    limits: ThrottleLimits,
+    max_keys: usize,
+    failures: Mutex<HashMap<String, Failures>>,
+}
+
+impl Throttle {
+    /// Creates a throttle without failures.
+    pub fn new(limits: ThrottleLimits) -> Throttle {
+        Throttle {
+            limits,
+            max_keys: MAX_KEYS,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long a key is still blocked, if it is.
+    pub fn retry_after(&self, key: &str, now: Instant) -> Option<Duration> {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(key)
+            .and_then(|failures| failures.blocked_until.checked_duration_since(now))
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Reserves an attempt, or returns how long the key is still blocked. The
+    /// attempt is counted as a failure up front, so that concurrent attempts
+    /// are throttled like sequential ones: it must be released if it succeeds.
+    pub fn attempt(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut all_failures = self.failures.lock().unwrap();
+        if all_failures.len() >= self.max_keys && !all_failures.contains_key(key) {
+            self.evict(&mut all_failures, now);
+        }
+        let failures = all_failures.entry(key.to_string()).or_insert(Failures {
+            count: 0,
+            last: now,
+            blocked_until: now,
+        });
+        if let Some(wait) = failures
+            .blocked_until
+            .checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())
+        {
+            return Err(wait);
+        }
+        if now.duration_since(failures.last) > self.limits.max_lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        failures.blocked_until = now + self.delay(failures.count);
+        Ok(())
+    }
+
+    /// Gives back an attempt which did not fail, without forgetting the other
+    /// failures of the key.
+    pub fn release(&self, key: &str) {
+        let mut all_failures = self.failures.lock().unwrap();
+        if let Some(failures) = all_failures.get_mut(key) {
+            failures.count = failures.count.saturating_sub(1);
+            if failures.count == 0 {
+                all_failures.remove(key);
+            } else {
+                failures.blocked_until = failures.last + self.delay(failures.count);
+            }
+        }
+    }
+
+    /// Forgets the failures of a key after a successful attempt.
+    pub fn succeed(&self, key: &str) {
+        self.failures.lock().unwrap().remove(key);
+    }
+
+    /// Forgets the failures older than the maximum lockout.
+    pub fn purge(&self, now: Instant) {
+        let max_lockout = self.limits.max_lockout;
+        self.failures
+            .lock()
+            .unwrap()
+            .retain(|_, failures| now.duration_since(failures.last) <= max_lockout);
+    }
+
+    /// Makes room for new keys: forgets the old failures, then the oldest
+    /// quarter of the keys if there are still too many.
+    fn evict(&self, all_failures: &mut HashMap<String, Failures>, now: Instant) {
+        let max_lockout = self.limits.max_lockout;
+        all_failures.retain(|_, failures| now.duration_since(failures.last) <= max_lockout);
+        if all_failures.len() >= self.max_keys {
+            let mut lasts: Vec<Instant> = all_failures.values().map(|f| f.last).collect();
+            let index = lasts.len() / 4;
+            let (_, &mut oldest_kept, _) = lasts.select_nth_unstable(index);
+            all_failures.retain(|_, failures| failures.last > oldest_kept);
+        }
+    }
+
+    /// The delay after a number of consecutive failures: none for the free
+    /// attempts, then one second doubled at each failure.
+    fn delay(&self, count: u32) -> Duration {
+        match count.checked_sub(self.limits.free_attempts.saturating_add(1)) {
+            None => Duration::ZERO,
+            Some(exponent) => Duration::from_secs(1)
+                .checked_mul(2u32.saturating_pow(exponent))
+                .unwrap_or(Duration::MAX)
+                .min(self.limits.max_lockout),
+        }
+    }
+}
+
+/// The value of a `Retry-After` header, in whole seconds.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+/// Spawns the periodic removal of the old failed attempts.
+pub fn spawn_throttle_cleanup(pdb: SharedPdb) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60));
+        loop {
+            interval.tick().await;
+            pdb.purge_throttles();
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_back_off_exponentially() {
+        let throttle = Throttle::new(ThrottleLimits {
+            free_attempts: 2,
+            max_lockout: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        assert_eq!(throttle.attempt("alice", start), Ok(()));
+        assert_eq!(throttle.attempt("alice", start), Ok(()));
+        assert_eq!(throttle.retry_after("alice", start), None);
+        assert_eq!(throttle.attempt("alice", start), Ok(()));
+        assert_eq!(
+            throttle.retry_after("alice", start),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            throttle.attempt("alice", start),
+            Err(Duration::from_secs(1))
+        );
+        assert_eq!(throttle.retry_after("bob", start), None);
+        let mut now = start;
+        for _ in 0..40 {
+            now += throttle.retry_after("alice", now).unwrap_or_default();
+            assert_eq!(throttle.attempt("alice", now), Ok(()));
+        }
+        assert_eq!(
+            throttle.retry_after("alice", now + Duration::from_secs(30)),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            throttle.retry_after("alice", now + Duration::from_secs(60)),
+            None
+        );
+    }
+
+    #[test]
+    fn should_reserve_concurrent_attempts() {
+        let throttle = Throttle::new(ThrottleLimits {
+            free_attempts: 2,
+            max_lockout: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        // Attempts started at once, before any of them failed: only the free
+        // ones and the one starting the backoff are let through.
+        let allowed = (0..10)
+            .filter(|_| throttle.attempt("alice", start).is_ok())
+            .count();
+        assert_eq!(allowed, 3);
+        // A successful attempt is given back without forgetting the others.
+        throttle.release("alice");
+        assert_eq!(throttle.retry_after("alice", start), None);
+        assert_eq!(throttle.attempt("alice", start), Ok(()));
+        assert!(throttle.retry_after("alice", start).is_some());
+        throttle.release("alice");
+        throttle.release("alice");
+        throttle.release("alice");
+        assert!(throttle.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn should_bound_the_tracked_keys() {
+        let mut throttle = Throttle::new(ThrottleLimits {
+            free_attempts: 1,
+            max_lockout: Duration::from_secs(60),
+        });
+        throttle.max_keys = 8;
+        let start = Instant::now();
+        for i in 0..100u64 {
+            let now = start + Duration::from_millis(i);
+            throttle.attempt(&format!("user{i}"), now).unwrap();
+            assert!(throttle.failures.lock().unwrap().len() <= 8);
+        }
+        // The most recent keys are kept.
+        assert!(throttle.failures.lock().unwrap().contains_key("user99"));
+        assert!(!throttle.failures.lock().unwrap().contains_key("user0"));
+    }
+
+    #[test]
+    fn should_forget_failures() {
+        let throttle = Throttle::new(ThrottleLimits {
+            free_attempts: 1,
+            max_lockout: Duration::from_secs(60),
+        });
+        let start = Instant::now();
+        throttle.attempt("alice", start).unwrap();
+        throttle.attempt("bob", start).unwrap();
+        throttle.succeed("alice");
+        assert_eq!(throttle.attempt("alice", start), Ok(()));
+        assert_eq!(throttle.retry_after("alice", start), None);
+        // The failures of bob are forgotten after a minute without failures.
+        let later = start + Duration::from_secs(61);
+        assert_eq!(throttle.attempt("bob", later), Ok(()));
+        assert_eq!(throttle.retry_after("bob", later), None);
+        throttle.purge(later + Duration::from_secs(61));
+        assert!(throttle.failures.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+/// Deletion of points by their owner. Deleted points are hidden right away,
+/// but are only removed once the deletion can no longer be undone.
+use crate::api::{parse_datetime, serialize_timestamp};
+use crate::auth::CurrentUser;
+use crate::util::now;
+use crate::visited::invalidate_days;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::{Date, PrimitiveDateTime};
+use sqlx::{Postgres, Transaction};
+use std::collections::HashSet;
+use time::Duration;
+
+/// How long a deletion can be undone.
+const UNDO_WINDOW: Duration = Duration::days(7);
+
+/// Query parameters of a range deletion.
+#[derive(Deserialize, Debug)]
+pub struct DeleteQuery {
+    /// Start of the deleted time range.
+    start: String,
+    /// End of the deleted time range.
+    end: String,
+    /// Only delete the points inside this box, given as
+    /// `min_lon,min_lat,max_lon,max_lat`.
+    bbox: Option<String>,
+    /// Only delete the points of this device.
+    device: Option<String>,
+}
+
+/// A set of points deleted together.
+#[derive(Serialize, Debug)]
+pub struct Deletion {
+    id: i32,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    /// The points are removed for good after this date.
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+    /// Number of deleted points.
+    points: i64,
+}
+
+/// Parses a `min_lon,min_lat,max_lon,max_lat` bounding box.
+fn parse_bbox(bbox: &str) -> Option<[f64; 4]> {
+    let values = bbox
+        .split(',')
+        .map(|v| v.trim().parse().ok())
+        .collect::<Option<Vec<f64>>>()?;
+    match values[..] {
+        [min_x, min_y, max_x, max_y] if min_x <= max_x && min_y <= max_y => {
+            Some([min_x, min_y, max_x, max_y])
+        }
+        _ => None,
+    }
+}
+
+async fn new_deletion(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+) -> sqlx::Result<(i32, PrimitiveDateTime)> {
+    let row = sqlx::query!(
+        r#"INSERT INTO deletions (user_identifier, created_at) VALUES ( $1, $2 )
+           RETURNING id, created_at"#,
+        user_id,
+        now()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok((row.id, row.created_at))
+}
+
+/// Commits a deletion if it matched some points, and forgets what was computed
+/// from the days it touched.
+async fn finish_deletion(
+    tx: Transaction<'_, Postgres>,
+    pool: &PgPool,
+    user_id: i32,
+    (id, created_at): (i32, PrimitiveDateTime),
+    days: Vec<Option<Date>>,
+) -> Result<(StatusCode, Json<Deletion>), (StatusCode, String)> {
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    if days.is_empty() {
+        tx.rollback().await.map_err(internal_error)?;
+        return Err((StatusCode::NOT_FOUND, "No matching points".to_string()));
+    }
+    tx.commit().await.map_err(internal_error)?;
+    let points = days.len() as i64;
+    let days: Vec<Date> = days
+        .into_iter()
+        .flatten()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    if let Err(e) = invalidate_days(pool, user_id, &days).await {
+        tracing::error!("error invalidating the visited places: {e}");
+    }
+    Ok((
+        StatusCode::OK,
+        Json(Deletion {
+            id,
+            created_at,
+            expires_at: created_at + UNDO_WINDOW,
+            points,
+        }),
+    ))
+}
+
+/// API method to delete the points of the current user in a time range,
+/// optionally restricted to a bounding box and a device.
+pub async fn delete_points(
+    Query(query): Query<DeleteQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Deletion>), (StatusCode, String)> {
+    let parse = |date: &str| {
+        parse_datetime(date).ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}")))
+    };
+    let (start, end) = (parse(&query.start)?, parse(&query.end)?);
+    let bbox = match &query.bbox {
+        Some(bbox) => Some(parse_bbox(bbox).ok_or((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid bounding box {bbox}"),
+        ))?),
+        None => None,
+    };
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    let deletion = new_deletion(&mut tx, current_user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let days = sqlx::query!(
+        r#"UPDATE points SET deletion=$2
+           WHERE user_identifier=$1 AND deletion IS NULL AND time_id >= $3 AND time_id < $4
+           AND ($5::TEXT IS NULL OR user_id=$5)
+           AND ($6::FLOAT8 IS NULL OR (coords_x BETWEEN $6 AND $8 AND coords_y BETWEEN $7 AND $9))
+           RETURNING DATE(time_id) AS day"#,
+        current_user.user_id,
+        deletion.0,
+        start,
+        end,
+        query.device,
+        bbox.map(|b| b[0]),
+        bbox.map(|b| b[1]),
+        bbox.map(|b| b[2]),
+        bbox.map(|b| b[3])
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .map(|row| row.day)
+    .collect();
+    finish_deletion(tx, &pool, current_user.user_id, deletion, days).await
+}
+
+/// API method to delete a single point of the current user.
+pub async fn delete_point(
+    Path(point_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Deletion>), (StatusCode, String)> {
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    let deletion = new_deletion(&mut tx, current_user.user_id)
+        .await
+        .map_err(internal_error)?;
+    let days = sqlx::query!(
+        r#"UPDATE points SET deletion=$3
+           WHERE user_identifier=$1 AND pt_id=$2 AND deletion IS NULL
+           RETURNING DATE(time_id) AS day"#,
+        current_user.user_id,
+        point_id,
+        deletion.0
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .map(|row| row.day)
+    .collect();
+    finish_deletion(tx, &pool, current_user.user_id, deletion, days).await
+}
+
+/// API method to list the deletions of the current user that can still be
+/// undone.
+pub async fn list_deletions(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Deletion>>), (StatusCode, String)> {
+    let deletions = sqlx::query!(
+        r#"SELECT deletions.id, created_at, COUNT(pt_id) AS "points!" FROM deletions
+           LEFT JOIN points ON points.deletion=deletions.id
+           WHERE deletions.user_identifier=$1 AND created_at > $2
+           GROUP BY deletions.id ORDER BY deletions.id"#,
+        current_user.user_id,
+        now() - UNDO_WINDOW
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .map(|row| Deletion {
+        id: row.id,
+        created_at: row.created_at,
+        expires_at: row.created_at + UNDO_WINDOW,
+        points: row.points,
+    })
+    .collect();
+    Ok((StatusCode::OK, Json(deletions)))
+}
+
+/// API method to undo a deletion of the current user.
+pub async fn restore_deletion(
+    Path(deletion_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    // The points must be restored before the deletion is removed, otherwise
+    // they would be removed with it.
+    let days: Vec<Date> = sqlx::query!(
+        r#"UPDATE points SET deletion=NULL WHERE deletion=(
+               SELECT id FROM deletions WHERE id=$1 AND user_identifier=$2 AND created_at > $3)
+           RETURNING DATE(time_id) AS day"#,
+        deletion_id,
+        current_user.user_id,
+        now() - UNDO_WINDOW
+    )
+    .fetch_all(&mut tx)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .filter_map(|row| row.day)
+    .collect::<HashSet<_>>()
+    .into_iter()
+    .collect();
+    let res = sqlx::query!(
+        r#"DELETE FROM deletions WHERE id=$1 AND user_identifier=$2 AND created_at > $3"#,
+        deletion_id,
+        current_user.user_id,
+        now() - UNDO_WINDOW
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if res.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "No such deletion".to_string()));
+    }
+    tx.commit().await.map_err(internal_error)?;
+    if let Err(e) = invalidate_days(&pool, current_user.user_id, &days).await {
+        tracing::error!("error invalidating the visited places: {e}");
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Removes for good the points of the deletions that can no longer be undone.
+/// Returns the number of removed points. With `dry_run`, nothing is removed.
+pub(crate) async fn purge_deletions(pool: &PgPool, dry_run: bool) -> sqlx::Result<i64> {
+    let expired = now() - UNDO_WINDOW;
+    let mut tx = pool.begin().await?;
+    let points = sqlx::query!(
+        r#"SELECT COUNT(*) AS "points!" FROM points
+           JOIN deletions ON points.deletion=deletions.id WHERE created_at <= $1"#,
+        expired
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .points;
+    if !dry_run {
+        // The points are removed with their deletion.
+        sqlx::query!(r#"DELETE FROM deletions WHERE created_at <= $1"#, expired)
+            .execute(&mut tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_parse_bounding_boxes() {
+        assert_eq!(
+            parse_bbox("2.25,48.8,2.45, 48.9"),
+            Some([2.25, 48.8, 2.45, 48.9])
+        );
+        assert_eq!(parse_bbox("2.45,48.8,2.25,48.9"), None);
+        assert_eq!(parse_bbox("2.25,48.8,2.45"), None);
+        assert_eq!(parse_bbox("a,b,c,d"), None);
+    }
+}
@@ -0,0 +1,341 @@
+/// The devices of each user. Devices are created when they first send points
+/// and can be renamed, given their own input token, or retired.
+use crate::api::{serialize_optional_timestamp, serialize_timestamp};
+use crate::auth::{CurrentUser, Scope};
+use crate::util::now;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+
+const DEVICE_TOKEN_LEN: usize = 64;
+
+/// The scope of the device tokens: they can only send points.
+pub(crate) const DEVICE_TOKEN_SCOPE: Scope = Scope::Ingest;
+
+/// A device of a user.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct Device {
+    id: i32,
+    /// The identifier sent by the device with its points.
+    device_id: String,
+    name: String,
+    description: Option<String>,
+    color: Option<String>,
+    /// The input token of the device. Only returned when it is created.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    input_token: Option<String>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    last_seen: Option<PrimitiveDateTime>,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    retired_at: Option<PrimitiveDateTime>,
+}
+
+/// The body of a device creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewDevice {
+    /// The identifier the device sends with its points.
+    device_id: String,
+    /// Display name, defaults to the identifier.
+    name: Option<String>,
+    description: Option<String>,
+    color: Option<String>,
+}
+
+/// The body of a device update request. Missing fields are left unchanged.
+#[derive(Deserialize, Debug)]
+pub struct DeviceUpdate {
+    name: Option<String>,
+    description: Option<String>,
+    color: Option<String>,
+}
+
+fn random_token() -> String {
+    let mut rng = rand::thread_rng();
+    (&mut rng)
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(DEVICE_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// How the device of a request is found.
+#[derive(Debug, PartialEq, Eq)]
+enum DeviceLookup<'a> {
+    /// The device of the token of the request.
+    Token(i32),
+    /// The device of the user with the identifier sent with the points.
+    Identifier(&'a str),
+}
+
+impl<'a> DeviceLookup<'a> {
+    /// Requests authenticated with a device token always go to that device,
+    /// whatever identifier the points carry.
+    fn new(current_user: &CurrentUser, device_id: &'a str) -> Self {
+        match current_user.device_id {
+            Some(id) => DeviceLookup::Token(id),
+            None => DeviceLookup::Identifier(device_id),
+        }
+    }
+}
+
+/// A device the points of a request are sent to.
+#[derive(Debug)]
+struct ResolvedDevice {
+    id: i32,
+    device_id: String,
+    retired: bool,
+}
+
+impl ResolvedDevice {
+    /// The id and identifier of the device, unless it is retired.
+    fn usable(self) -> Option<(i32, String)> {
+        (!self.retired).then_some((self.id, self.device_id))
+    }
+}
+
+/// Finds the device the points of a request belong to, creating it if needed.
+/// Returns the id and identifier of the device, or `None` if it is retired.
+pub(crate) async fn resolve_device(
+    pool: &PgPool,
+    current_user: &CurrentUser,
+    device_id: &str,
+) -> sqlx::Result<Option<(i32, String)>> {
+    let device = match DeviceLookup::new(current_user, device_id) {
+        DeviceLookup::Token(id) => {
+            sqlx::query_as!(
+                ResolvedDevice,
+                r#"SELECT id, device_id, retired_at IS NOT NULL AS "retired!"
+                   FROM devices WHERE id=$1"#,
+                id
+            )
+            .fetch_one(pool)
+            .await?
+        }
+        // The no-op update makes RETURNING work for existing devices too.
+        DeviceLookup::Identifier(device_id) => {
+            sqlx::query_as!(
+                ResolvedDevice,
+                r#"INSERT INTO devices (user_identifier, device_id, name) VALUES ( $1, $2::VARCHAR, $2::VARCHAR )
+                   ON CONFLICT (user_identifier, device_id) DO UPDATE SET device_id=EXCLUDED.device_id
+                   RETURNING id, device_id, retired_at IS NOT NULL AS "retired!""#,
+                current_user.user_id,
+                device_id
+            )
+            .fetch_one(pool)
+            .await?
+        }
+    };
+    Ok(device.usable())
+}
+
+/// Records the time of the last point received from a device.
+pub(crate) async fn touch_device(
+    pool: &PgPool,
+    device: i32,
+    time: PrimitiveDateTime,
+) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"UPDATE devices SET last_seen=GREATEST(last_seen, $2) WHERE id=$1"#,
+        device,
+        time
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// API method to list the devices of the current user.
+pub async fn list_devices(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Device>>), (StatusCode, String)> {
+    let devices = sqlx::query_as!(
+        Device,
+        r#"SELECT id, device_id, name, description, color, NULL::TEXT AS "input_token?",
+           created_at, last_seen, retired_at
+           FROM devices WHERE user_identifier=$1 ORDER BY id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(devices)))
+}
+
+/// API method to create a device with its own input token. The token is only
+/// returned in this response.
+pub async fn create_device(
+    Json(new_device): Json<NewDevice>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Device>), (StatusCode, String)> {
+    if new_device.device_id.is_empty() || new_device.device_id.len() > 50 {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The device identifier must be between 1 and 50 characters".to_string(),
+        ));
+    }
+    let name = new_device
+        .name
+        .unwrap_or_else(|| new_device.device_id.clone());
+    sqlx::query_as!(
+        Device,
+        r#"INSERT INTO devices (user_identifier, device_id, name, description, color, input_token)
+           VALUES ( $1, $2, $3, $4, $5, $6 )
+           ON CONFLICT (user_identifier, device_id) DO NOTHING
+           RETURNING id, device_id, name, description, color, input_token,
+           created_at, last_seen, retired_at"#,
+        current_user.user_id,
+        new_device.device_id,
+        name,
+        new_device.description,
+        new_device.color,
+        random_token()
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(|device| (StatusCode::CREATED, Json(device)))
+    .ok_or((
+        StatusCode::CONFLICT,
+        "The device already exists".to_string(),
+    ))
+}
+
+/// API method to rename a device or change its description and color.
+pub async fn update_device(
+    Path(id): Path<i32>,
+    Json(update): Json<DeviceUpdate>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Device>), (StatusCode, String)> {
+    sqlx::query_as!(
+        Device,
+        r#"UPDATE devices SET name=COALESCE($3, name), description=COALESCE($4, description),
+           color=COALESCE($5, color)
+           WHERE id=$1 AND user_identifier=$2
+           RETURNING id, device_id, name, description, color, NULL::TEXT AS "input_token?",
+           created_at, last_seen, retired_at"#,
+        id,
+        current_user.user_id,
+        update.name,
+        update.description,
+        update.color
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(|device| (StatusCode::OK, Json(device)))
+    .ok_or((StatusCode::NOT_FOUND, "No such device".to_string()))
+}
+
+/// API method to give a device a new input token. The previous token stops
+/// working immediately.
+pub async fn regenerate_device_token(
+    Path(id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Device>), (StatusCode, String)> {
+    sqlx::query_as!(
+        Device,
+        r#"UPDATE devices SET input_token=$3
+           WHERE id=$1 AND user_identifier=$2 AND retired_at IS NULL
+           RETURNING id, device_id, name, description, color, input_token,
+           created_at, last_seen, retired_at"#,
+        id,
+        current_user.user_id,
+        random_token()
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(|device| (StatusCode::OK, Json(device)))
+    .ok_or((StatusCode::NOT_FOUND, "No such device".to_string()))
+}
+
+/// API method to retire a device. Its token is revoked and the points it sends
+/// are ignored, but its history is kept.
+pub async fn retire_device(
+    Path(id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"UPDATE devices SET retired_at=$3, input_token=NULL
+           WHERE id=$1 AND user_identifier=$2 AND retired_at IS NULL"#,
+        id,
+        current_user.user_id,
+        now()
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such device".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::allows;
+    use axum::http::Method;
+
+    fn device_user(device_id: Option<i32>) -> CurrentUser {
+        CurrentUser {
+            user_id: 1,
+            is_admin: false,
+            device_id,
+            scope: if device_id.is_some() {
+                DEVICE_TOKEN_SCOPE
+            } else {
+                Scope::Admin
+            },
+        }
+    }
+
+    #[test]
+    fn should_restrict_device_tokens_to_input() {
+        assert!(allows(DEVICE_TOKEN_SCOPE, &Method::POST, "/api/input"));
+        for (method, path) in [
+            (Method::GET, "/api/query"),
+            (Method::GET, "/api/devices"),
+            (Method::POST, "/api/devices/1/token"),
+            (Method::DELETE, "/api/devices/1"),
+            (Method::GET, "/api/tokens"),
+        ] {
+            assert!(!allows(DEVICE_TOKEN_SCOPE, &method, path));
+        }
+    }
+
+    #[test]
+    fn should_send_points_to_the_device_of_the_token() {
+        assert_eq!(
+            DeviceLookup::new(&device_user(Some(7)), "someone-else"),
+            DeviceLookup::Token(7)
+        );
+        assert_eq!(
+            DeviceLookup::new(&device_user(None), "phone"),
+            DeviceLookup::Identifier("phone")
+        );
+    }
+
+    #[test]
+    fn should_reject_retired_devices() {
+        let device = |retired| ResolvedDevice {
+            id: 7,
+            device_id: "phone".to_string(),
+            retired,
+        };
+        assert_eq!(device(false).usable(), Some((7, "phone".to_string())));
+        assert_eq!(device(true).usable(), None);
+    }
+}
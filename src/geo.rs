@@ -0,0 +1,186 @@
+/// Geometry helpers shared by the geofencing and analysis code. Coordinates are
+/// always `[longitude, latitude]` pairs in degrees, the same order as the
+/// GeoJSON objects sent by Overland and the `coords_x`/`coords_y` columns.
+use serde::{Deserialize, Serialize};
+
+const EARTH_RADIUS_M: f64 = 6_371_008.8;
+
+/// Great-circle distance in meters between two `[longitude, latitude]` points.
+pub fn haversine(a: [f64; 2], b: [f64; 2]) -> f64 {
+    let (lat1, lat2) = (a[1].to_radians(), b[1].to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (b[0] - a[0]).to_radians();
+    let h = (d_lat / 2.).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.).sin().powi(2);
+    2. * EARTH_RADIUS_M * h.sqrt().asin()
+}
+
+/// Even-odd rule point in polygon test. The polygon ring does not need to be
+/// explicitly closed.
+pub fn point_in_polygon(point: [f64; 2], polygon: &[[f64; 2]]) -> bool {
+    let mut inside = false;
+    if polygon.len() < 3 {
+        return false;
+    }
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (pi, pj) = (polygon[i], polygon[j]);
+        if ((pi[1] > point[1]) != (pj[1] > point[1]))
+            && (point[0] < (pj[0] - pi[0]) * (point[1] - pi[1]) / (pj[1] - pi[1]) + pi[0])
+        {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+/// Distance in meters from a point to the segment `[a, b]`, using a local
+/// equirectangular projection. This is precise enough for the short segments of
+/// a GPS track.
+fn segment_distance(point: [f64; 2], a: [f64; 2], b: [f64; 2]) -> f64 {
+    let scale_x = point[1].to_radians().cos() * EARTH_RADIUS_M.to_radians();
+    let scale_y = EARTH_RADIUS_M.to_radians();
+    let project = |p: [f64; 2]| ((p[0] - point[0]) * scale_x, (p[1] - point[1]) * scale_y);
+    let (ax, ay) = project(a);
+    let (bx, by) = project(b);
+    let (dx, dy) = (bx - ax, by - ay);
+    let len2 = dx * dx + dy * dy;
+    let t = if len2 == 0. {
+        0.
+    } else {
+        (-(ax * dx + ay * dy) / len2).clamp(0., 1.)
+    };
+    ((ax + t * dx).powi(2) + (ay + t * dy).powi(2)).sqrt()
+}
+
+/// Simplifies a path with the Douglas-Peucker algorithm. Points closer than
+/// `tolerance` meters to the simplified path are removed.
+pub fn simplify(path: &[[f64; 2]], tolerance: f64) -> Vec<[f64; 2]> {
+    path.iter()
+        .zip(simplify_mask(path, tolerance))
+        .filter_map(|(p, k)| if k { Some(*p) } else { None })
+        .collect()
+}
+
+/// Same as `simplify`, but tells for each point of the path if it is kept.
+pub fn simplify_mask(path: &[[f64; 2]], tolerance: f64) -> Vec<bool> {
+    if path.len() < 3 {
+        return vec![true; path.len()];
+    }
+    let mut keep = vec![false; path.len()];
+    keep[0] = true;
+    keep[path.len() - 1] = true;
+    let mut stack = vec![(0, path.len() - 1)];
+    while let Some((first, last)) = stack.pop() {
+        let (mut max_dist, mut max_idx) = (0., first);
+        for idx in first + 1..last {
+            let dist = segment_distance(path[idx], path[first], path[last]);
+            if dist > max_dist {
+                max_dist = dist;
+                max_idx = idx;
+            }
+        }
+        if max_dist > tolerance {
+            keep[max_idx] = true;
+            stack.push((first, max_idx));
+            stack.push((max_idx, last));
+        }
+    }
+    keep
+}
+
+/// A user defined area, either a circle around a point or a polygon.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Shape {
+    /// A circle defined by its center and radius in meters.
+    Circle {
+        /// Center of the circle.
+        center: [f64; 2],
+        /// Radius in meters.
+        radius: f64,
+    },
+    /// A polygon defined by the list of its vertices.
+    Polygon {
+        /// Vertices of the polygon.
+        coordinates: Vec<[f64; 2]>,
+    },
+}
+
+impl Shape {
+    /// Checks if a point lies inside the shape.
+    pub fn contains(&self, point: [f64; 2]) -> bool {
+        match self {
+            Shape::Circle { center, radius } => haversine(*center, point) <= *radius,
+            Shape::Polygon { coordinates } => point_in_polygon(point, coordinates),
+        }
+    }
+
+    /// The center of the shape. For polygons, this is the average of the
+    /// vertices.
+    pub fn center(&self) -> [f64; 2] {
+        match self {
+            Shape::Circle { center, .. } => *center,
+            Shape::Polygon { coordinates } => {
+                let n = coordinates.len().max(1) as f64;
+                let (x, y) = coordinates
+                    .iter()
+                    .fold((0., 0.), |(x, y), c| (x + c[0], y + c[1]));
+                [x / n, y / n]
+            }
+        }
+    }
+
+    /// Checks that the shape is usable: a positive radius or at least three
+    /// vertices.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Shape::Circle { radius, .. } => *radius > 0.,
+            Shape::Polygon { coordinates } => coordinates.len() >= 3,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_compute_haversine_distance() {
+        // Paris to London is roughly 344km.
+        let paris = [2.3522, 48.8566];
+        let london = [-0.1278, 51.5074];
+        let dist = haversine(paris, london);
+        assert!((dist - 343_500.).abs() < 2_000., "{dist}");
+        assert_eq!(haversine(paris, paris), 0.);
+    }
+
+    #[test]
+    fn should_simplify_paths() {
+        // A straight line with a small wiggle and a real corner.
+        let path = vec![[0., 0.], [0.001, 0.000001], [0.002, 0.], [0.002, 0.001]];
+        assert_eq!(
+            simplify(&path, 5.),
+            vec![[0., 0.], [0.002, 0.], [0.002, 0.001]]
+        );
+        assert_eq!(simplify(&path, 0.), path);
+        assert_eq!(simplify_mask(&path, 5.), vec![true, false, true, true]);
+    }
+
+    #[test]
+    fn should_check_points_in_shapes() {
+        let square = Shape::Polygon {
+            coordinates: vec![[0., 0.], [1., 0.], [1., 1.], [0., 1.]],
+        };
+        assert!(square.contains([0.5, 0.5]));
+        assert!(!square.contains([1.5, 0.5]));
+        assert_eq!(square.center(), [0.5, 0.5]);
+
+        let circle = Shape::Circle {
+            center: [2.3522, 48.8566],
+            radius: 100.,
+        };
+        assert!(circle.contains([2.3523, 48.8566]));
+        assert!(!circle.contains([2.3622, 48.8566]));
+    }
+}
@@ -0,0 +1,246 @@
+/// Offline reverse geocoding from GeoNames dumps. The gazetteer is loaded in
+/// memory at startup and indexed in a grid of one degree cells, no network
+/// request is ever made.
+use crate::geo::haversine;
+use crate::settings::Geocoder as GeocoderSettings;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::sync::Arc;
+
+/// Localities further than this many cells away are never returned.
+const MAX_RING: i32 = 5;
+
+/// The geocoder shared between requests. `None` when no gazetteer is
+/// configured.
+pub type SharedGeocoder = Option<Arc<Geocoder>>;
+
+/// The nearest locality to a point.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Place {
+    /// Name of the locality.
+    pub name: String,
+    /// Name of the first level administrative region, or its code when the
+    /// region names are not loaded.
+    pub region: Option<String>,
+    /// ISO 3166 country code.
+    pub country_code: String,
+    /// Name of the country, when the country names are loaded.
+    pub country: Option<String>,
+    /// Distance to the locality in meters.
+    pub distance: f64,
+}
+
+struct Locality {
+    name: String,
+    coords: [f64; 2],
+    country_code: String,
+    admin1_code: String,
+}
+
+/// An in-memory spatial index of localities.
+pub struct Geocoder {
+    localities: Vec<Locality>,
+    grid: HashMap<(i32, i32), Vec<usize>>,
+    regions: HashMap<String, String>,
+    countries: HashMap<String, String>,
+}
+
+/// Query parameters of the reverse geocoding API.
+#[derive(Deserialize, Debug)]
+pub struct GeocodeQuery {
+    /// Longitude of the point.
+    lon: f64,
+    /// Latitude of the point.
+    lat: f64,
+}
+
+fn cell(coords: [f64; 2]) -> (i32, i32) {
+    (
+        (coords[0].floor() as i32).rem_euclid(360),
+        coords[1].floor() as i32,
+    )
+}
+
+impl Geocoder {
+    /// Loads the gazetteer files listed in the settings.
+    pub fn load(settings: &GeocoderSettings) -> io::Result<Geocoder> {
+        let open = |path: &String| File::open(path).map(BufReader::new);
+        let geocoder = Geocoder::from_readers(
+            open(&settings.cities_path)?,
+            settings.admin1_path.as_ref().map(open).transpose()?,
+            settings.countries_path.as_ref().map(open).transpose()?,
+        )?;
+        tracing::info!("loaded {} localities", geocoder.localities.len());
+        Ok(geocoder)
+    }
+
+    /// Builds a geocoder from a GeoNames cities dump (e.g. `cities1000.txt`)
+    /// and optionally the `admin1CodesASCII.txt` and `countryInfo.txt` files.
+    pub fn from_readers<R: BufRead>(
+        cities: R,
+        admin1: Option<R>,
+        countries: Option<R>,
+    ) -> io::Result<Geocoder> {
+        let mut localities = vec![];
+        let mut grid: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for line in cities.lines() {
+            let line = line?;
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 11 {
+                continue;
+            }
+            let (Ok(lat), Ok(lon)) = (fields[4].parse::<f64>(), fields[5].parse::<f64>()) else {
+                continue;
+            };
+            grid.entry(cell([lon, lat]))
+                .or_default()
+                .push(localities.len());
+            localities.push(Locality {
+                name: fields[1].to_string(),
+                coords: [lon, lat],
+                country_code: fields[8].to_string(),
+                admin1_code: fields[10].to_string(),
+            });
+        }
+        // Both files are tab separated with the code in the first column and
+        // the name in the second one for admin1 codes, fifth for countries.
+        let read_names =
+            |reader: Option<R>, column: usize| -> io::Result<HashMap<String, String>> {
+                let mut names = HashMap::new();
+                for line in reader.into_iter().flat_map(|r| r.lines()) {
+                    let line = line?;
+                    if line.starts_with('#') {
+                        continue;
+                    }
+                    let fields: Vec<&str> = line.split('\t').collect();
+                    if let (Some(code), Some(name)) = (fields.first(), fields.get(column)) {
+                        names.insert(code.to_string(), name.to_string());
+                    }
+                }
+                Ok(names)
+            };
+        Ok(Geocoder {
+            localities,
+            grid,
+            regions: read_names(admin1, 1)?,
+            countries: read_names(countries, 4)?,
+        })
+    }
+
+    /// Finds the nearest locality to a `[longitude, latitude]` point.
+    pub fn nearest(&self, point: [f64; 2]) -> Option<Place> {
+        let (cx, cy) = cell(point);
+        // Smallest distance covered by a ring of cells around the point.
+        let ring_width = 111_000. * point[1].to_radians().cos().max(0.1);
+        let mut best: Option<(usize, f64)> = None;
+        for ring in 0..=MAX_RING {
+            for dx in -ring..=ring {
+                for dy in -ring..=ring {
+                    if dx.abs() != ring && dy.abs() != ring {
+                        continue;
+                    }
+                    let Some(indices) = self.grid.get(&((cx + dx).rem_euclid(360), cy + dy)) else {
+                        continue;
+                    };
+                    for &idx in indices {
+                        let dist = haversine(point, self.localities[idx].coords);
+                        if best.is_none_or(|(_, d)| dist < d) {
+                            best = Some((idx, dist));
+                        }
+                    }
+                }
+            }
+            if matches!(best, Some((_, d)) if d <= ring as f64 * ring_width) {
+                break;
+            }
+        }
+        best.map(|(idx, distance)| {
+            let locality = &self.localities[idx];
+            let admin1 = format!("{}.{}", locality.country_code, locality.admin1_code);
+            Place {
+                name: locality.name.clone(),
+                region: self
+                    .regions
+                    .get(&admin1)
+                    .cloned()
+                    .or_else(|| Some(locality.admin1_code.clone()).filter(|c| !c.is_empty())),
+                country_code: locality.country_code.clone(),
+                country: self.countries.get(&locality.country_code).cloned(),
+                distance,
+            }
+        })
+    }
+}
+
+/// API method to get the nearest locality of a point.
+pub async fn reverse_geocode(
+    Query(query): Query<GeocodeQuery>,
+    Extension(geocoder): Extension<SharedGeocoder>,
+) -> Result<(StatusCode, Json<Place>), (StatusCode, String)> {
+    let geocoder = geocoder.ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "Reverse geocoding is not configured".to_string(),
+    ))?;
+    geocoder
+        .nearest([query.lon, query.lat])
+        .map(|place| (StatusCode::OK, Json(place)))
+        .ok_or((StatusCode::NOT_FOUND, "No locality nearby".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CITIES: &str = "2988507\tParis\tParis\t\t48.85341\t2.3488\tP\tPPLC\tFR\t\t11\t75\t751\t75056\t2138551\t\t42\tEurope/Paris\t2022-03-09
+2990969\tNantes\tNantes\t\t47.21725\t-1.55336\tP\tPPLA\tFR\t\t52\t44\t441\t44109\t277269\t\t26\tEurope/Paris\t2022-03-09
+2643743\tLondon\tLondon\t\t51.50853\t-0.12574\tP\tPPLC\tGB\t\tENG\tGLA\t\t\t8961989\t\t25\tEurope/London\t2022-03-09
+4030939\tRikitea\tRikitea\t\t-23.1203\t-134.96917\tP\tPPLA\tPF\t\t\t\t\t\t739\t\t5\tPacific/Gambier\t2022-03-09";
+
+    fn geocoder() -> Geocoder {
+        Geocoder::from_readers(
+            CITIES.as_bytes(),
+            Some("FR.11\tÎle-de-France\tIle-de-France\t3012874".as_bytes()),
+            Some("# ISO\tISO3\nFR\tFRA\t250\tFR\tFrance\tParis".as_bytes()),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn should_find_nearest_locality() {
+        let geocoder = geocoder();
+        let place = geocoder.nearest([2.35, 48.86]).unwrap();
+        assert_eq!(place.name, "Paris");
+        assert_eq!(place.region, Some("Île-de-France".to_string()));
+        assert_eq!(place.country, Some("France".to_string()));
+        assert!(place.distance < 2_000.);
+
+        // Without a region name, the region code is returned.
+        let place = geocoder.nearest([-1.5, 47.]).unwrap();
+        assert_eq!(place.name, "Nantes");
+        assert_eq!(place.region, Some("52".to_string()));
+        assert_eq!(place.country, Some("France".to_string()));
+
+        // London is closer than Paris even though they are in different cells.
+        assert_eq!(geocoder.nearest([0.5, 51.]).unwrap().name, "London");
+    }
+
+    #[test]
+    fn should_not_find_far_localities() {
+        let geocoder = geocoder();
+        assert_eq!(geocoder.nearest([100., 0.]), None);
+        let place = geocoder.nearest([0., 51.5]).unwrap();
+        assert_eq!(
+            (place.region, place.country),
+            (Some("ENG".to_string()), None)
+        );
+        assert_eq!(
+            geocoder.nearest([-134.9, -23.1]).unwrap().country_code,
+            "PF"
+        );
+    }
+}
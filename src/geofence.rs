@@ -0,0 +1,331 @@
+/// Geofences are user defined areas. Devices entering or leaving them are
+/// detected when new points are added, stored as events and sent to the user
+/// webhooks.
+use crate::api::{parse_datetime, serialize_timestamp};
+use crate::auth::CurrentUser;
+use crate::geo::Shape;
+use crate::webhook::WebhookSender;
+use axum::extract::{Path, Query};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use std::collections::HashMap;
+
+const MAX_EVENTS: i64 = 1000;
+
+/// A geofence defined by a user.
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct Geofence {
+    id: i32,
+    name: String,
+    shape: sqlx::types::Json<Shape>,
+}
+
+/// The body of a geofence creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewGeofence {
+    name: String,
+    shape: Shape,
+}
+
+/// The direction of a geofence crossing.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "GEOFENCE_TRANSITION", rename_all = "lowercase")]
+pub enum Transition {
+    /// The device entered the geofence.
+    Enter,
+    /// The device left the geofence.
+    Exit,
+}
+
+/// A stored geofence transition event.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct GeofenceEvent {
+    id: i32,
+    geofence_id: Option<i32>,
+    geofence_name: String,
+    device_id: String,
+    transition: Transition,
+    #[serde(rename = "time", serialize_with = "serialize_timestamp")]
+    time_id: PrimitiveDateTime,
+    coords_x: f64,
+    coords_y: f64,
+}
+
+/// Query parameters of the event history.
+#[derive(Deserialize, Debug)]
+pub struct EventQuery {
+    /// Only return events after this date.
+    start: Option<String>,
+    /// Only return events before this date.
+    end: Option<String>,
+    /// Only return events of this geofence.
+    geofence: Option<i32>,
+    /// Only return events of this device.
+    device: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+struct StateChange {
+    fence: usize,
+    inside: bool,
+    transition: Option<Transition>,
+}
+
+/// Keeps track of the position of a user's devices relative to their
+/// geofences while points are being added.
+pub(crate) struct GeofenceTracker {
+    user_id: i32,
+    fences: Vec<Geofence>,
+    states: HashMap<(i32, String), bool>,
+}
+
+impl GeofenceTracker {
+    /// Loads the geofences of a user and the last known state of their devices.
+    pub async fn load(pool: &PgPool, user_id: i32) -> sqlx::Result<GeofenceTracker> {
+        let fences = sqlx::query_as!(
+            Geofence,
+            r#"SELECT id, name, shape AS "shape: sqlx::types::Json<Shape>"
+               FROM geofences WHERE user_identifier=$1"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+        let states = if fences.is_empty() {
+            HashMap::new()
+        } else {
+            sqlx::query!(
+                r#"SELECT geofence_id, device_id, inside FROM geofence_states
+                   JOIN geofences ON geofences.id=geofence_id WHERE user_identifier=$1"#,
+                user_id
+            )
+            .fetch_all(pool)
+            .await?
+            .into_iter()
+            .map(|row| ((row.geofence_id, row.device_id), row.inside))
+            .collect()
+        };
+        Ok(GeofenceTracker {
+            user_id,
+            fences,
+            states,
+        })
+    }
+
+    /// Updates the in-memory states with a new position of a device. The first
+    /// observation of a device for a geofence sets its state without producing
+    /// a transition.
+    fn update(&mut self, device_id: &str, point: [f64; 2]) -> Vec<StateChange> {
+        let mut changes = vec![];
+        for (idx, fence) in self.fences.iter().enumerate() {
+            let inside = fence.shape.contains(point);
+            let previous = self
+                .states
+                .insert((fence.id, device_id.to_string()), inside);
+            let transition = match (previous, inside) {
+                (Some(false), true) => Some(Transition::Enter),
+                (Some(true), false) => Some(Transition::Exit),
+                (Some(_), _) => continue,
+                (None, _) => None,
+            };
+            changes.push(StateChange {
+                fence: idx,
+                inside,
+                transition,
+            });
+        }
+        changes
+    }
+
+    /// Records a new position of a device, stores the resulting transitions
+    /// and sends them to the webhooks of the user.
+    pub async fn record(
+        &mut self,
+        pool: &PgPool,
+        webhooks: &WebhookSender,
+        device_id: &str,
+        point: [f64; 2],
+        time: PrimitiveDateTime,
+    ) -> sqlx::Result<()> {
+        for change in self.update(device_id, point) {
+            let fence = &self.fences[change.fence];
+            sqlx::query!(
+                r#"INSERT INTO geofence_states (geofence_id, device_id, inside) VALUES ( $1, $2, $3 )
+                   ON CONFLICT (geofence_id, device_id) DO UPDATE SET inside=EXCLUDED.inside"#,
+                fence.id,
+                device_id,
+                change.inside
+            )
+            .execute(pool)
+            .await?;
+            if let Some(transition) = change.transition {
+                let event = sqlx::query_as!(
+                    GeofenceEvent,
+                    r#"INSERT INTO geofence_events (
+                        user_identifier, geofence_id, geofence_name, device_id, transition,
+                        time_id, coords_x, coords_y)
+                        VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 )
+                        RETURNING id, geofence_id, geofence_name, device_id,
+                        transition AS "transition: Transition", time_id, coords_x, coords_y"#,
+                    self.user_id,
+                    fence.id,
+                    fence.name,
+                    device_id,
+                    transition as Transition,
+                    time,
+                    point[0],
+                    point[1]
+                )
+                .fetch_one(pool)
+                .await?;
+                let event_type = match transition {
+                    Transition::Enter => "geofence.enter",
+                    Transition::Exit => "geofence.exit",
+                };
+                webhooks.dispatch(self.user_id, event_type, &event);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// API method to list the geofences of the current user.
+pub async fn list_geofences(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Geofence>>), (StatusCode, String)> {
+    let fences = sqlx::query_as!(
+        Geofence,
+        r#"SELECT id, name, shape AS "shape: sqlx::types::Json<Shape>"
+           FROM geofences WHERE user_identifier=$1 ORDER BY id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(fences)))
+}
+
+/// API method to create a new geofence for the current user.
+pub async fn create_geofence(
+    Json(new_fence): Json<NewGeofence>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Geofence>), (StatusCode, String)> {
+    if !new_fence.shape.is_valid() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid geofence shape".to_string(),
+        ));
+    }
+    let fence = sqlx::query_as!(
+        Geofence,
+        r#"INSERT INTO geofences (user_identifier, name, shape) VALUES ( $1, $2, $3 )
+           RETURNING id, name, shape AS "shape: sqlx::types::Json<Shape>""#,
+        current_user.user_id,
+        new_fence.name,
+        sqlx::types::Json(new_fence.shape) as _
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(fence)))
+}
+
+/// API method to remove a geofence of the current user. Its past events are
+/// kept.
+pub async fn delete_geofence(
+    Path(fence_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"DELETE FROM geofences WHERE id=$1 AND user_identifier=$2"#,
+        fence_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such geofence".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// API method to query the geofence event history of the current user.
+pub async fn list_events(
+    Query(query): Query<EventQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<GeofenceEvent>>), (StatusCode, String)> {
+    let parse = |date: &Option<String>| match date {
+        Some(date) => parse_datetime(date)
+            .map(Some)
+            .ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}"))),
+        None => Ok(None),
+    };
+    let (start, end) = (parse(&query.start)?, parse(&query.end)?);
+    let events = sqlx::query_as!(
+        GeofenceEvent,
+        r#"SELECT id, geofence_id, geofence_name, device_id,
+           transition AS "transition: Transition", time_id, coords_x, coords_y
+           FROM geofence_events WHERE user_identifier=$1
+           AND ($2::TIMESTAMP IS NULL OR time_id >= $2)
+           AND ($3::TIMESTAMP IS NULL OR time_id < $3)
+           AND ($4::INT IS NULL OR geofence_id=$4)
+           AND ($5::TEXT IS NULL OR device_id=$5)
+           ORDER BY time_id DESC LIMIT $6"#,
+        current_user.user_id,
+        start,
+        end,
+        query.geofence,
+        query.device,
+        MAX_EVENTS
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(events)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_detect_transitions() {
+        let mut tracker = GeofenceTracker {
+            user_id: 1,
+            fences: vec![Geofence {
+                id: 1,
+                name: "home".to_string(),
+                shape: sqlx::types::Json(Shape::Circle {
+                    center: [0., 0.],
+                    radius: 1000.,
+                }),
+            }],
+            states: HashMap::new(),
+        };
+        let first = tracker.update("phone", [0.1, 0.]);
+        assert_eq!(
+            first,
+            vec![StateChange {
+                fence: 0,
+                inside: false,
+                transition: None
+            }]
+        );
+        assert!(tracker.update("phone", [0.1, 0.1]).is_empty());
+        let enter = tracker.update("phone", [0., 0.]);
+        assert_eq!(enter[0].transition, Some(Transition::Enter));
+        // Devices are tracked independently.
+        assert_eq!(tracker.update("tablet", [0., 0.])[0].transition, None);
+        let exit = tracker.update("phone", [0.1, 0.]);
+        assert_eq!(exit[0].transition, Some(Transition::Exit));
+    }
+}
@@ -0,0 +1,359 @@
+/// Location sharing between users. A user (the owner) can grant another user
+/// (the grantee) access to their live position only or to their whole history,
+/// optionally restricted to a time window and with a reduced precision.
+use crate::api::{parse_datetime, serialize_optional_timestamp};
+use crate::auth::CurrentUser;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use time::macros::format_description;
+
+/// Coordinates are never rounded to more decimals than this.
+const MAX_PRECISION: i16 = 6;
+
+/// What a grant gives access to.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "GRANT_SCOPE", rename_all = "lowercase")]
+pub enum GrantScope {
+    /// Only the last known position of each device.
+    Live,
+    /// All the points.
+    History,
+}
+
+/// A location sharing grant.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct Grant {
+    id: i32,
+    owner: String,
+    grantee: String,
+    scope: GrantScope,
+    #[serde(rename = "start", serialize_with = "serialize_optional_timestamp")]
+    start_time: Option<PrimitiveDateTime>,
+    #[serde(rename = "end", serialize_with = "serialize_optional_timestamp")]
+    end_time: Option<PrimitiveDateTime>,
+    precision: Option<i16>,
+}
+
+/// The grants of the current user.
+#[derive(Serialize, Debug)]
+pub struct Grants {
+    /// Grants given by the current user.
+    given: Vec<Grant>,
+    /// Grants given to the current user.
+    received: Vec<Grant>,
+}
+
+/// The body of a grant creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewGrant {
+    /// Username of the grantee.
+    user: String,
+    /// What the grant gives access to.
+    scope: GrantScope,
+    /// Only points after this date are visible.
+    start: Option<String>,
+    /// Only points before this date are visible.
+    end: Option<String>,
+    /// Number of decimals the coordinates are rounded to, e.g. 2 for about 1km.
+    precision: Option<i16>,
+}
+
+/// Query parameter selecting whose data to view.
+#[derive(Deserialize, Debug)]
+pub struct UserQuery {
+    /// Username of the user whose data to view. Defaults to the current user.
+    pub user: Option<String>,
+}
+
+/// The points a user is allowed to see.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Access {
+    /// Owner of the visible points, `None` for the points of every user.
+    pub user_id: Option<i32>,
+    /// Only points after this time are visible.
+    pub start: Option<PrimitiveDateTime>,
+    /// Only points before this time are visible.
+    pub end: Option<PrimitiveDateTime>,
+    /// Number of decimals of the visible coordinates.
+    pub precision: Option<i16>,
+}
+
+impl Access {
+    /// Full access to the points of a user, or of every user for `None`.
+    fn full(user_id: Option<i32>) -> Access {
+        Access {
+            user_id,
+            start: None,
+            end: None,
+            precision: None,
+        }
+    }
+
+    /// The points a user sees by default: their own, or all of them for an
+    /// admin.
+    pub fn own(current_user: &CurrentUser) -> Access {
+        Access::full(Some(current_user.user_id).filter(|_| !current_user.is_admin))
+    }
+
+    /// SQL conditions on the `points` table restricting to the visible points.
+    /// The conditions start with `WHERE` if `first` and `AND` otherwise.
+    pub fn filter(&self, first: bool) -> String {
+        let format = format_description!("[year]-[month]-[day] [hour]:[minute]:[second]");
+        let mut conditions = vec![];
+        if let Some(user_id) = self.user_id {
+            conditions.push(format!("user_identifier={user_id}"));
+        }
+        if let Some(start) = self.start {
+            conditions.push(format!(
+                "time_id >= '{}'::TIMESTAMP",
+                start.format(&format).unwrap()
+            ));
+        }
+        if let Some(end) = self.end {
+            conditions.push(format!(
+                "time_id < '{}'::TIMESTAMP",
+                end.format(&format).unwrap()
+            ));
+        }
+        if conditions.is_empty() {
+            "".to_string()
+        } else {
+            format!(
+                "{} {}",
+                if first { "WHERE" } else { "AND" },
+                conditions.join(" AND ")
+            )
+        }
+    }
+
+    /// Rounds coordinates to the visible precision.
+    pub fn round(&self, coords: [f64; 2]) -> [f64; 2] {
+        match self.precision {
+            Some(precision) => {
+                let factor = 10f64.powi(precision.into());
+                coords.map(|c| (c * factor).round() / factor)
+            }
+            None => coords,
+        }
+    }
+}
+
+/// Finds the points the current user can see for a request. Without `user`,
+/// these are the points of the current user. Otherwise the user must be an
+/// admin, or have been given a grant including `scope`.
+pub(crate) async fn resolve_access(
+    pool: &PgPool,
+    current_user: &CurrentUser,
+    user: Option<&str>,
+    scope: GrantScope,
+) -> Result<Access, (StatusCode, String)> {
+    let Some(username) = user else {
+        return Ok(Access::own(current_user));
+    };
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    // Unknown users and users without a grant are indistinguishable.
+    let no_access = (
+        StatusCode::NOT_FOUND,
+        format!("No access to the locations of {username}"),
+    );
+    let owner_id = sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_optional(pool)
+        .await
+        .map_err(internal_error)?
+        .ok_or(no_access.clone())?;
+    if owner_id == current_user.user_id || current_user.is_admin {
+        return Ok(Access::full(Some(owner_id)));
+    }
+    let grant = sqlx::query!(
+        r#"SELECT scope AS "scope: GrantScope", start_time, end_time, precision
+           FROM grants WHERE owner_id=$1 AND grantee_id=$2"#,
+        owner_id,
+        current_user.user_id
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(internal_error)?
+    .ok_or(no_access)?;
+    if scope == GrantScope::History && grant.scope == GrantScope::Live {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Only the live position of {username} is shared"),
+        ));
+    }
+    Ok(Access {
+        user_id: Some(owner_id),
+        start: grant.start_time,
+        end: grant.end_time,
+        precision: grant.precision,
+    })
+}
+
+/// API method to list the grants given by and to the current user.
+pub async fn list_grants(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Grants>), (StatusCode, String)> {
+    let given = sqlx::query_as!(
+        Grant,
+        r#"SELECT grants.id, owners.username AS owner, grantees.username AS grantee,
+           scope AS "scope: GrantScope", start_time, end_time, precision
+           FROM grants JOIN users owners ON owners.id=owner_id
+           JOIN users grantees ON grantees.id=grantee_id
+           WHERE owner_id=$1 ORDER BY grants.id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let received = sqlx::query_as!(
+        Grant,
+        r#"SELECT grants.id, owners.username AS owner, grantees.username AS grantee,
+           scope AS "scope: GrantScope", start_time, end_time, precision
+           FROM grants JOIN users owners ON owners.id=owner_id
+           JOIN users grantees ON grantees.id=grantee_id
+           WHERE grantee_id=$1 ORDER BY grants.id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(Grants { given, received })))
+}
+
+/// API method to give another user access to the locations of the current
+/// user. An existing grant to the same user is replaced.
+pub async fn create_grant(
+    Json(new_grant): Json<NewGrant>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Grant>), (StatusCode, String)> {
+    let parse = |date: &Option<String>| match date {
+        Some(date) => parse_datetime(date)
+            .map(Some)
+            .ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}"))),
+        None => Ok(None),
+    };
+    let (start, end) = (parse(&new_grant.start)?, parse(&new_grant.end)?);
+    if matches!((start, end), (Some(start), Some(end)) if start >= end) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The start of the window must be before its end".to_string(),
+        ));
+    }
+    if new_grant
+        .precision
+        .is_some_and(|precision| !(0..=MAX_PRECISION).contains(&precision))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The precision must be between 0 and {MAX_PRECISION} decimals"),
+        ));
+    }
+    let grantee_id =
+        sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, new_grant.user)
+            .fetch_optional(&pool)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+            .filter(|id| *id != current_user.user_id)
+            .ok_or((
+                StatusCode::BAD_REQUEST,
+                format!("Invalid user {}", new_grant.user),
+            ))?;
+    let grant = sqlx::query_as!(
+        Grant,
+        r#"WITH grant_row AS (
+               INSERT INTO grants (owner_id, grantee_id, scope, start_time, end_time, precision)
+               VALUES ( $1, $2, $3, $4, $5, $6 )
+               ON CONFLICT (owner_id, grantee_id) DO UPDATE SET scope=EXCLUDED.scope,
+               start_time=EXCLUDED.start_time, end_time=EXCLUDED.end_time,
+               precision=EXCLUDED.precision
+               RETURNING *
+           )
+           SELECT grant_row.id, owners.username AS owner, grantees.username AS grantee,
+           scope AS "scope: GrantScope", start_time, end_time, precision
+           FROM grant_row JOIN users owners ON owners.id=owner_id
+           JOIN users grantees ON grantees.id=grantee_id"#,
+        current_user.user_id,
+        grantee_id,
+        new_grant.scope as GrantScope,
+        start,
+        end,
+        new_grant.precision
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(grant)))
+}
+
+/// API method to remove a grant given by the current user.
+pub async fn delete_grant(
+    Path(grant_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"DELETE FROM grants WHERE id=$1 AND owner_id=$2"#,
+        grant_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such grant".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+    use time::macros::datetime;
+
+    #[test]
+    fn should_filter_visible_points() {
+        let user = CurrentUser {
+            user_id: 3,
+            is_admin: false,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        let admin = CurrentUser {
+            user_id: 1,
+            is_admin: true,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        assert_eq!(Access::own(&user).filter(true), "WHERE user_identifier=3");
+        assert_eq!(Access::own(&admin).filter(false), "");
+        let access = Access {
+            user_id: Some(2),
+            start: Some(datetime!(2022-05-01 0:00)),
+            end: Some(datetime!(2022-06-01 12:30)),
+            precision: None,
+        };
+        assert_eq!(
+            access.filter(false),
+            "AND user_identifier=2 AND time_id >= '2022-05-01 00:00:00'::TIMESTAMP \
+             AND time_id < '2022-06-01 12:30:00'::TIMESTAMP"
+        );
+    }
+
+    #[test]
+    fn should_reduce_precision() {
+        let mut access = Access::full(Some(1));
+        assert_eq!(access.round([2.3456, 48.8567]), [2.3456, 48.8567]);
+        access.precision = Some(2);
+        assert_eq!(access.round([2.3456, 48.8567]), [2.35, 48.86]);
+        access.precision = Some(0);
+        assert_eq!(access.round([2.3456, -48.8567]), [2., -49.]);
+    }
+}
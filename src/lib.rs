@@ -2,21 +2,65 @@
 
 //! This crate contains the server and API for the roverland app.
 
+/// Account deletion with all the data of a user.
+pub mod account;
+/// The administration console: users, registration tokens and server health.
+pub mod admin;
 /// The API module contains all the API method implementations for the REST
 /// server.
 pub mod api;
+mod app;
 /// Module containing all the authentication, registration, cookies, etc. logic.
 pub mod auth;
+mod create_admin;
+/// Deletion of points by their owner, with an undo window.
+pub mod deletion;
+/// The devices of each user and their input tokens.
+pub mod devices;
+/// Geometry helpers: distances, shapes and point in polygon tests.
+pub mod geo;
+/// Offline reverse geocoding from a local gazetteer.
+pub mod geocoder;
+/// User defined geofences and the detection of devices entering and leaving
+/// them.
+pub mod geofence;
+/// Location sharing permissions between users.
+pub mod grants;
+/// Live streaming of the ingested points.
+pub mod live;
+/// Password changes and resets.
+pub mod password;
+/// Privacy zones dropping, snapping or hiding the points around sensitive
+/// places.
+pub mod privacy;
+mod register_token;
+/// Data retention policies and the purge of the expired points.
+pub mod retention;
+/// Segmentation of the raw points into trips and stays.
+pub mod segments;
 /// This module is used to parse and read from configuration files for the
 /// server.
 pub mod settings;
-mod app;
-mod create_admin;
-mod register_token;
+/// Public share links to a live position or a time range.
+pub mod share;
+/// Archives of all the personal data of a user.
+pub mod takeout;
+/// Management of the input tokens of the users.
+pub mod tokens;
+mod util;
+/// Report of the countries and regions visited by a user.
+pub mod visited;
+/// User webhooks and the signed delivery of events to them.
+pub mod webhook;
 
+pub use account::delete_user;
 pub use app::run_server;
 pub use create_admin::create_admin;
-pub use register_token::add_register_token;
+pub use password::reset_password;
+pub use register_token::{add_register_token, NewRegisterToken};
+pub use retention::purge_retention;
+pub use takeout::export_takeout;
+pub use tokens::{add_input_token, list_input_tokens, revoke_input_token, rotate_input_token};
 
 use askama::Template;
 use axum::http::{HeaderMap, StatusCode};
@@ -0,0 +1,179 @@
+/// Live location streaming. Ingested points are broadcast inside the server and
+/// pushed to the connected clients as Server-Sent Events. This only works
+/// within a single server instance.
+use crate::api::{serialize_timestamp, BatteryState, Motion};
+use crate::auth::CurrentUser;
+use axum::extract::Query;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::Extension;
+use serde::{Deserialize, Serialize};
+use sqlx::types::time::PrimitiveDateTime;
+use std::convert::Infallible;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+/// Number of points kept for slow clients before they start missing some.
+const LIVE_CAPACITY: usize = 1024;
+
+/// A newly ingested point.
+#[derive(Serialize, Debug, Clone)]
+pub struct LivePoint {
+    /// The user owning the device.
+    #[serde(skip)]
+    pub(crate) user_id: i32,
+    /// Whether the point is in a privacy zone hiding it from the other users.
+    #[serde(skip)]
+    pub(crate) hidden: bool,
+    /// The device identifier.
+    pub(crate) device: String,
+    /// Timestamp of the fix.
+    #[serde(serialize_with = "serialize_timestamp")]
+    pub(crate) time: PrimitiveDateTime,
+    /// Coordinates of the fix as `[longitude, latitude]`.
+    pub(crate) coordinates: [f64; 2],
+    /// Altitude in meters.
+    pub(crate) altitude: Option<i16>,
+    /// Speed in meters per second.
+    pub(crate) speed: Option<i32>,
+    /// Battery level between 0 and 1.
+    pub(crate) battery_level: Option<f32>,
+    /// Battery state.
+    pub(crate) battery_state: Option<BatteryState>,
+    /// Motion types detected by the device.
+    pub(crate) motion: Vec<Motion>,
+}
+
+/// Query parameters of the live stream.
+#[derive(Deserialize, Debug)]
+pub struct LiveQuery {
+    /// Only stream the points of this device.
+    device: Option<String>,
+}
+
+/// The broadcast channel between the ingestion path and the live streams.
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<LivePoint>,
+}
+
+impl Default for LiveFeed {
+    fn default() -> Self {
+        LiveFeed::new()
+    }
+}
+
+impl LiveFeed {
+    /// Creates a new feed without any subscriber.
+    pub fn new() -> LiveFeed {
+        let (sender, _) = broadcast::channel(LIVE_CAPACITY);
+        LiveFeed { sender }
+    }
+
+    /// Sends a point to the connected clients. Nothing happens when no client
+    /// is connected.
+    pub fn publish(&self, point: LivePoint) {
+        if self.sender.receiver_count() > 0 {
+            // This only fails when the last client disconnected in the
+            // meantime.
+            let _ = self.sender.send(point);
+        }
+    }
+
+    /// Subscribes to the points visible to a user.
+    fn subscribe(
+        &self,
+        current_user: CurrentUser,
+        device: Option<String>,
+    ) -> impl Stream<Item = LivePoint> {
+        BroadcastStream::new(self.sender.subscribe()).filter_map(move |point| match point {
+            Ok(point)
+                if (point.user_id == current_user.user_id
+                    || (current_user.is_admin && !point.hidden))
+                    && device.as_ref().is_none_or(|device| *device == point.device) =>
+            {
+                Some(point)
+            }
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                tracing::warn!("live stream lagging, skipped {skipped} points");
+                None
+            }
+        })
+    }
+}
+
+/// API method streaming the newly ingested points of the current user as
+/// Server-Sent Events. Each event is named `point` and contains a JSON
+/// `LivePoint`.
+pub async fn live(
+    Query(query): Query<LiveQuery>,
+    Extension(feed): Extension<LiveFeed>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let stream = feed
+        .subscribe(current_user, query.device)
+        .filter_map(|point| Event::default().event("point").json_data(point).ok())
+        .map(Ok);
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+    use time::macros::datetime;
+
+    fn point(user_id: i32, device: &str) -> LivePoint {
+        LivePoint {
+            user_id,
+            hidden: false,
+            device: device.to_string(),
+            time: datetime!(2022-05-01 12:00),
+            coordinates: [2.35, 48.85],
+            altitude: None,
+            speed: None,
+            battery_level: None,
+            battery_state: None,
+            motion: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn should_only_stream_visible_points() {
+        let feed = LiveFeed::new();
+        let user = CurrentUser {
+            user_id: 1,
+            is_admin: false,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        let admin = CurrentUser {
+            user_id: 2,
+            is_admin: true,
+            device_id: None,
+            scope: Scope::Admin,
+        };
+        let user_stream = feed.subscribe(user.clone(), None);
+        let tablet_stream = feed.subscribe(user, Some("tablet".to_string()));
+        let admin_stream = feed.subscribe(admin, None);
+        tokio::pin!(user_stream, tablet_stream, admin_stream);
+        feed.publish(point(3, "phone"));
+        feed.publish(point(1, "phone"));
+        feed.publish(point(1, "tablet"));
+        feed.publish(LivePoint {
+            hidden: true,
+            ..point(1, "watch")
+        });
+        feed.publish(point(1, "laptop"));
+        assert_eq!(user_stream.next().await.unwrap().device, "phone");
+        assert_eq!(user_stream.next().await.unwrap().device, "tablet");
+        assert_eq!(user_stream.next().await.unwrap().device, "watch");
+        assert_eq!(tablet_stream.next().await.unwrap().user_id, 1);
+        assert_eq!(admin_stream.next().await.unwrap().user_id, 3);
+        assert_eq!(admin_stream.next().await.unwrap().device, "phone");
+        assert_eq!(admin_stream.next().await.unwrap().device, "tablet");
+        assert_eq!(admin_stream.next().await.unwrap().device, "laptop");
+    }
+}
@@ -1,5 +1,10 @@
 use clap::{Parser, Subcommand};
-use overland_client::{add_register_token, create_admin, run_server};
+use overland_client::auth::Scope;
+use overland_client::{
+    add_input_token, add_register_token, create_admin, delete_user, export_takeout,
+    list_input_tokens, purge_retention, reset_password, revoke_input_token, rotate_input_token,
+    run_server, NewRegisterToken,
+};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
@@ -16,8 +21,81 @@ enum Commands {
     /// Manually create an administrator user. This will prompt for a username
     /// and password.
     CreateAdmin,
+    /// Reset the password of a user. This will prompt for the new password,
+    /// and end all the sessions of the user.
+    ResetPassword {
+        /// The user whose password is reset.
+        username: String,
+    },
     /// Manually create a registration token to let a user register on the app.
-    AddRegisterToken,
+    AddRegisterToken {
+        /// The token expires after this many hours.
+        #[clap(long)]
+        expires_hours: Option<i64>,
+        /// How many users can register with the token.
+        #[clap(long, default_value_t = 1)]
+        max_uses: i32,
+        /// Reserve the token to a username.
+        #[clap(long)]
+        username: Option<String>,
+    },
+    /// Purge the points older than the retention policies.
+    PurgeRetention {
+        /// Only show what would be removed.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Delete a user and all their data.
+    DeleteUser {
+        /// The user to delete.
+        username: String,
+        /// Do not ask for a confirmation.
+        #[clap(long)]
+        yes: bool,
+    },
+    /// Manage the input tokens of the users.
+    Tokens {
+        #[clap(subcommand)]
+        command: TokenCommands,
+    },
+    /// Write an archive of all the data of a user.
+    Takeout {
+        /// The user to export.
+        username: String,
+        /// Path of the archive, `<username>-takeout.zip` by default.
+        #[clap(long, short)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum TokenCommands {
+    /// List the input tokens of a user.
+    List {
+        /// The owner of the tokens.
+        username: String,
+    },
+    /// Create an input token for a user.
+    Create {
+        /// The owner of the token.
+        username: String,
+        /// A label telling what the token is used for.
+        #[clap(long)]
+        label: Option<String>,
+        /// What the token can do: ingest, read or admin.
+        #[clap(long, default_value = "ingest")]
+        scope: Scope,
+    },
+    /// Replace an input token by a new one.
+    Rotate {
+        /// The id of the token.
+        id: i32,
+    },
+    /// Revoke an input token.
+    Revoke {
+        /// The id of the token.
+        id: i32,
+    },
 }
 
 #[tokio::main]
@@ -27,7 +105,34 @@ async fn main() -> Result<(), sqlx::Error> {
     match &cli.command {
         Commands::RunServer => run_server().await?,
         Commands::CreateAdmin => create_admin().await?,
-        Commands::AddRegisterToken => add_register_token().await?,
+        Commands::ResetPassword { username } => reset_password(username).await?,
+        Commands::AddRegisterToken {
+            expires_hours,
+            max_uses,
+            username,
+        } => {
+            add_register_token(NewRegisterToken {
+                expires_hours: *expires_hours,
+                max_uses: Some(*max_uses),
+                username: username.clone(),
+            })
+            .await?
+        }
+        Commands::PurgeRetention { dry_run } => purge_retention(*dry_run).await?,
+        Commands::DeleteUser { username, yes } => delete_user(username, *yes).await?,
+        Commands::Tokens { command } => match command {
+            TokenCommands::List { username } => list_input_tokens(username).await?,
+            TokenCommands::Create {
+                username,
+                label,
+                scope,
+            } => add_input_token(username, label.as_deref(), *scope).await?,
+            TokenCommands::Rotate { id } => rotate_input_token(*id).await?,
+            TokenCommands::Revoke { id } => revoke_input_token(*id).await?,
+        },
+        Commands::Takeout { username, output } => {
+            export_takeout(username, output.as_deref()).await?
+        }
     };
     Ok(())
 }
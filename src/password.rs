@@ -0,0 +1,272 @@
+/// Password changes and resets. Users change their password from their account
+/// page, administrators reset it from the command line or with a one-time
+/// link. A new password ends the other sessions of the user.
+use crate::api::serialize_timestamp;
+use crate::auth::{
+    client_ip, end_sessions, session_cookie, CurrentUser, PasswordDatabase, SharedPdb,
+};
+use crate::settings::Settings;
+use crate::util::{hash_token, now};
+use crate::HtmlTemplate;
+use askama::Template;
+use axum::extract::{ConnectInfo, Form, Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::time::PrimitiveDateTime;
+use std::net::SocketAddr;
+use time::Duration;
+
+const RESET_TOKEN_LEN: usize = 64;
+/// How long a reset link can be used.
+const RESET_LINK_HOURS: i64 = 24;
+
+#[derive(Template)]
+#[template(path = "reset_password.html")]
+struct ResetPasswordTemplate {
+    token: String,
+}
+
+#[derive(Template)]
+#[template(path = "password_changed.html")]
+struct PasswordChangedTemplate {
+    /// Where to go next.
+    url: String,
+}
+
+/// The password change form of the account page.
+#[derive(Deserialize, Debug)]
+pub struct ChangePassword {
+    current: String,
+    new: String,
+    confirm: String,
+}
+
+/// The query of a reset link.
+#[derive(Deserialize, Debug)]
+pub struct ResetQuery {
+    token: String,
+}
+
+/// The form of a reset link.
+#[derive(Deserialize, Debug)]
+pub struct ResetPassword {
+    token: String,
+    new: String,
+    confirm: String,
+}
+
+/// A one-time password reset link.
+#[derive(Serialize, Debug)]
+pub struct ResetLink {
+    /// The reset page, with the token.
+    link: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+}
+
+/// Checks a new password and its confirmation.
+fn check_new_password(new: &str, confirm: &str) -> Result<(), (StatusCode, String)> {
+    if new.is_empty() {
+        Err((
+            StatusCode::BAD_REQUEST,
+            "The password cannot be empty".to_string(),
+        ))
+    } else if new != confirm {
+        Err((
+            StatusCode::BAD_REQUEST,
+            "The passwords do not match".to_string(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets the password of a user and ends their sessions, except the one of the
+/// `kept` cookie. Returns `false` if there is no such user.
+async fn replace_password(
+    pdb: &PasswordDatabase,
+    user_id: i32,
+    password: String,
+    kept: Option<&str>,
+) -> Result<bool, (StatusCode, String)> {
+    let updated = pdb.set_password(user_id, password).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error changing the password".to_string(),
+        )
+    })?;
+    if updated {
+        end_sessions(pdb.pool(), user_id, kept)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    }
+    Ok(updated)
+}
+
+/// The method called by the password change form of the account page. The
+/// current password is checked again, and the other sessions are ended.
+pub async fn change_password(
+    Form(form): Form<ChangePassword>,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(pdb): Extension<SharedPdb>,
+    Extension(current_user): Extension<CurrentUser>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let username = sqlx::query_scalar!(
+        r#"SELECT username FROM users WHERE id=$1"#,
+        current_user.user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let ip = client_ip(&headers, peer.map(|ConnectInfo(peer)| peer));
+    pdb.confirm_password(&username, &form.current, ip.as_deref())
+        .await?;
+    check_new_password(&form.new, &form.confirm)?;
+    let kept = session_cookie(&headers);
+    replace_password(&pdb, current_user.user_id, form.new, kept.as_deref()).await?;
+    tracing::info!("user {username} changed their password");
+    Ok(HtmlTemplate(PasswordChangedTemplate {
+        url: "/account".to_string(),
+    }))
+}
+
+/// API method for an administrator to issue a one-time password reset link
+/// for a user. It replaces the previous links of the user.
+pub async fn create_reset_link(
+    Path(user_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<ResetLink>), (StatusCode, String)> {
+    if !current_user.is_admin {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only administrators can reset passwords".to_string(),
+        ));
+    }
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let token: String = {
+        let mut rng = rand::thread_rng();
+        (&mut rng)
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(RESET_TOKEN_LEN)
+            .map(char::from)
+            .collect()
+    };
+    let now = now();
+    let expires_at = now + Duration::hours(RESET_LINK_HOURS);
+    let mut tx = pool.begin().await.map_err(internal_error)?;
+    sqlx::query!(
+        r#"DELETE FROM password_resets WHERE user_id=$1 OR expires_at <= $2"#,
+        user_id,
+        now
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    let res = sqlx::query!(
+        r#"INSERT INTO password_resets (user_id, token_hash, created_by, created_at, expires_at)
+           SELECT id, $2, $3, $4, $5 FROM users WHERE id=$1"#,
+        user_id,
+        hash_token(&token),
+        current_user.user_id,
+        now,
+        expires_at
+    )
+    .execute(&mut tx)
+    .await
+    .map_err(internal_error)?;
+    if res.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, "No such user".to_string()));
+    }
+    tx.commit().await.map_err(internal_error)?;
+    tracing::info!("password reset link issued for user {user_id}");
+    Ok((
+        StatusCode::CREATED,
+        Json(ResetLink {
+            link: format!("/reset-password?token={token}"),
+            expires_at,
+        }),
+    ))
+}
+
+/// The page of a reset link.
+pub async fn serve_reset_password(Query(query): Query<ResetQuery>) -> impl IntoResponse {
+    HtmlTemplate(ResetPasswordTemplate { token: query.token })
+}
+
+/// The method called by the form of a reset link. The link can only be used
+/// once, and all the sessions of the user are ended.
+pub async fn reset_password_with_link(
+    Form(form): Form<ResetPassword>,
+    Extension(pdb): Extension<SharedPdb>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_new_password(&form.new, &form.confirm)?;
+    let user_id = sqlx::query_scalar!(
+        r#"DELETE FROM password_resets WHERE token_hash=$1 AND expires_at > $2
+           RETURNING user_id"#,
+        hash_token(&form.token),
+        now()
+    )
+    .fetch_optional(pdb.pool())
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((
+        StatusCode::UNAUTHORIZED,
+        "This link has expired or has already been used".to_string(),
+    ))?;
+    replace_password(&pdb, user_id, form.new, None).await?;
+    tracing::info!("password of user {user_id} reset with a link");
+    Ok(HtmlTemplate(PasswordChangedTemplate {
+        url: "/login".to_string(),
+    }))
+}
+
+/// Resets the password of a user from the command line. This will prompt for
+/// the new password. All the sessions of the user are ended.
+pub async fn reset_password(username: &str) -> Result<(), sqlx::Error> {
+    let settings = Settings::new().unwrap();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.");
+
+    let user_id = sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_one(&pool)
+        .await?;
+    let password = rpassword::prompt_password("New password: ").unwrap();
+    let confirm = rpassword::prompt_password("Confirm the new password: ").unwrap();
+    if let Err((_, e)) = check_new_password(&password, &confirm) {
+        println!("{e}");
+        return Ok(());
+    }
+    let password_db = PasswordDatabase::new(&pool);
+    if let Err((_, e)) = replace_password(&password_db, user_id, password, None).await {
+        println!("{e}");
+        return Ok(());
+    }
+    println!("Password of {username} reset");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_check_new_passwords() {
+        assert!(check_new_password("secret", "secret").is_ok());
+        assert_eq!(
+            check_new_password("secret", "secert").unwrap_err().0,
+            StatusCode::BAD_REQUEST
+        );
+        assert!(check_new_password("", "").is_err());
+    }
+}
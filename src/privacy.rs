@@ -0,0 +1,195 @@
+/// Privacy zones hide sensitive places like a home. The points inside a zone
+/// are dropped, snapped to the center of the zone, or only hidden from the
+/// other users (grants, share links and admins).
+use crate::auth::CurrentUser;
+use crate::geo::Shape;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+
+/// What happens to the points inside a privacy zone.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "PRIVACY_ACTION", rename_all = "lowercase")]
+pub enum PrivacyAction {
+    /// The points are not stored, and not shown if they already were.
+    Drop,
+    /// The points are moved to the center of the zone.
+    Snap,
+    /// The points are only visible to their owner.
+    Hide,
+}
+
+/// A privacy zone defined by a user.
+#[derive(Serialize, sqlx::FromRow, Debug, Clone)]
+pub struct PrivacyZone {
+    id: i32,
+    name: String,
+    shape: sqlx::types::Json<Shape>,
+    action: PrivacyAction,
+}
+
+/// The body of a privacy zone creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewPrivacyZone {
+    name: String,
+    shape: Shape,
+    action: PrivacyAction,
+}
+
+/// The privacy zones of one or several users, used to filter their points.
+#[derive(Default)]
+pub(crate) struct PrivacyZones {
+    zones: HashMap<i32, Vec<(Shape, PrivacyAction)>>,
+}
+
+impl PrivacyZones {
+    /// Loads the privacy zones of a user, or of all the users with `None`.
+    pub async fn load(pool: &PgPool, user_id: Option<i32>) -> sqlx::Result<PrivacyZones> {
+        let mut zones: HashMap<i32, Vec<_>> = HashMap::new();
+        for row in sqlx::query!(
+            r#"SELECT user_identifier, shape AS "shape: sqlx::types::Json<Shape>",
+               action AS "action: PrivacyAction"
+               FROM privacy_zones WHERE $1::INT IS NULL OR user_identifier=$1 ORDER BY id"#,
+            user_id
+        )
+        .fetch_all(pool)
+        .await?
+        {
+            zones
+                .entry(row.user_identifier)
+                .or_default()
+                .push((row.shape.0, row.action));
+        }
+        Ok(PrivacyZones { zones })
+    }
+
+    /// Applies the privacy zones of a user to one of their points. Returns the
+    /// position to show, or `None` if the point must not be shown. `owner`
+    /// tells if the point is shown to the user it belongs to.
+    pub fn apply(&self, user_id: i32, point: [f64; 2], owner: bool) -> Option<[f64; 2]> {
+        let mut snapped = None;
+        for (shape, action) in self.zones.get(&user_id).into_iter().flatten() {
+            if !shape.contains(point) {
+                continue;
+            }
+            match action {
+                PrivacyAction::Drop => return None,
+                PrivacyAction::Hide if !owner => return None,
+                PrivacyAction::Hide => (),
+                PrivacyAction::Snap => snapped = snapped.or(Some(shape.center())),
+            }
+        }
+        Some(snapped.unwrap_or(point))
+    }
+}
+
+/// API method to list the privacy zones of the current user.
+pub async fn list_privacy_zones(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<PrivacyZone>>), (StatusCode, String)> {
+    let zones = sqlx::query_as!(
+        PrivacyZone,
+        r#"SELECT id, name, shape AS "shape: sqlx::types::Json<Shape>",
+           action AS "action: PrivacyAction"
+           FROM privacy_zones WHERE user_identifier=$1 ORDER BY id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(zones)))
+}
+
+/// API method to create a privacy zone for the current user. It applies to
+/// the points already stored as well as to the new ones.
+pub async fn create_privacy_zone(
+    Json(new_zone): Json<NewPrivacyZone>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<PrivacyZone>), (StatusCode, String)> {
+    if !new_zone.shape.is_valid() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid privacy zone shape".to_string(),
+        ));
+    }
+    let zone = sqlx::query_as!(
+        PrivacyZone,
+        r#"INSERT INTO privacy_zones (user_identifier, name, shape, action)
+           VALUES ( $1, $2, $3, $4 )
+           RETURNING id, name, shape AS "shape: sqlx::types::Json<Shape>",
+           action AS "action: PrivacyAction""#,
+        current_user.user_id,
+        new_zone.name,
+        sqlx::types::Json(new_zone.shape) as _,
+        new_zone.action as PrivacyAction
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(zone)))
+}
+
+/// API method to remove a privacy zone of the current user. Dropped points
+/// are lost, but the others show up again.
+pub async fn delete_privacy_zone(
+    Path(zone_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"DELETE FROM privacy_zones WHERE id=$1 AND user_identifier=$2"#,
+        zone_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such privacy zone".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_apply_privacy_zones() {
+        let home = Shape::Circle {
+            center: [2.35, 48.85],
+            radius: 200.,
+        };
+        let office = Shape::Polygon {
+            coordinates: vec![[2.40, 48.80], [2.41, 48.80], [2.41, 48.81], [2.40, 48.81]],
+        };
+        let gym = Shape::Circle {
+            center: [2.30, 48.90],
+            radius: 100.,
+        };
+        let zones = PrivacyZones {
+            zones: HashMap::from([(
+                1,
+                vec![
+                    (home, PrivacyAction::Snap),
+                    (office, PrivacyAction::Hide),
+                    (gym, PrivacyAction::Drop),
+                ],
+            )]),
+        };
+        let outside = [2.0, 48.0];
+        assert_eq!(zones.apply(1, outside, false), Some(outside));
+        assert_eq!(zones.apply(2, [2.30, 48.90], false), Some([2.30, 48.90]));
+        assert_eq!(zones.apply(1, [2.351, 48.85], true), Some([2.35, 48.85]));
+        assert_eq!(zones.apply(1, [2.405, 48.805], true), Some([2.405, 48.805]));
+        assert_eq!(zones.apply(1, [2.405, 48.805], false), None);
+        assert_eq!(zones.apply(1, [2.30, 48.90], true), None);
+    }
+}
@@ -1,19 +1,76 @@
+use crate::api::{serialize_optional_timestamp, serialize_timestamp};
 use crate::settings::Settings;
-use sqlx::postgres::PgPoolOptions;
+use crate::util::now;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::time::PrimitiveDateTime;
+use time::Duration;
 
 const REGISTER_TOKEN_LEN: usize = 64;
+/// The longest expiry of a registration token, ten years.
+const MAX_EXPIRES_HOURS: i64 = 24 * 365 * 10;
 
-/// Adds a registration token and prints it to stdout.
-pub async fn add_register_token() -> Result<(), sqlx::Error> {
-    let settings = Settings::new().unwrap();
+/// A registration token, shared as an invite link.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct RegisterToken {
+    id: i32,
+    register_token: String,
+    /// The only username the token can register, if any.
+    username: Option<String>,
+    max_uses: i32,
+    uses: i32,
+    /// The username of the administrator who issued the token.
+    created_by: Option<String>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    expires_at: Option<PrimitiveDateTime>,
+    /// Whether the token can still be used.
+    valid: bool,
+    /// The registration page prefilled with the token.
+    link: String,
+}
 
-    let pool = PgPoolOptions::new()
-        .max_connections(settings.database.max_connections)
-        .connect(&settings.database.url)
-        .await
-        .expect("Cannot connect to postgres database.");
+/// The limits of a new registration token.
+#[derive(Deserialize, Debug, Default)]
+pub struct NewRegisterToken {
+    /// The token expires this many hours after its creation. It never expires
+    /// when absent.
+    pub expires_hours: Option<i64>,
+    /// How many users can register with the token, one by default.
+    pub max_uses: Option<i32>,
+    /// Reserves the token to a username.
+    pub username: Option<String>,
+}
+
+impl NewRegisterToken {
+    /// Checks the limits, and returns why they are invalid.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self.expires_hours.is_some_and(|hours| hours <= 0) {
+            Err("The expiry must be positive")
+        } else if self
+            .expires_hours
+            .is_some_and(|hours| hours > MAX_EXPIRES_HOURS)
+        {
+            Err("The expiry cannot be more than ten years")
+        } else if self.max_uses.is_some_and(|max_uses| max_uses <= 0) {
+            Err("The token must allow at least one use")
+        } else if self.username.as_deref() == Some("") {
+            Err("The username cannot be empty")
+        } else {
+            Ok(())
+        }
+    }
+}
 
+/// Stores a new registration token issued by `created_by`, or from the
+/// command line with `None`.
+pub(crate) async fn insert_register_token(
+    pool: &PgPool,
+    new_token: NewRegisterToken,
+    created_by: Option<i32>,
+) -> sqlx::Result<RegisterToken> {
     let register_token: String = {
         let mut rng = rand::thread_rng();
         (&mut rng)
@@ -22,14 +79,157 @@ pub async fn add_register_token() -> Result<(), sqlx::Error> {
             .map(char::from)
             .collect()
     };
-    sqlx::query!(
-        r#"INSERT INTO register_tokens (register_token, used) VALUES ( $1, $2 )"#,
+    let now = now();
+    sqlx::query_as!(
+        RegisterToken,
+        r#"WITH token AS (
+               INSERT INTO register_tokens (register_token, created_at, expires_at, max_uses,
+               username, created_by) VALUES ( $1, $2, $3, $4, $5, $6 ) RETURNING *)
+           SELECT token.id, register_token, token.username, max_uses, uses,
+           users.username AS "created_by?", created_at, expires_at, true AS "valid!",
+           '/register?token=' || register_token AS "link!"
+           FROM token LEFT JOIN users ON users.id=token.created_by"#,
         register_token,
-        false,
+        now,
+        new_token
+            .expires_hours
+            .map(|hours| now + Duration::hours(hours)),
+        new_token.max_uses.unwrap_or(1),
+        new_token.username,
+        created_by
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Lists all the registration tokens, the most recent first.
+pub(crate) async fn load_register_tokens(pool: &PgPool) -> sqlx::Result<Vec<RegisterToken>> {
+    sqlx::query_as!(
+        RegisterToken,
+        r#"SELECT register_tokens.id, register_token, register_tokens.username, max_uses, uses,
+           users.username AS "created_by?", created_at, expires_at,
+           uses < max_uses AND (expires_at IS NULL OR expires_at > $1) AS "valid!",
+           '/register?token=' || register_token AS "link!"
+           FROM register_tokens LEFT JOIN users ON users.id=register_tokens.created_by
+           ORDER BY register_tokens.id DESC"#,
+        now()
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Removes a registration token. Returns `false` if there is no such token.
+pub(crate) async fn remove_register_token(pool: &PgPool, id: i32) -> sqlx::Result<bool> {
+    let res = sqlx::query!(r#"DELETE FROM register_tokens WHERE id=$1"#, id)
+        .execute(pool)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// Returns whether a registration token can still be used, with the username
+/// it is reserved to.
+pub(crate) async fn usable_register_token(
+    pool: &PgPool,
+    token: &str,
+) -> sqlx::Result<Option<Option<String>>> {
+    sqlx::query_scalar!(
+        r#"SELECT username FROM register_tokens
+           WHERE register_token=$1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > $2)"#,
+        token,
+        now()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Counts a use of a registration token for a username, if the token is still
+/// valid for it. Returns the id of the token.
+pub(crate) async fn use_register_token(
+    pool: &PgPool,
+    token: &str,
+    username: &str,
+) -> sqlx::Result<Option<i32>> {
+    sqlx::query_scalar!(
+        r#"UPDATE register_tokens SET uses=uses+1
+           WHERE register_token=$1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > $2)
+           AND (username IS NULL OR username=$3)
+           RETURNING id"#,
+        token,
+        now(),
+        username
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Gives back a use of a registration token, when the registration failed.
+pub(crate) async fn release_register_token(pool: &PgPool, id: i32) -> sqlx::Result<()> {
+    sqlx::query!(
+        r#"UPDATE register_tokens SET uses=uses-1 WHERE id=$1 AND uses > 0"#,
+        id
     )
-    .execute(&pool)
+    .execute(pool)
     .await?;
-    println!("The token in: {}", register_token);
+    Ok(())
+}
+
+/// Adds a registration token and prints it to stdout.
+pub async fn add_register_token(new_token: NewRegisterToken) -> Result<(), sqlx::Error> {
+    if let Err(e) = new_token.validate() {
+        println!("{e}");
+        return Ok(());
+    }
+    let settings = Settings::new().unwrap();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.");
+
+    let register_token = insert_register_token(&pool, new_token, None).await?;
+    println!("The token in: {}", register_token.register_token);
+    println!(
+        "Invite link: {}{}",
+        settings.base.url.trim_end_matches('/'),
+        register_token.link
+    );
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_validate_register_token_limits() {
+        assert!(NewRegisterToken::default().validate().is_ok());
+        let new_token = NewRegisterToken {
+            expires_hours: Some(MAX_EXPIRES_HOURS),
+            max_uses: Some(5),
+            username: Some("alice".to_string()),
+        };
+        assert!(new_token.validate().is_ok());
+        for new_token in [
+            NewRegisterToken {
+                expires_hours: Some(0),
+                ..Default::default()
+            },
+            NewRegisterToken {
+                expires_hours: Some(i64::MAX),
+                ..Default::default()
+            },
+            NewRegisterToken {
+                max_uses: Some(-1),
+                ..Default::default()
+            },
+            NewRegisterToken {
+                username: Some(String::new()),
+                ..Default::default()
+            },
+        ] {
+            assert!(new_token.validate().is_err());
+        }
+    }
+}
@@ -0,0 +1,512 @@
+/// Data retention. Raw points older than the retention period of their owner
+/// are regularly purged, keeping nothing, daily summaries or simplified tracks.
+use crate::auth::CurrentUser;
+use crate::deletion::purge_deletions;
+use crate::geo::simplify_mask;
+use crate::settings::{self, Settings};
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::time::Date;
+use sqlx::{Postgres, Transaction};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// The longest retention period, about a century. Longer periods would not
+/// fit in a date.
+pub const MAX_RETENTION_DAYS: i32 = 36500;
+
+/// What is kept of the points older than the retention period.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "RETENTION_MODE", rename_all = "lowercase")]
+pub enum RetentionMode {
+    /// Nothing, the points and everything computed from them are deleted.
+    Delete,
+    /// A summary of each day and device: number of points, first and last
+    /// times and distance.
+    Summarize,
+    /// A simplified track, without the points that do not change its shape.
+    Simplify,
+}
+
+/// A retention policy. Missing fields fall back to the server settings.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RetentionPolicy {
+    /// Raw points older than this many days are purged.
+    days: Option<i32>,
+    /// What is kept of the purged points.
+    mode: Option<RetentionMode>,
+}
+
+/// The retention policy of a user and the server defaults.
+#[derive(Serialize, Debug)]
+pub struct UserRetention {
+    #[serde(flatten)]
+    policy: RetentionPolicy,
+    /// The server policy, used for the missing fields.
+    default: RetentionPolicy,
+}
+
+/// The outcome of the purge of the points of a user.
+#[derive(Debug)]
+pub struct PurgeReport {
+    /// The owner of the points.
+    pub username: String,
+    /// What was kept of the purged points.
+    pub mode: RetentionMode,
+    /// Points before this day were purged.
+    pub cutoff: Date,
+    /// Number of points removed.
+    pub points: u64,
+    /// Number of days touched.
+    pub days: u64,
+}
+
+impl RetentionPolicy {
+    /// The retention period and mode applying to a user, if any.
+    fn effective(&self, settings: &settings::Retention) -> Option<(i32, RetentionMode)> {
+        self.days
+            .or(settings.raw_points_days)
+            .map(|days| (days, self.mode.unwrap_or(settings.mode)))
+    }
+}
+
+/// The first day kept with a retention period of `days` days, if there is
+/// one.
+fn cutoff(today: Date, days: i32) -> Option<Date> {
+    today.checked_sub(time::Duration::days(days.into()))
+}
+
+async fn delete_expired(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    cutoff: Date,
+) -> sqlx::Result<u64> {
+    let start = cutoff.midnight();
+    sqlx::query!(
+        r#"DELETE FROM segments WHERE user_identifier=$1 AND end_time < $2"#,
+        user_id,
+        start
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM geofence_events WHERE user_identifier=$1 AND time_id < $2"#,
+        user_id,
+        start
+    )
+    .execute(&mut *tx)
+    .await?;
+    for query in [
+        sqlx::query!(
+            r#"DELETE FROM visited_days WHERE user_identifier=$1 AND day < $2"#,
+            user_id,
+            cutoff
+        ),
+        sqlx::query!(
+            r#"DELETE FROM visited_cache WHERE user_identifier=$1 AND day < $2"#,
+            user_id,
+            cutoff
+        ),
+        sqlx::query!(
+            r#"DELETE FROM daily_summaries WHERE user_identifier=$1 AND day < $2"#,
+            user_id,
+            cutoff
+        ),
+    ] {
+        query.execute(&mut *tx).await?;
+    }
+    // The deleted points are left to `purge_deletions`, so that their
+    // deletion can still be undone.
+    Ok(sqlx::query!(
+        r#"DELETE FROM points WHERE user_identifier=$1 AND time_id < $2 AND deletion IS NULL"#,
+        user_id,
+        start
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected())
+}
+
+/// Replaces the expired points with a summary of each day and device. The
+/// distance is computed like in the `available` API.
+async fn summarize_expired(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    cutoff: Date,
+) -> sqlx::Result<u64> {
+    let start = cutoff.midnight();
+    sqlx::query!(
+        r#"WITH ordered AS (
+            SELECT user_id, time_id, coords_x, coords_y,
+                LAG(coords_x) OVER w AS prev_x, LAG(coords_y) OVER w AS prev_y
+            FROM points WHERE user_identifier=$1 AND time_id < $2 AND deletion IS NULL
+            WINDOW w AS (PARTITION BY user_id, DATE(time_id) ORDER BY time_id)
+        )
+        INSERT INTO daily_summaries (user_identifier, device_id, day, points, first, last, distance)
+        SELECT $1, user_id, DATE(time_id), COUNT(*), MIN(time_id), MAX(time_id),
+            COALESCE(SUM(2 * 6371008.8 * ASIN(LEAST(1, SQRT(
+                POWER(SIN(RADIANS(coords_y - prev_y) / 2), 2)
+                + COS(RADIANS(prev_y)) * COS(RADIANS(coords_y))
+                * POWER(SIN(RADIANS(coords_x - prev_x) / 2), 2)))))
+                FILTER (WHERE prev_x IS NOT NULL AND prev_y IS NOT NULL), 0)::FLOAT8
+        FROM ordered GROUP BY user_id, DATE(time_id)
+        ON CONFLICT (user_identifier, device_id, day) DO UPDATE SET
+            points=daily_summaries.points + EXCLUDED.points,
+            first=LEAST(daily_summaries.first, EXCLUDED.first),
+            last=GREATEST(daily_summaries.last, EXCLUDED.last),
+            distance=daily_summaries.distance + EXCLUDED.distance"#,
+        user_id,
+        start
+    )
+    .execute(&mut *tx)
+    .await?;
+    // Like in `delete_expired`, the deleted points are left alone.
+    Ok(sqlx::query!(
+        r#"DELETE FROM points WHERE user_identifier=$1 AND time_id < $2 AND deletion IS NULL"#,
+        user_id,
+        start
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected())
+}
+
+/// The expired days with points not simplified yet.
+async fn days_to_simplify(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    cutoff: Date,
+) -> sqlx::Result<Vec<Date>> {
+    sqlx::query_scalar!(
+        r#"SELECT DISTINCT DATE(time_id) AS "day!" FROM points
+           WHERE user_identifier=$1 AND time_id < $2 AND NOT simplified
+           AND deletion IS NULL"#,
+        user_id,
+        cutoff.midnight()
+    )
+    .fetch_all(&mut *tx)
+    .await
+}
+
+/// Simplifies the track of each device on a day, without changing anything.
+/// Returns the points kept and the ones dropped.
+async fn simplify_day(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    day: Date,
+    tolerance: f64,
+) -> sqlx::Result<(Vec<i32>, Vec<i32>)> {
+    let rows = sqlx::query!(
+        r#"SELECT pt_id, user_id, coords_x AS "coords_x!", coords_y AS "coords_y!"
+           FROM points WHERE user_identifier=$1 AND NOT simplified AND deletion IS NULL
+           AND time_id >= $2 AND time_id < $3
+           AND coords_x IS NOT NULL AND coords_y IS NOT NULL
+           ORDER BY user_id, time_id"#,
+        user_id,
+        day.midnight(),
+        day.next_day().unwrap_or(day).midnight()
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let (mut kept, mut dropped) = (vec![], vec![]);
+    for track in rows.chunk_by(|a, b| a.user_id == b.user_id) {
+        let path: Vec<_> = track.iter().map(|p| [p.coords_x, p.coords_y]).collect();
+        for (point, keep) in track.iter().zip(simplify_mask(&path, tolerance)) {
+            if keep {
+                kept.push(point.pt_id);
+            } else {
+                dropped.push(point.pt_id);
+            }
+        }
+    }
+    Ok((kept, dropped))
+}
+
+/// Simplifies the track of each expired day and device. The kept points are
+/// flagged so that they are not simplified again.
+async fn simplify_expired(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    cutoff: Date,
+    tolerance: f64,
+) -> sqlx::Result<u64> {
+    let mut removed = 0;
+    for day in days_to_simplify(tx, user_id, cutoff).await? {
+        let (kept, dropped) = simplify_day(tx, user_id, day, tolerance).await?;
+        sqlx::query!(
+            r#"UPDATE points SET simplified=true WHERE pt_id = ANY($1)"#,
+            &kept
+        )
+        .execute(&mut *tx)
+        .await?;
+        removed += sqlx::query!(r#"DELETE FROM points WHERE pt_id = ANY($1)"#, &dropped)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+    }
+    Ok(removed)
+}
+
+/// Counts the points a purge would remove, without changing anything.
+async fn count_expired(
+    tx: &mut Transaction<'_, Postgres>,
+    user_id: i32,
+    cutoff: Date,
+    mode: RetentionMode,
+    tolerance: f64,
+) -> sqlx::Result<u64> {
+    if mode == RetentionMode::Simplify {
+        let mut dropped = 0;
+        for day in days_to_simplify(tx, user_id, cutoff).await? {
+            dropped += simplify_day(tx, user_id, day, tolerance).await?.1.len() as u64;
+        }
+        return Ok(dropped);
+    }
+    let points = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) AS "points!" FROM points
+           WHERE user_identifier=$1 AND time_id < $2 AND deletion IS NULL"#,
+        user_id,
+        cutoff.midnight()
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    Ok(points as u64)
+}
+
+/// Purges the points of a user before `cutoff`, in a transaction. Returns the
+/// number of points removed and of days touched. With `dry_run`, only the
+/// counts are computed.
+async fn purge_user(
+    pool: &PgPool,
+    settings: &settings::Retention,
+    user_id: i32,
+    cutoff: Date,
+    mode: RetentionMode,
+    dry_run: bool,
+) -> sqlx::Result<(u64, u64)> {
+    let mut tx = pool.begin().await?;
+    let touched_days = sqlx::query!(
+        r#"SELECT COUNT(DISTINCT DATE(time_id)) AS "days!" FROM points
+           WHERE user_identifier=$1 AND time_id < $2 AND deletion IS NULL
+           AND ($3 OR NOT simplified)"#,
+        user_id,
+        cutoff.midnight(),
+        mode != RetentionMode::Simplify
+    )
+    .fetch_one(&mut tx)
+    .await?
+    .days;
+    let tolerance = settings.simplify_tolerance;
+    let points = if dry_run {
+        count_expired(&mut tx, user_id, cutoff, mode, tolerance).await?
+    } else {
+        match mode {
+            RetentionMode::Delete => delete_expired(&mut tx, user_id, cutoff).await?,
+            RetentionMode::Summarize => summarize_expired(&mut tx, user_id, cutoff).await?,
+            RetentionMode::Simplify => {
+                simplify_expired(&mut tx, user_id, cutoff, tolerance).await?
+            }
+        }
+    };
+    tx.commit().await?;
+    Ok((points, touched_days as u64))
+}
+
+/// Purges the expired points of every user. With `dry_run`, nothing is
+/// changed but the reports tell what would have been removed. A user whose
+/// purge fails is logged and skipped.
+pub(crate) async fn purge(
+    pool: &PgPool,
+    settings: &settings::Retention,
+    dry_run: bool,
+) -> sqlx::Result<Vec<PurgeReport>> {
+    let today = OffsetDateTime::now_utc().date();
+    let users = sqlx::query!(
+        r#"SELECT id, username, retention_days,
+           retention_mode AS "retention_mode: RetentionMode" FROM users ORDER BY id"#
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut reports = vec![];
+    for user in users {
+        let policy = RetentionPolicy {
+            days: user.retention_days,
+            mode: user.retention_mode,
+        };
+        let Some((days, mode)) = policy.effective(settings) else {
+            continue;
+        };
+        let Some(cutoff) = cutoff(today, days) else {
+            tracing::warn!(
+                "retention: skipping {}, {days} days is out of range",
+                user.username
+            );
+            continue;
+        };
+        match purge_user(pool, settings, user.id, cutoff, mode, dry_run).await {
+            Ok((points, days)) => reports.push(PurgeReport {
+                username: user.username,
+                mode,
+                cutoff,
+                points,
+                days,
+            }),
+            Err(e) => tracing::error!("error purging the points of {}: {e}", user.username),
+        }
+    }
+    Ok(reports)
+}
+
+/// Runs the purge regularly in the background.
+pub(crate) fn spawn_purge_task(pool: PgPool, settings: settings::Retention) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(settings.interval_secs));
+        loop {
+            interval.tick().await;
+            match purge_deletions(&pool, false).await {
+                Ok(0) => (),
+                Ok(points) => tracing::info!("removed {points} deleted points for good"),
+                Err(e) => tracing::error!("error removing the deleted points: {e}"),
+            }
+            match purge(&pool, &settings, false).await {
+                Ok(reports) => {
+                    for report in reports.iter().filter(|report| report.points > 0) {
+                        tracing::info!(
+                            "retention: removed {} points of {} before {} ({:?})",
+                            report.points,
+                            report.username,
+                            report.cutoff,
+                            report.mode
+                        );
+                    }
+                }
+                Err(e) => tracing::error!("error purging expired points: {e}"),
+            }
+        }
+    });
+}
+
+/// Purges the expired points from the command line, or only shows what would
+/// be removed with `dry_run`.
+pub async fn purge_retention(dry_run: bool) -> Result<(), sqlx::Error> {
+    let settings = Settings::new().unwrap();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.");
+
+    let deleted = purge_deletions(&pool, dry_run).await?;
+    println!(
+        "{} {deleted} deleted points past their undo window",
+        if dry_run { "Would remove" } else { "Removed" }
+    );
+    let reports = purge(&pool, &settings.retention, dry_run).await?;
+    if reports.is_empty() {
+        println!("No retention policy applies.");
+    }
+    for report in reports {
+        println!(
+            "{}: {} {} points from {} days before {} ({:?})",
+            report.username,
+            if dry_run { "would remove" } else { "removed" },
+            report.points,
+            report.days,
+            report.cutoff,
+            report.mode
+        );
+    }
+    Ok(())
+}
+
+/// API method to get the retention policy of the current user.
+pub async fn get_retention(
+    Extension(pool): Extension<PgPool>,
+    Extension(settings): Extension<settings::Retention>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<UserRetention>), (StatusCode, String)> {
+    let policy = sqlx::query_as!(
+        RetentionPolicy,
+        r#"SELECT retention_days AS days, retention_mode AS "mode: RetentionMode"
+           FROM users WHERE id=$1"#,
+        current_user.user_id
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        StatusCode::OK,
+        Json(UserRetention {
+            policy,
+            default: RetentionPolicy {
+                days: settings.raw_points_days,
+                mode: Some(settings.mode),
+            },
+        }),
+    ))
+}
+
+/// API method to set the retention policy of the current user. Missing fields
+/// reset to the server defaults.
+pub async fn set_retention(
+    Json(policy): Json<RetentionPolicy>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if policy
+        .days
+        .is_some_and(|days| !(1..=MAX_RETENTION_DAYS).contains(&days))
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("The retention period must be between 1 and {MAX_RETENTION_DAYS} days"),
+        ));
+    }
+    sqlx::query!(
+        r#"UPDATE users SET retention_days=$2, retention_mode=$3 WHERE id=$1"#,
+        current_user.user_id,
+        policy.days,
+        policy.mode as Option<RetentionMode>
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::date;
+
+    #[test]
+    fn should_resolve_retention_policies() {
+        let server = settings::Retention {
+            raw_points_days: Some(365),
+            ..Default::default()
+        };
+        let unset = RetentionPolicy {
+            days: None,
+            mode: None,
+        };
+        let custom = RetentionPolicy {
+            days: Some(30),
+            mode: Some(RetentionMode::Delete),
+        };
+        assert_eq!(
+            unset.effective(&server),
+            Some((365, RetentionMode::Summarize))
+        );
+        assert_eq!(custom.effective(&server), Some((30, RetentionMode::Delete)));
+        assert_eq!(unset.effective(&settings::Retention::default()), None);
+        assert_eq!(
+            cutoff(date!(2022 - 03 - 01), 30),
+            Some(date!(2022 - 01 - 30))
+        );
+        assert_eq!(cutoff(date!(2022 - 03 - 01), i32::MAX), None);
+    }
+}
@@ -0,0 +1,582 @@
+/// Server side segmentation of the raw point stream into trips and stays, for
+/// devices that do not send Overland trip objects.
+use crate::api::{parse_datetime, parse_motions, serialize_timestamp, Motion};
+use crate::auth::CurrentUser;
+use crate::geo::{haversine, simplify};
+use crate::geocoder::{Geocoder, Place, SharedGeocoder};
+use crate::privacy::PrivacyZones;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use std::collections::HashMap;
+use time::Duration;
+
+/// A point of a device track, as used by the segmentation.
+#[derive(Debug, Clone)]
+pub(crate) struct TrackPoint {
+    pub time: PrimitiveDateTime,
+    pub coords: [f64; 2],
+    pub speed: Option<i32>,
+    pub motions: Vec<Motion>,
+}
+
+/// Thresholds used to split a track.
+#[derive(Debug, Clone)]
+pub(crate) struct SegmentationParams {
+    /// Points further apart in time than this are not part of the same trip.
+    pub max_gap: Duration,
+    /// A stay is a group of points within this distance in meters of its first
+    /// point.
+    pub stay_radius: f64,
+    /// Minimum duration of a stay.
+    pub min_stay: Duration,
+    /// A trip is only split when a new transport mode lasts at least this long.
+    pub min_mode_duration: Duration,
+    /// Tolerance in meters of the simplified trip paths.
+    pub simplify_tolerance: f64,
+}
+
+impl Default for SegmentationParams {
+    fn default() -> Self {
+        SegmentationParams {
+            max_gap: Duration::minutes(10),
+            stay_radius: 100.,
+            min_stay: Duration::minutes(5),
+            min_mode_duration: Duration::minutes(2),
+            simplify_tolerance: 10.,
+        }
+    }
+}
+
+/// The type specific part of a segment.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SegmentKind {
+    /// The device stayed around a place.
+    Stay {
+        /// Center of the stay.
+        place: [f64; 2],
+    },
+    /// The device moved from one place to another.
+    Trip {
+        /// First point of the trip.
+        start_place: [f64; 2],
+        /// Last point of the trip.
+        end_place: [f64; 2],
+        /// Length of the trip in meters.
+        distance: f64,
+        /// The transport mode used for most of the trip, if known.
+        mode: Option<Motion>,
+        /// The simplified path of the trip.
+        path: Vec<[f64; 2]>,
+    },
+}
+
+/// A trip or a stay of a device.
+#[derive(Serialize, Debug, Clone)]
+pub struct Segment {
+    device: String,
+    #[serde(serialize_with = "serialize_timestamp")]
+    start: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_timestamp")]
+    end: PrimitiveDateTime,
+    #[serde(flatten)]
+    kind: SegmentKind,
+    /// Nearest locality of a stay.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    locality: Option<Place>,
+    /// Nearest locality of the start of a trip.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_locality: Option<Place>,
+    /// Nearest locality of the end of a trip.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end_locality: Option<Place>,
+}
+
+impl Segment {
+    fn new(
+        device: &str,
+        start: PrimitiveDateTime,
+        end: PrimitiveDateTime,
+        kind: SegmentKind,
+    ) -> Segment {
+        Segment {
+            device: device.to_string(),
+            start,
+            end,
+            kind,
+            locality: None,
+            start_locality: None,
+            end_locality: None,
+        }
+    }
+
+    /// Fills the localities of the segment.
+    fn annotate(&mut self, geocoder: &Geocoder) {
+        match &self.kind {
+            SegmentKind::Stay { place } => self.locality = geocoder.nearest(*place),
+            SegmentKind::Trip {
+                start_place,
+                end_place,
+                ..
+            } => {
+                self.start_locality = geocoder.nearest(*start_place);
+                self.end_locality = geocoder.nearest(*end_place);
+            }
+        }
+    }
+}
+
+/// Query parameters of the segments API.
+#[derive(Deserialize, Debug)]
+pub struct SegmentQuery {
+    /// Start of the time range.
+    start: String,
+    /// End of the time range.
+    end: String,
+    /// Only segment the points of this device.
+    device: Option<String>,
+    /// Store the computed segments, replacing the stored segments of the range.
+    #[serde(default)]
+    materialize: bool,
+    /// Return the stored segments instead of computing them.
+    #[serde(default)]
+    stored: bool,
+}
+
+fn speed_mode(speed: i32) -> Option<Motion> {
+    // Overland reports speeds in m/s and -1 when unknown.
+    match speed {
+        s if s <= 0 => None,
+        1..=2 => Some(Motion::Walking),
+        3..=7 => Some(Motion::Cycling),
+        _ => Some(Motion::Driving),
+    }
+}
+
+/// The transport mode of a single point. Motion activity from the phone takes
+/// precedence over the speed.
+fn point_mode(point: &TrackPoint) -> Option<Motion> {
+    point
+        .motions
+        .iter()
+        .find(|m| **m != Motion::Stationary)
+        .copied()
+        .or_else(|| point.speed.and_then(speed_mode))
+}
+
+fn dominant_mode(points: &[TrackPoint]) -> Option<Motion> {
+    let mut durations: HashMap<Motion, f64> = HashMap::new();
+    for pair in points.windows(2) {
+        if let Some(mode) = point_mode(&pair[0]) {
+            *durations.entry(mode).or_default() += (pair[1].time - pair[0].time).as_seconds_f64();
+        }
+    }
+    durations
+        .into_iter()
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(mode, _)| mode)
+}
+
+/// Splits a track in runs of the same transport mode. Runs are returned as
+/// their start index, consecutive runs share their boundary point.
+fn split_by_mode(points: &[TrackPoint], params: &SegmentationParams) -> Vec<usize> {
+    let mut runs: Vec<(usize, Option<Motion>)> = vec![];
+    for (idx, mode) in points.iter().map(point_mode).enumerate() {
+        match runs.last_mut() {
+            Some((_, last)) if last.is_none() => *last = mode,
+            Some((_, last)) if mode.is_none() || *last == mode => (),
+            _ => runs.push((idx, mode)),
+        }
+    }
+    let run_duration = |runs: &[(usize, Option<Motion>)], k: usize| {
+        let end = runs.get(k + 1).map(|r| r.0).unwrap_or(points.len() - 1);
+        points[end].time - points[runs[k].0].time
+    };
+    // Merge the shortest runs into their neighbours until all are long enough.
+    while runs.len() > 1 {
+        let shortest = (0..runs.len())
+            .min_by_key(|&k| run_duration(&runs, k))
+            .unwrap();
+        if run_duration(&runs, shortest) >= params.min_mode_duration {
+            break;
+        }
+        if shortest == 0 {
+            runs[1].0 = 0;
+            runs.remove(0);
+        } else {
+            runs.remove(shortest);
+        }
+        runs.dedup_by(|b, a| a.1 == b.1);
+    }
+    runs.into_iter().map(|(start, _)| start).collect()
+}
+
+fn stay(device: &str, points: &[TrackPoint]) -> Segment {
+    let n = points.len() as f64;
+    let place = [
+        points.iter().map(|p| p.coords[0]).sum::<f64>() / n,
+        points.iter().map(|p| p.coords[1]).sum::<f64>() / n,
+    ];
+    Segment::new(
+        device,
+        points[0].time,
+        points[points.len() - 1].time,
+        SegmentKind::Stay { place },
+    )
+}
+
+fn trip(device: &str, points: &[TrackPoint], params: &SegmentationParams) -> Segment {
+    let path: Vec<[f64; 2]> = points.iter().map(|p| p.coords).collect();
+    Segment::new(
+        device,
+        points[0].time,
+        points[points.len() - 1].time,
+        SegmentKind::Trip {
+            start_place: path[0],
+            end_place: path[path.len() - 1],
+            distance: path.windows(2).map(|w| haversine(w[0], w[1])).sum(),
+            mode: dominant_mode(points),
+            path: simplify(&path, params.simplify_tolerance),
+        },
+    )
+}
+
+/// Turns the moving points between two stays into trips, split on time gaps
+/// and changes of transport mode.
+fn push_trips(
+    segments: &mut Vec<Segment>,
+    device: &str,
+    points: &[TrackPoint],
+    params: &SegmentationParams,
+) {
+    let mut start = 0;
+    for idx in 1..=points.len() {
+        if idx < points.len() && points[idx].time - points[idx - 1].time <= params.max_gap {
+            continue;
+        }
+        let piece = &points[start..idx];
+        start = idx;
+        if piece.len() < 2 {
+            continue;
+        }
+        let runs = split_by_mode(piece, params);
+        for (k, run_start) in runs.iter().enumerate() {
+            let run_end = runs.get(k + 1).copied().unwrap_or(piece.len() - 1);
+            segments.push(trip(device, &piece[*run_start..=run_end], params));
+        }
+    }
+}
+
+/// Segments the time ordered track of a device into trips and stays.
+pub(crate) fn segment(
+    device: &str,
+    points: &[TrackPoint],
+    params: &SegmentationParams,
+) -> Vec<Segment> {
+    let mut segments = vec![];
+    let mut trip_start = None;
+    let mut i = 0;
+    while i < points.len() {
+        let mut j = i + 1;
+        while j < points.len()
+            && haversine(points[i].coords, points[j].coords) <= params.stay_radius
+        {
+            j += 1;
+        }
+        if points[j - 1].time - points[i].time >= params.min_stay {
+            if let Some(start) = trip_start.take() {
+                push_trips(&mut segments, device, &points[start..=i], params);
+            }
+            segments.push(stay(device, &points[i..j]));
+            trip_start = Some(j - 1);
+            i = j;
+        } else {
+            trip_start.get_or_insert(i);
+            i += 1;
+        }
+    }
+    if let Some(start) = trip_start {
+        push_trips(&mut segments, device, &points[start..], params);
+    }
+
+    // Drop the jitter between two stays and merge the stays it separated.
+    let mut merged: Vec<Segment> = vec![];
+    for seg in segments {
+        if let SegmentKind::Trip { distance, .. } = seg.kind {
+            if distance < params.stay_radius {
+                continue;
+            }
+        }
+        if let (Some(last), SegmentKind::Stay { place }) = (merged.last_mut(), &seg.kind) {
+            if let SegmentKind::Stay { place: last_place } = last.kind {
+                if haversine(last_place, *place) <= params.stay_radius {
+                    last.end = seg.end;
+                    continue;
+                }
+            }
+        }
+        merged.push(seg);
+    }
+    merged
+}
+
+async fn stored_segments(
+    pool: &PgPool,
+    user_id: i32,
+    start: PrimitiveDateTime,
+    end: PrimitiveDateTime,
+    device: Option<String>,
+) -> sqlx::Result<Vec<Segment>> {
+    Ok(sqlx::query!(
+        r#"SELECT device_id, start_time, end_time, details AS "details: sqlx::types::Json<SegmentKind>"
+           FROM segments WHERE user_identifier=$1 AND start_time < $3 AND end_time >= $2
+           AND ($4::TEXT IS NULL OR device_id=$4) ORDER BY device_id, start_time"#,
+        user_id,
+        start,
+        end,
+        device
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| Segment::new(&row.device_id, row.start_time, row.end_time, row.details.0))
+    .collect())
+}
+
+async fn materialize(
+    pool: &PgPool,
+    user_id: i32,
+    start: PrimitiveDateTime,
+    end: PrimitiveDateTime,
+    device: &Option<String>,
+    segments: &[Segment],
+) -> sqlx::Result<()> {
+    let mut transaction = pool.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM segments WHERE user_identifier=$1 AND start_time < $3 AND end_time >= $2
+           AND ($4::TEXT IS NULL OR device_id=$4)"#,
+        user_id,
+        start,
+        end,
+        device.as_ref()
+    )
+    .execute(&mut transaction)
+    .await?;
+    for seg in segments {
+        sqlx::query!(
+            r#"INSERT INTO segments (user_identifier, device_id, start_time, end_time, details)
+               VALUES ( $1, $2, $3, $4, $5 )"#,
+            user_id,
+            seg.device,
+            seg.start,
+            seg.end,
+            sqlx::types::Json(&seg.kind) as _
+        )
+        .execute(&mut transaction)
+        .await?;
+    }
+    transaction.commit().await
+}
+
+/// Loads the track of each device of a user in a time range.
+pub(crate) async fn load_tracks(
+    pool: &PgPool,
+    user_id: i32,
+    start: PrimitiveDateTime,
+    end: PrimitiveDateTime,
+    device: Option<&str>,
+) -> sqlx::Result<Vec<(String, Vec<TrackPoint>)>> {
+    let rows = sqlx::query!(
+        r#"SELECT user_id, time_id, speed, motion, coords_x, coords_y FROM points
+           WHERE user_identifier=$1 AND time_id >= $2 AND time_id < $3 AND deletion IS NULL
+           AND ($4::TEXT IS NULL OR user_id=$4) ORDER BY user_id, time_id"#,
+        user_id,
+        start,
+        end,
+        device
+    )
+    .fetch_all(pool)
+    .await?;
+    let zones = PrivacyZones::load(pool, Some(user_id)).await?;
+    let mut tracks: Vec<(String, Vec<TrackPoint>)> = vec![];
+    for row in rows {
+        let (Some(time), Some(x), Some(y)) = (row.time_id, row.coords_x, row.coords_y) else {
+            continue;
+        };
+        let Some(coords) = zones.apply(user_id, [x, y], true) else {
+            continue;
+        };
+        let point = TrackPoint {
+            time,
+            coords,
+            speed: row.speed,
+            motions: parse_motions(&row.motion.unwrap_or_default()),
+        };
+        match tracks.last_mut() {
+            Some((device, track)) if *device == row.user_id => track.push(point),
+            _ => tracks.push((row.user_id, vec![point])),
+        }
+    }
+    Ok(tracks)
+}
+
+/// API method to get the trips and stays of the current user in a time range.
+/// Segments are annotated with the nearest localities when reverse geocoding
+/// is configured.
+pub async fn query_segments(
+    Query(query): Query<SegmentQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(geocoder): Extension<SharedGeocoder>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Segment>>), (StatusCode, String)> {
+    let parse = |date: &str| {
+        parse_datetime(date).ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}")))
+    };
+    let (start, end) = (parse(&query.start)?, parse(&query.end)?);
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+
+    let annotate = |mut segments: Vec<Segment>| {
+        if let Some(geocoder) = &geocoder {
+            segments.iter_mut().for_each(|seg| seg.annotate(geocoder));
+        }
+        segments
+    };
+
+    if query.stored {
+        let segments = stored_segments(&pool, current_user.user_id, start, end, query.device)
+            .await
+            .map_err(internal_error)?;
+        return Ok((StatusCode::OK, Json(annotate(segments))));
+    }
+
+    let params = SegmentationParams::default();
+    let segments: Vec<Segment> = load_tracks(
+        &pool,
+        current_user.user_id,
+        start,
+        end,
+        query.device.as_deref(),
+    )
+    .await
+    .map_err(internal_error)?
+    .iter()
+    .flat_map(|(device, track)| segment(device, track, &params))
+    .collect();
+    if query.materialize {
+        materialize(
+            &pool,
+            current_user.user_id,
+            start,
+            end,
+            &query.device,
+            &segments,
+        )
+        .await
+        .map_err(internal_error)?;
+    }
+    Ok((StatusCode::OK, Json(annotate(segments))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn track(moves: &[(i64, [f64; 2], i32, Option<Motion>)]) -> Vec<TrackPoint> {
+        moves
+            .iter()
+            .map(|(minute, coords, speed, motion)| TrackPoint {
+                time: datetime!(2022-05-01 8:00) + Duration::minutes(*minute),
+                coords: *coords,
+                speed: Some(*speed),
+                motions: motion.iter().copied().collect(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn should_split_stays_and_trips() {
+        let stationary = Some(Motion::Stationary);
+        let mut moves = vec![];
+        // At home for 20 minutes.
+        for minute in 0..20 {
+            moves.push((minute, [0., 0.], 0, stationary));
+        }
+        // Drive east for 10 minutes.
+        for minute in 1..=10 {
+            let x = 0.01 * minute as f64;
+            moves.push((20 + minute, [x, 0.], 15, Some(Motion::Driving)));
+        }
+        // Stay at work for 30 minutes.
+        for minute in 1..=30 {
+            moves.push((30 + minute, [0.1, 0.], 0, stationary));
+        }
+        let segments = segment("phone", &track(&moves), &SegmentationParams::default());
+        let kinds: Vec<&str> = segments
+            .iter()
+            .map(|s| match s.kind {
+                SegmentKind::Stay { .. } => "stay",
+                SegmentKind::Trip { .. } => "trip",
+            })
+            .collect();
+        assert_eq!(kinds, vec!["stay", "trip", "stay"]);
+        match &segments[1].kind {
+            SegmentKind::Trip {
+                distance,
+                mode,
+                path,
+                ..
+            } => {
+                assert!((distance - 11_119.).abs() < 50., "{distance}");
+                assert_eq!(*mode, Some(Motion::Driving));
+                // A straight line only keeps its endpoints.
+                assert_eq!(path.len(), 2);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn should_split_trips_on_mode_changes_and_gaps() {
+        let mut moves = vec![];
+        // Walk 5 minutes then drive 10 minutes.
+        for minute in 0..5 {
+            moves.push((
+                minute,
+                [0.001 * minute as f64, 0.],
+                1,
+                Some(Motion::Walking),
+            ));
+        }
+        for minute in 5..15 {
+            let x = 0.004 + 0.01 * (minute - 4) as f64;
+            moves.push((minute, [x, 0.], 15, Some(Motion::Driving)));
+        }
+        // No data for an hour, then drive again.
+        for minute in 75..85 {
+            let x = 1. + 0.01 * (minute - 75) as f64;
+            moves.push((minute, [x, 0.], 15, Some(Motion::Driving)));
+        }
+        let segments = segment("phone", &track(&moves), &SegmentationParams::default());
+        let modes: Vec<Option<Motion>> = segments
+            .iter()
+            .map(|s| match s.kind {
+                SegmentKind::Trip { mode, .. } => mode,
+                SegmentKind::Stay { .. } => None,
+            })
+            .collect();
+        assert_eq!(
+            modes,
+            vec![
+                Some(Motion::Walking),
+                Some(Motion::Driving),
+                Some(Motion::Driving)
+            ]
+        );
+        assert_eq!(segments[2].start, datetime!(2022-05-01 9:15));
+    }
+}
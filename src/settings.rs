@@ -1,5 +1,6 @@
 /// This module is used to parse and read from configuration files for the
 /// server.
+use crate::retention::{RetentionMode, MAX_RETENTION_DAYS};
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 
@@ -20,12 +21,222 @@ pub struct Auth {
     /// Development mode where no authentication is needed.
     #[serde(default = "default_dev")]
     pub develop: bool,
+    /// Number of hours after which an unused login session expires.
+    #[serde(default = "default_session_idle")]
+    pub session_idle_hours: i64,
+    /// Number of days after which a login session expires, even when used.
+    #[serde(default = "default_session_max")]
+    pub session_max_days: i64,
+    /// Whether tokens are accepted as a `token=` query parameter. The
+    /// Overland app needs it, but the tokens end up in the access logs.
+    #[serde(default = "default_query_tokens")]
+    pub query_tokens: bool,
+    /// Failed logins allowed for a username before the next attempts are
+    /// delayed.
+    #[serde(default = "default_login_attempts")]
+    pub login_attempts: u32,
+    /// Failed logins and invalid tokens allowed from an address before the
+    /// next attempts are delayed.
+    #[serde(default = "default_ip_attempts")]
+    pub ip_attempts: u32,
+    /// The delay after a failed attempt starts at one second and doubles up to
+    /// this many minutes.
+    #[serde(default = "default_lockout_minutes")]
+    pub lockout_minutes: u64,
 }
 
 fn default_dev() -> bool {
     false
 }
 
+fn default_session_idle() -> i64 {
+    48
+}
+
+fn default_session_max() -> i64 {
+    30
+}
+
+fn default_query_tokens() -> bool {
+    true
+}
+
+fn default_login_attempts() -> u32 {
+    5
+}
+
+fn default_ip_attempts() -> u32 {
+    20
+}
+
+fn default_lockout_minutes() -> u64 {
+    15
+}
+
+/// This configuration object contains the webhook delivery config.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Webhooks {
+    /// Number of delivery attempts before giving up on an event.
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+    /// Delay before the first retry in seconds. It is doubled after each failed
+    /// attempt.
+    #[serde(default = "default_webhook_backoff")]
+    pub initial_backoff_secs: u64,
+    /// Timeout of a single delivery request in seconds.
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout_secs: u64,
+    /// Lets webhooks target loopback, private and link-local addresses, like a
+    /// home automation server on the local network.
+    #[serde(default)]
+    pub allow_private_targets: bool,
+}
+
+impl Default for Webhooks {
+    fn default() -> Self {
+        Webhooks {
+            max_attempts: default_webhook_attempts(),
+            initial_backoff_secs: default_webhook_backoff(),
+            timeout_secs: default_webhook_timeout(),
+            allow_private_targets: false,
+        }
+    }
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
+}
+
+fn default_webhook_backoff() -> u64 {
+    2
+}
+
+fn default_webhook_timeout() -> u64 {
+    10
+}
+
+/// This configuration object contains the paths of the offline gazetteer used
+/// for reverse geocoding. Reverse geocoding is disabled when it is absent.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Geocoder {
+    /// Path to a GeoNames cities dump such as `cities1000.txt`.
+    pub cities_path: String,
+    /// Optional path to the GeoNames `admin1CodesASCII.txt` file, used for
+    /// region names.
+    pub admin1_path: Option<String>,
+    /// Optional path to the GeoNames `countryInfo.txt` file, used for country
+    /// names.
+    pub countries_path: Option<String>,
+}
+
+/// This configuration object contains the paths of the country and region
+/// boundaries used by the visited places report. The report is disabled when
+/// it is absent.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Boundaries {
+    /// Path to a GeoJSON feature collection of country polygons, such as the
+    /// Natural Earth admin 0 countries.
+    pub countries_path: String,
+    /// Optional path to a GeoJSON feature collection of first level
+    /// administrative regions, such as the Natural Earth admin 1 states and
+    /// provinces.
+    pub regions_path: Option<String>,
+}
+
+/// This configuration object contains the server wide data retention policy.
+/// Users can override it with their own.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Retention {
+    /// Raw points older than this many days are purged. They are kept forever
+    /// when absent.
+    pub raw_points_days: Option<i32>,
+    /// What is kept of the purged points.
+    #[serde(default = "default_retention_mode")]
+    pub mode: RetentionMode,
+    /// Tolerance in meters of the simplification of the purged tracks.
+    #[serde(default = "default_retention_tolerance")]
+    pub simplify_tolerance: f64,
+    /// Delay between two purges in seconds.
+    #[serde(default = "default_retention_interval")]
+    pub interval_secs: u64,
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention {
+            raw_points_days: None,
+            mode: default_retention_mode(),
+            simplify_tolerance: default_retention_tolerance(),
+            interval_secs: default_retention_interval(),
+        }
+    }
+}
+
+impl Retention {
+    /// Checks the retention period and the delay between two purges.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self
+            .raw_points_days
+            .is_some_and(|days| !(1..=MAX_RETENTION_DAYS).contains(&days))
+        {
+            Err(ConfigError::Message(format!(
+                "retention.raw_points_days must be between 1 and {MAX_RETENTION_DAYS}"
+            )))
+        } else if self.interval_secs == 0 {
+            Err(ConfigError::Message(
+                "retention.interval_secs must be positive".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+fn default_retention_mode() -> RetentionMode {
+    RetentionMode::Summarize
+}
+
+fn default_retention_tolerance() -> f64 {
+    10.
+}
+
+fn default_retention_interval() -> u64 {
+    6 * 3600
+}
+
+/// This configuration object contains the personal data takeout config.
+#[derive(Debug, Deserialize, Clone)]
+#[allow(unused)]
+pub struct Takeout {
+    /// Directory where the archives are written.
+    #[serde(default = "default_takeout_dir")]
+    pub dir: String,
+    /// Number of hours an archive can be downloaded before it is removed.
+    #[serde(default = "default_takeout_expiry")]
+    pub expires_hours: i64,
+}
+
+impl Default for Takeout {
+    fn default() -> Self {
+        Takeout {
+            dir: default_takeout_dir(),
+            expires_hours: default_takeout_expiry(),
+        }
+    }
+}
+
+fn default_takeout_dir() -> String {
+    "data/takeouts".to_string()
+}
+
+fn default_takeout_expiry() -> i64 {
+    72
+}
+
 /// The app wide settings
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
@@ -46,6 +257,19 @@ pub struct Settings {
     pub auth: Auth,
     /// The app-wide config.
     pub base: BaseSettings,
+    /// Webhook delivery config.
+    #[serde(default)]
+    pub webhooks: Webhooks,
+    /// Reverse geocoding config.
+    pub geocoder: Option<Geocoder>,
+    /// Country and region boundaries config.
+    pub boundaries: Option<Boundaries>,
+    /// Data retention config.
+    #[serde(default)]
+    pub retention: Retention,
+    /// Personal data takeout config.
+    #[serde(default)]
+    pub takeout: Takeout,
 }
 
 impl Settings {
@@ -57,6 +281,8 @@ impl Settings {
             .add_source(File::with_name("config/local").required(false))
             .add_source(Environment::with_prefix("rov"))
             .build()?;
-        s.try_deserialize()
+        let settings: Settings = s.try_deserialize()?;
+        settings.retention.validate()?;
+        Ok(settings)
     }
 }
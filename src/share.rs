@@ -0,0 +1,291 @@
+/// Public share links. A link gives read-only access, without logging in, to
+/// either the live position of a device or a fixed time range, until it expires
+/// or is revoked.
+use crate::api::{parse_datetime, serialize_optional_timestamp, serialize_timestamp};
+use crate::auth::CurrentUser;
+use crate::privacy::PrivacyZones;
+use crate::util::now;
+use crate::HtmlTemplate;
+use askama::Template;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgPool;
+use sqlx::types::time::PrimitiveDateTime;
+use time::Duration;
+
+const SHARE_TOKEN_LEN: usize = 32;
+/// Maximum number of points returned by a share feed.
+const MAX_SHARE_POINTS: i64 = 5000;
+
+/// What a share link gives access to.
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "SHARE_SCOPE", rename_all = "lowercase")]
+pub enum ShareScope {
+    /// The position of a device while the link is valid.
+    Live,
+    /// The points of a fixed time range.
+    Range,
+}
+
+/// A share link created by a user.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct Share {
+    id: i32,
+    token: String,
+    scope: ShareScope,
+    device_id: Option<String>,
+    #[serde(rename = "start", serialize_with = "serialize_optional_timestamp")]
+    start_time: Option<PrimitiveDateTime>,
+    #[serde(rename = "end", serialize_with = "serialize_optional_timestamp")]
+    end_time: Option<PrimitiveDateTime>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+    revoked: bool,
+}
+
+/// The body of a share link creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewShare {
+    /// What the link gives access to.
+    scope: ShareScope,
+    /// The shared device. Required for live links, optional for time ranges.
+    device: Option<String>,
+    /// Start of the shared time range.
+    start: Option<String>,
+    /// End of the shared time range.
+    end: Option<String>,
+    /// Validity of the link in seconds.
+    expires_in: i64,
+}
+
+/// A shared point. Only the position and time are exposed.
+#[derive(Serialize, Debug)]
+pub struct SharedPoint {
+    /// Timestamp of the point.
+    #[serde(serialize_with = "serialize_timestamp")]
+    time: PrimitiveDateTime,
+    /// Coordinates as `[longitude, latitude]`.
+    coordinates: [f64; 2],
+}
+
+/// The content of a share link.
+#[derive(Serialize, Debug)]
+pub struct ShareFeed {
+    /// What the link gives access to.
+    scope: ShareScope,
+    /// When the link stops working.
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+    /// The shared points, oldest first.
+    points: Vec<SharedPoint>,
+}
+
+#[derive(Template)]
+#[template(path = "share.html")]
+struct ShareTemplate {
+    token: String,
+}
+
+/// Loads a share link if it is still valid. Unknown, expired and revoked links
+/// are indistinguishable.
+async fn valid_share(pool: &PgPool, token: &str) -> Result<(i32, Share), (StatusCode, String)> {
+    let row = sqlx::query!(
+        r#"SELECT user_identifier, id, token, scope AS "scope: ShareScope", device_id,
+           start_time, end_time, created_at, expires_at, revoked
+           FROM shares WHERE token=$1 AND NOT revoked AND expires_at > $2"#,
+        token,
+        now()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "No such share link".to_string()))?;
+    Ok((
+        row.user_identifier,
+        Share {
+            id: row.id,
+            token: row.token,
+            scope: row.scope,
+            device_id: row.device_id,
+            start_time: row.start_time,
+            end_time: row.end_time,
+            created_at: row.created_at,
+            expires_at: row.expires_at,
+            revoked: row.revoked,
+        },
+    ))
+}
+
+/// API method to list the share links of the current user.
+pub async fn list_shares(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Share>>), (StatusCode, String)> {
+    let shares = sqlx::query_as!(
+        Share,
+        r#"SELECT id, token, scope AS "scope: ShareScope", device_id, start_time, end_time,
+           created_at, expires_at, revoked
+           FROM shares WHERE user_identifier=$1 ORDER BY id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(shares)))
+}
+
+/// API method to create a share link. The link is `/share/<token>`.
+pub async fn create_share(
+    Json(new_share): Json<NewShare>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Share>), (StatusCode, String)> {
+    let bad_request = |msg: &str| (StatusCode::BAD_REQUEST, msg.to_string());
+    if new_share.expires_in <= 0 {
+        return Err(bad_request("The link must expire in the future"));
+    }
+    let parse = |date: &Option<String>| match date {
+        Some(date) => parse_datetime(date)
+            .map(Some)
+            .ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}"))),
+        None => Ok(None),
+    };
+    let (start, end) = (parse(&new_share.start)?, parse(&new_share.end)?);
+    match new_share.scope {
+        ShareScope::Live if new_share.device.is_none() => {
+            return Err(bad_request("Live links need a device"))
+        }
+        ShareScope::Live if start.is_some() || end.is_some() => {
+            return Err(bad_request("Live links cannot have a time range"))
+        }
+        ShareScope::Range if !matches!((start, end), (Some(start), Some(end)) if start < end) => {
+            return Err(bad_request(
+                "Time range links need a start before their end",
+            ))
+        }
+        _ => (),
+    }
+    let token: String = {
+        let mut rng = rand::thread_rng();
+        (&mut rng)
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(SHARE_TOKEN_LEN)
+            .map(char::from)
+            .collect()
+    };
+    let now = now();
+    let expires_at = now
+        .checked_add(Duration::seconds(new_share.expires_in))
+        .ok_or_else(|| bad_request("Invalid expiry"))?;
+    let share = sqlx::query_as!(
+        Share,
+        r#"INSERT INTO shares (user_identifier, token, scope, device_id, start_time, end_time,
+           created_at, expires_at)
+           VALUES ( $1, $2, $3, $4, $5, $6, $7, $8 )
+           RETURNING id, token, scope AS "scope: ShareScope", device_id, start_time, end_time,
+           created_at, expires_at, revoked"#,
+        current_user.user_id,
+        token,
+        new_share.scope as ShareScope,
+        new_share.device,
+        start,
+        end,
+        now,
+        expires_at
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(share)))
+}
+
+/// API method to revoke a share link of the current user.
+pub async fn revoke_share(
+    Path(share_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"UPDATE shares SET revoked=true WHERE id=$1 AND user_identifier=$2"#,
+        share_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such share link".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// Public page showing a share link on a read-only map.
+pub async fn serve_share(
+    Path(token): Path<String>,
+    Extension(pool): Extension<PgPool>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    valid_share(&pool, &token).await?;
+    Ok(HtmlTemplate(ShareTemplate { token }))
+}
+
+/// Public JSON feed of a share link. Live links return the points of the
+/// device since the link was created, or its last known position if there are
+/// none yet.
+pub async fn share_feed(
+    Path(token): Path<String>,
+    Extension(pool): Extension<PgPool>,
+) -> Result<(StatusCode, Json<ShareFeed>), (StatusCode, String)> {
+    let (user_id, share) = valid_share(&pool, &token).await?;
+    let (start, end) = match share.scope {
+        ShareScope::Live => (None, None),
+        ShareScope::Range => (share.start_time, share.end_time),
+    };
+    let zones = PrivacyZones::load(&pool, Some(user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let points = sqlx::query!(
+        r#"SELECT time_id AS "time_id!", coords_x AS "coords_x!", coords_y AS "coords_y!"
+           FROM points WHERE user_identifier=$1 AND deletion IS NULL
+           AND ($2::TEXT IS NULL OR user_id=$2)
+           AND coords_x IS NOT NULL AND coords_y IS NOT NULL
+           AND time_id >= COALESCE($3::TIMESTAMP, (
+               SELECT LEAST($5::TIMESTAMP, MAX(time_id)) FROM points
+               WHERE user_identifier=$1 AND user_id=$2 AND deletion IS NULL))
+           AND time_id < COALESCE($4::TIMESTAMP, $6::TIMESTAMP)
+           ORDER BY time_id LIMIT $7"#,
+        user_id,
+        share.device_id,
+        start,
+        end,
+        share.created_at,
+        share.expires_at,
+        MAX_SHARE_POINTS
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .filter_map(|row| {
+        Some(SharedPoint {
+            time: row.time_id,
+            coordinates: zones.apply(user_id, [row.coords_x, row.coords_y], false)?,
+        })
+    })
+    .collect();
+    Ok((
+        StatusCode::OK,
+        Json(ShareFeed {
+            scope: share.scope,
+            expires_at: share.expires_at,
+            points,
+        }),
+    ))
+}
@@ -0,0 +1,578 @@
+/// Personal data takeout. A zip archive of everything the server holds about a
+/// user is generated in the background and can be downloaded until it
+/// expires. Secrets such as tokens are redacted.
+use crate::api::{parse_motions, serialize_optional_timestamp, serialize_timestamp, BatteryState};
+use crate::auth::CurrentUser;
+use crate::privacy::PrivacyZones;
+use crate::segments::{load_tracks, segment, SegmentationParams};
+use crate::settings::{self, Settings};
+use crate::util::now;
+use axum::extract::Path;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use rand::Rng;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::time::PrimitiveDateTime;
+use std::io::{Seek, Write};
+use time::Duration;
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+/// Version of the layout of the archives.
+const TAKEOUT_FORMAT: u32 = 1;
+
+/// The state of a takeout job.
+#[derive(sqlx::Type, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TAKEOUT_STATUS", rename_all = "lowercase")]
+pub enum TakeoutStatus {
+    /// The archive is being generated.
+    Pending,
+    /// The archive can be downloaded.
+    Ready,
+    /// The generation failed.
+    Failed,
+}
+
+/// A takeout archive requested by a user.
+#[derive(Serialize, Debug)]
+pub struct Takeout {
+    id: i32,
+    status: TakeoutStatus,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    finished_at: Option<PrimitiveDateTime>,
+    #[serde(serialize_with = "serialize_timestamp")]
+    expires_at: PrimitiveDateTime,
+    /// Download link of a ready archive.
+    download: Option<String>,
+}
+
+/// A file of the archive.
+struct ArchiveFile {
+    name: &'static str,
+    description: &'static str,
+    /// Number of records in the file.
+    entries: usize,
+    content: Vec<u8>,
+}
+
+impl ArchiveFile {
+    fn json(name: &'static str, description: &'static str, value: &Value) -> ArchiveFile {
+        ArchiveFile {
+            name,
+            description,
+            entries: value.as_array().map_or(1, Vec::len),
+            content: serde_json::to_vec_pretty(value).unwrap(),
+        }
+    }
+}
+
+/// Runs a query returning rows of a user, given as `$1`, and returns them as
+/// a JSON array.
+async fn rows_json(pool: &PgPool, query: &str, user_id: i32) -> sqlx::Result<Value> {
+    sqlx::query_scalar(&format!(
+        "SELECT COALESCE(json_agg(t), '[]'::json) FROM ({query}) t"
+    ))
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+}
+
+/// Quotes a CSV field when needed.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Loads the points of a user as a GeoJSON feature collection and as CSV. The
+/// privacy zones apply like in the other views of the owner.
+async fn points_files(pool: &PgPool, user_id: i32) -> sqlx::Result<[ArchiveFile; 2]> {
+    let zones = PrivacyZones::load(pool, Some(user_id)).await?;
+    let rows = sqlx::query!(
+        r#"SELECT pt_id, user_id, time_id AS "time_id!", altitude, speed, motion,
+           battery AS "battery: BatteryState", battery_level, wifi,
+           coords_x AS "coords_x!", coords_y AS "coords_y!"
+           FROM points WHERE user_identifier=$1 AND deletion IS NULL AND time_id IS NOT NULL
+           AND coords_x IS NOT NULL AND coords_y IS NOT NULL ORDER BY time_id, pt_id"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut features = vec![];
+    let mut csv = String::from(
+        "id,device_id,timestamp,longitude,latitude,altitude,speed,motion,battery_state,battery_level,wifi\n",
+    );
+    for row in rows {
+        let Some([x, y]) = zones.apply(user_id, [row.coords_x, row.coords_y], true) else {
+            continue;
+        };
+        let timestamp = crate::api::format_timestamp(&row.time_id);
+        let motion = row.motion.as_deref().map(parse_motions).unwrap_or_default();
+        let wifi = row
+            .wifi
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end()
+            .to_string();
+        let properties = json!({
+            "id": row.pt_id,
+            "device_id": row.user_id,
+            "timestamp": timestamp,
+            "altitude": row.altitude,
+            "speed": row.speed,
+            "motion": motion,
+            "battery_state": row.battery,
+            "battery_level": row.battery_level,
+            "wifi": wifi,
+        });
+        let to_field = |value: &Value| match value {
+            Value::Null => String::new(),
+            Value::String(s) => csv_field(s),
+            other => other.to_string(),
+        };
+        let motion = properties["motion"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(" ");
+        csv.push_str(
+            &[
+                row.pt_id.to_string(),
+                csv_field(&row.user_id),
+                timestamp,
+                x.to_string(),
+                y.to_string(),
+                to_field(&properties["altitude"]),
+                to_field(&properties["speed"]),
+                motion,
+                to_field(&properties["battery_state"]),
+                to_field(&properties["battery_level"]),
+                csv_field(&wifi),
+            ]
+            .join(","),
+        );
+        csv.push('\n');
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "Point", "coordinates": [x, y]},
+            "properties": properties,
+        }));
+    }
+    let entries = features.len();
+    Ok([
+        ArchiveFile {
+            name: "points.geojson",
+            description: "All the points, as a GeoJSON feature collection",
+            entries,
+            content: serde_json::to_vec(&json!({
+                "type": "FeatureCollection",
+                "features": features,
+            }))
+            .unwrap(),
+        },
+        ArchiveFile {
+            name: "points.csv",
+            description: "All the points, as CSV",
+            entries,
+            content: csv.into_bytes(),
+        },
+    ])
+}
+
+/// Computes the trips and stays of all the points of a user.
+async fn trips_file(pool: &PgPool, user_id: i32) -> sqlx::Result<ArchiveFile> {
+    let range = sqlx::query!(
+        r#"SELECT MIN(time_id) AS first, MAX(time_id) AS last FROM points
+           WHERE user_identifier=$1 AND deletion IS NULL"#,
+        user_id
+    )
+    .fetch_one(pool)
+    .await?;
+    let segments = match (range.first, range.last) {
+        (Some(first), Some(last)) => {
+            let params = SegmentationParams::default();
+            load_tracks(pool, user_id, first, last + Duration::seconds(1), None)
+                .await?
+                .iter()
+                .flat_map(|(device, track)| segment(device, track, &params))
+                .collect()
+        }
+        _ => vec![],
+    };
+    Ok(ArchiveFile::json(
+        "trips.json",
+        "Trips and stays computed from the points",
+        &serde_json::to_value(segments).unwrap(),
+    ))
+}
+
+/// Collects the content of the archive of a user, except the manifest.
+async fn collect(pool: &PgPool, user_id: i32) -> sqlx::Result<Vec<ArchiveFile>> {
+    let account = rows_json(
+        pool,
+        "SELECT id, username, is_admin, retention_days, retention_mode FROM users WHERE id=$1",
+        user_id,
+    )
+    .await?;
+    let mut files = vec![ArchiveFile::json(
+        "account.json",
+        "Account metadata and settings",
+        &account[0],
+    )];
+    files.extend(points_files(pool, user_id).await?);
+    files.push(trips_file(pool, user_id).await?);
+    let tables: [(&'static str, &'static str, &str); 10] = [
+        (
+            "devices.json",
+            "Devices, with redacted input tokens",
+            "SELECT id, device_id, name, description, color,
+             LEFT(input_token, 4) || '...' AS input_token, created_at, last_seen, retired_at
+             FROM devices WHERE user_identifier=$1 ORDER BY id",
+        ),
+        (
+            "tokens.json",
+            "Input tokens, redacted",
+            "SELECT id, LEFT(input_token, 4) || '...' AS input_token, label, scope,
+             valid, created_at, last_used FROM input_tokens WHERE user_id=$1 ORDER BY id",
+        ),
+        (
+            "daily_summaries.json",
+            "Summaries of the days whose points expired",
+            "SELECT device_id, day, points, first, last, distance
+             FROM daily_summaries WHERE user_identifier=$1 ORDER BY day, device_id",
+        ),
+        (
+            "geofences.json",
+            "Geofences",
+            "SELECT id, name, shape, created FROM geofences WHERE user_identifier=$1 ORDER BY id",
+        ),
+        (
+            "geofence_events.json",
+            "Geofence entries and exits",
+            "SELECT geofence_id, geofence_name, device_id, transition, time_id AS time,
+             coords_x AS longitude, coords_y AS latitude
+             FROM geofence_events WHERE user_identifier=$1 ORDER BY time_id",
+        ),
+        (
+            "privacy_zones.json",
+            "Privacy zones",
+            "SELECT id, name, shape, action, created
+             FROM privacy_zones WHERE user_identifier=$1 ORDER BY id",
+        ),
+        (
+            "webhooks.json",
+            "Webhooks, without their secrets",
+            "SELECT id, url, created FROM webhooks WHERE user_identifier=$1 ORDER BY id",
+        ),
+        (
+            "shares.json",
+            "Share links, with redacted tokens",
+            "SELECT id, LEFT(token, 4) || '...' AS token, scope, device_id, start_time,
+             end_time, created_at, expires_at, revoked
+             FROM shares WHERE user_identifier=$1 ORDER BY id",
+        ),
+        (
+            "grants.json",
+            "Location sharing grants given and received",
+            "SELECT grants.id, owners.username AS owner, grantees.username AS grantee, scope,
+             start_time, end_time, precision FROM grants
+             JOIN users owners ON owners.id=owner_id JOIN users grantees ON grantees.id=grantee_id
+             WHERE owner_id=$1 OR grantee_id=$1 ORDER BY grants.id",
+        ),
+        (
+            "deletions.json",
+            "Pending deletions of points",
+            "SELECT deletions.id, created_at, COUNT(pt_id) AS points FROM deletions
+             LEFT JOIN points ON points.deletion=deletions.id
+             WHERE deletions.user_identifier=$1 GROUP BY deletions.id ORDER BY deletions.id",
+        ),
+    ];
+    for (name, description, query) in tables {
+        let rows = rows_json(pool, query, user_id).await?;
+        files.push(ArchiveFile::json(name, description, &rows));
+    }
+    Ok(files)
+}
+
+/// Writes the archive with a manifest describing its files.
+fn write_archive<W: Write + Seek>(
+    writer: W,
+    user_id: i32,
+    files: &[ArchiveFile],
+) -> zip::result::ZipResult<()> {
+    let mut zip = ZipWriter::new(writer);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let manifest = json!({
+        "format": TAKEOUT_FORMAT,
+        "generated_at": crate::api::format_timestamp(&now()),
+        "user_id": user_id,
+        "files": files.iter().map(|file| json!({
+            "name": file.name,
+            "description": file.description,
+            "entries": file.entries,
+        })).collect::<Vec<_>>(),
+    });
+    zip.start_file("manifest.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(&manifest).unwrap())?;
+    for file in files {
+        zip.start_file(file.name, options)?;
+        zip.write_all(&file.content)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Generates the archive of a user into `path`.
+async fn generate(pool: &PgPool, user_id: i32, path: std::path::PathBuf) -> Result<(), String> {
+    let files = collect(pool, user_id).await.map_err(|e| e.to_string())?;
+    tokio::task::spawn_blocking(move || -> Result<(), String> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| e.to_string())?;
+        }
+        // Write to a temporary file so that a partial archive is never served.
+        let partial = path.with_extension("part");
+        let file = std::fs::File::create(&partial).map_err(|e| e.to_string())?;
+        write_archive(file, user_id, &files).map_err(|e| e.to_string())?;
+        std::fs::rename(&partial, &path).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Background job generating a requested archive.
+async fn run_takeout(pool: PgPool, dir: String, takeout_id: i32, user_id: i32) {
+    let name: String = {
+        let mut rng = rand::thread_rng();
+        (&mut rng)
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect()
+    };
+    let file = format!("takeout-{takeout_id}-{name}.zip");
+    let path = std::path::Path::new(&dir).join(&file);
+    let (status, file, error) = match generate(&pool, user_id, path).await {
+        Ok(()) => (TakeoutStatus::Ready, Some(file), None),
+        Err(e) => {
+            tracing::error!("error generating takeout {takeout_id}: {e}");
+            (TakeoutStatus::Failed, None, Some(e))
+        }
+    };
+    if let Err(e) = sqlx::query!(
+        r#"UPDATE takeouts SET status=$2, file=$3, error=$4, finished_at=$5 WHERE id=$1"#,
+        takeout_id,
+        status as TakeoutStatus,
+        file,
+        error,
+        now()
+    )
+    .execute(&pool)
+    .await
+    {
+        tracing::error!("error updating takeout {takeout_id}: {e}");
+    }
+}
+
+/// Removes the expired archives.
+async fn purge_takeouts(pool: &PgPool, settings: &settings::Takeout) -> sqlx::Result<()> {
+    let expired = sqlx::query!(
+        r#"DELETE FROM takeouts WHERE expires_at <= $1 RETURNING file"#,
+        now()
+    )
+    .fetch_all(pool)
+    .await?;
+    for file in expired.into_iter().filter_map(|row| row.file) {
+        let path = std::path::Path::new(&settings.dir).join(file);
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            tracing::warn!("error removing {}: {e}", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Spawns the hourly removal of the expired archives.
+pub(crate) fn spawn_takeout_cleanup(pool: PgPool, settings: settings::Takeout) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            if let Err(e) = purge_takeouts(&pool, &settings).await {
+                tracing::error!("error removing the expired takeouts: {e}");
+            }
+        }
+    });
+}
+
+/// API method to request a takeout archive of the current user. The archive
+/// is generated in the background.
+pub async fn create_takeout(
+    Extension(pool): Extension<PgPool>,
+    Extension(settings): Extension<settings::Takeout>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Takeout>), (StatusCode, String)> {
+    let created_at = now();
+    let takeout = sqlx::query!(
+        r#"INSERT INTO takeouts (user_identifier, created_at, expires_at)
+           SELECT $1, $2, $3 WHERE NOT EXISTS (
+               SELECT 1 FROM takeouts WHERE user_identifier=$1 AND status='pending')
+           RETURNING id, status AS "status: TakeoutStatus", created_at, finished_at, expires_at"#,
+        current_user.user_id,
+        created_at,
+        created_at + Duration::hours(settings.expires_hours)
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((
+        StatusCode::CONFLICT,
+        "An archive is already being generated".to_string(),
+    ))?;
+    tokio::spawn(run_takeout(
+        pool,
+        settings.dir,
+        takeout.id,
+        current_user.user_id,
+    ));
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(Takeout {
+            id: takeout.id,
+            status: takeout.status,
+            created_at: takeout.created_at,
+            finished_at: takeout.finished_at,
+            expires_at: takeout.expires_at,
+            download: None,
+        }),
+    ))
+}
+
+/// API method to list the takeout archives of the current user.
+pub async fn list_takeouts(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Takeout>>), (StatusCode, String)> {
+    let takeouts = sqlx::query!(
+        r#"SELECT id, status AS "status: TakeoutStatus", created_at, finished_at, expires_at
+           FROM takeouts WHERE user_identifier=$1 AND expires_at > $2 ORDER BY id"#,
+        current_user.user_id,
+        now()
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .into_iter()
+    .map(|row| Takeout {
+        id: row.id,
+        status: row.status,
+        created_at: row.created_at,
+        finished_at: row.finished_at,
+        expires_at: row.expires_at,
+        download: (row.status == TakeoutStatus::Ready)
+            .then(|| format!("/api/takeout/{}/download", row.id)),
+    })
+    .collect();
+    Ok((StatusCode::OK, Json(takeouts)))
+}
+
+/// API method to download a ready takeout archive of the current user.
+pub async fn download_takeout(
+    Path(takeout_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(settings): Extension<settings::Takeout>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let file = sqlx::query!(
+        r#"SELECT file AS "file!" FROM takeouts
+           WHERE id=$1 AND user_identifier=$2 AND status='ready' AND expires_at > $3"#,
+        takeout_id,
+        current_user.user_id,
+        now()
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .ok_or((StatusCode::NOT_FOUND, "No such archive".to_string()))?
+    .file;
+    let content = tokio::fs::read(std::path::Path::new(&settings.dir).join(&file))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"takeout-{takeout_id}.zip\""),
+            ),
+        ],
+        content,
+    ))
+}
+
+/// Writes the takeout archive of a user from the command line.
+pub async fn export_takeout(username: &str, output: Option<&str>) -> Result<(), sqlx::Error> {
+    let settings = Settings::new().unwrap();
+
+    let pool = PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.");
+
+    let user_id = sqlx::query!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_one(&pool)
+        .await?
+        .id;
+    let output = output
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{username}-takeout.zip"));
+    generate(&pool, user_id, output.clone().into())
+        .await
+        .expect("Cannot write the archive.");
+    println!("Archive written to {output}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn should_quote_csv_fields() {
+        assert_eq!(csv_field("phone"), "phone");
+        assert_eq!(csv_field("my, phone"), "\"my, phone\"");
+        assert_eq!(csv_field("the \"phone\""), "\"the \"\"phone\"\"\"");
+    }
+
+    #[test]
+    fn should_write_archives_with_a_manifest() {
+        let files = [ArchiveFile::json(
+            "devices.json",
+            "Devices",
+            &json!([{"id": 1}, {"id": 2}]),
+        )];
+        let mut buffer = Cursor::new(vec![]);
+        write_archive(&mut buffer, 7, &files).unwrap();
+        let mut archive = zip::ZipArchive::new(buffer).unwrap();
+        assert_eq!(archive.len(), 2);
+        let mut manifest = String::new();
+        archive
+            .by_name("manifest.json")
+            .unwrap()
+            .read_to_string(&mut manifest)
+            .unwrap();
+        let manifest: Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["user_id"], 7);
+        assert_eq!(manifest["files"][0]["entries"], 2);
+    }
+}
@@ -0,0 +1,280 @@
+/// Management of the input tokens of the users. Input tokens authenticate the
+/// Overland app and scripts; they can be labelled, rotated and revoked, and
+/// their scope restricts them to sending points or reading data.
+use crate::api::{serialize_optional_timestamp, serialize_timestamp};
+use crate::auth::{CurrentUser, Scope};
+use crate::settings::Settings;
+use crate::util::now;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::time::PrimitiveDateTime;
+
+const INPUT_TOKEN_LEN: usize = 64;
+
+/// An input token of a user. Unlike device tokens, they are always shown to
+/// their owner, who needs them to configure the Overland app.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct InputToken {
+    id: i32,
+    input_token: String,
+    label: Option<String>,
+    scope: Scope,
+    /// Revoked tokens are kept, but rejected.
+    valid: bool,
+    #[serde(serialize_with = "serialize_timestamp")]
+    created_at: PrimitiveDateTime,
+    #[serde(serialize_with = "serialize_optional_timestamp")]
+    last_used: Option<PrimitiveDateTime>,
+}
+
+/// The body of an input token update request.
+#[derive(Deserialize, Debug)]
+pub struct TokenLabel {
+    label: Option<String>,
+}
+
+/// The body of an input token creation request. Tokens can only send points
+/// unless another scope is asked for.
+#[derive(Deserialize, Debug)]
+pub struct NewToken {
+    label: Option<String>,
+    scope: Option<Scope>,
+}
+
+fn random_token() -> String {
+    let mut rng = rand::thread_rng();
+    (&mut rng)
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(INPUT_TOKEN_LEN)
+        .map(char::from)
+        .collect()
+}
+
+async fn load_tokens(pool: &PgPool, user_id: i32) -> sqlx::Result<Vec<InputToken>> {
+    sqlx::query_as!(
+        InputToken,
+        r#"SELECT id, input_token, label, scope AS "scope: Scope", valid, created_at,
+           last_used FROM input_tokens WHERE user_id=$1 ORDER BY id"#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Creates a token for a user. It is also called on user creation.
+pub(crate) async fn insert_token(
+    pool: &PgPool,
+    user_id: i32,
+    label: Option<String>,
+    scope: Scope,
+) -> sqlx::Result<InputToken> {
+    sqlx::query_as!(
+        InputToken,
+        r#"INSERT INTO input_tokens (input_token, user_id, label, scope)
+           VALUES ( $1, $2, $3, $4 )
+           RETURNING id, input_token, label, scope AS "scope: Scope", valid, created_at,
+           last_used"#,
+        random_token(),
+        user_id,
+        label,
+        scope as Scope
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// Replaces a token by a new one with the same label and scope. A revoked token becomes
+/// valid again. `user_id` restricts the change to the tokens of a user.
+async fn rotate(pool: &PgPool, id: i32, user_id: Option<i32>) -> sqlx::Result<Option<InputToken>> {
+    sqlx::query_as!(
+        InputToken,
+        r#"UPDATE input_tokens SET input_token=$3, valid=true, created_at=$4, last_used=NULL
+           WHERE id=$1 AND ($2::INT IS NULL OR user_id=$2)
+           RETURNING id, input_token, label, scope AS "scope: Scope", valid, created_at,
+           last_used"#,
+        id,
+        user_id,
+        random_token(),
+        now()
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Revokes a token. `user_id` restricts the change to the tokens of a user.
+/// Returns `false` if there is no such token.
+async fn revoke(pool: &PgPool, id: i32, user_id: Option<i32>) -> sqlx::Result<bool> {
+    let res = sqlx::query!(
+        r#"UPDATE input_tokens SET valid=false
+           WHERE id=$1 AND ($2::INT IS NULL OR user_id=$2)"#,
+        id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+/// API method to list the input tokens of the current user. The tokens are
+/// shown in full, so a read token cannot list them.
+pub async fn list_tokens(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<InputToken>>), (StatusCode, String)> {
+    if current_user.scope != Scope::Admin {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Listing tokens needs the admin scope".to_string(),
+        ));
+    }
+    let tokens = load_tokens(&pool, current_user.user_id)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+/// API method to create an input token for the current user.
+pub async fn create_token(
+    Json(new_token): Json<NewToken>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<InputToken>), (StatusCode, String)> {
+    let token = insert_token(
+        &pool,
+        current_user.user_id,
+        new_token.label,
+        new_token.scope.unwrap_or(Scope::Ingest),
+    )
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(token)))
+}
+
+/// API method to change the label of an input token of the current user.
+pub async fn update_token(
+    Path(id): Path<i32>,
+    Json(update): Json<TokenLabel>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<InputToken>), (StatusCode, String)> {
+    sqlx::query_as!(
+        InputToken,
+        r#"UPDATE input_tokens SET label=$3 WHERE id=$1 AND user_id=$2
+           RETURNING id, input_token, label, scope AS "scope: Scope", valid, created_at,
+           last_used"#,
+        id,
+        current_user.user_id,
+        update.label
+    )
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+    .map(|token| (StatusCode::OK, Json(token)))
+    .ok_or((StatusCode::NOT_FOUND, "No such token".to_string()))
+}
+
+/// API method to replace an input token of the current user by a new one. The
+/// previous token stops working immediately.
+pub async fn rotate_token(
+    Path(id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<InputToken>), (StatusCode, String)> {
+    rotate(&pool, id, Some(current_user.user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map(|token| (StatusCode::OK, Json(token)))
+        .ok_or((StatusCode::NOT_FOUND, "No such token".to_string()))
+}
+
+/// API method to revoke an input token of the current user.
+pub async fn revoke_token(
+    Path(id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let revoked = revoke(&pool, id, Some(current_user.user_id))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if revoked {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "No such token".to_string()))
+    }
+}
+
+async fn connect() -> PgPool {
+    let settings = Settings::new().unwrap();
+
+    PgPoolOptions::new()
+        .max_connections(settings.database.max_connections)
+        .connect(&settings.database.url)
+        .await
+        .expect("Cannot connect to postgres database.")
+}
+
+fn print_token(token: &InputToken) {
+    println!(
+        "{}\t{}\t{:?}\t{}\t{}\t{}",
+        token.id,
+        token.input_token,
+        token.scope,
+        if token.valid { "valid" } else { "revoked" },
+        token.label.as_deref().unwrap_or("-"),
+        token
+            .last_used
+            .map_or("never used".to_string(), |last_used| last_used.to_string())
+    );
+}
+
+async fn user_id(pool: &PgPool, username: &str) -> sqlx::Result<i32> {
+    sqlx::query_scalar!(r#"SELECT id FROM users WHERE username=$1"#, username)
+        .fetch_one(pool)
+        .await
+}
+
+/// Prints the input tokens of a user.
+pub async fn list_input_tokens(username: &str) -> Result<(), sqlx::Error> {
+    let pool = connect().await;
+    for token in load_tokens(&pool, user_id(&pool, username).await?).await? {
+        print_token(&token);
+    }
+    Ok(())
+}
+
+/// Creates an input token for a user and prints it.
+pub async fn add_input_token(
+    username: &str,
+    label: Option<&str>,
+    scope: Scope,
+) -> Result<(), sqlx::Error> {
+    let pool = connect().await;
+    let user_id = user_id(&pool, username).await?;
+    print_token(&insert_token(&pool, user_id, label.map(str::to_string), scope).await?);
+    Ok(())
+}
+
+/// Replaces an input token by a new one and prints it.
+pub async fn rotate_input_token(id: i32) -> Result<(), sqlx::Error> {
+    let pool = connect().await;
+    match rotate(&pool, id, None).await? {
+        Some(token) => print_token(&token),
+        None => println!("No such token"),
+    }
+    Ok(())
+}
+
+/// Revokes an input token.
+pub async fn revoke_input_token(id: i32) -> Result<(), sqlx::Error> {
+    let pool = connect().await;
+    if revoke(&pool, id, None).await? {
+        println!("Token {id} revoked");
+    } else {
+        println!("No such token");
+    }
+    Ok(())
+}
@@ -0,0 +1,29 @@
+/// Small helpers shared by the modules.
+use sha2::{Digest, Sha256};
+use sqlx::types::time::PrimitiveDateTime;
+use time::OffsetDateTime;
+
+/// The current UTC time, as stored in the database.
+pub(crate) fn now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_utc();
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+/// Only a hash of the session cookies and secret tokens is stored, so that a
+/// leak of the database does not leak usable credentials.
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_hash_tokens() {
+        let hash = hash_token("cookie");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, hash_token("cookie"));
+        assert_ne!(hash, hash_token("cookie2"));
+    }
+}
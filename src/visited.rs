@@ -0,0 +1,479 @@
+/// Countries and regions visited by a user. Points are matched against local
+/// boundary polygons, and the result is cached per day in the database since
+/// past days rarely change.
+use crate::api::parse_datetime;
+use crate::auth::CurrentUser;
+use crate::geo::point_in_polygon;
+use crate::settings::Boundaries as BoundariesSettings;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::postgres::PgPool;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::sync::Arc;
+use time::macros::format_description;
+use time::{Date, OffsetDateTime, Time};
+
+/// Properties holding the name of a country, in order of preference. These
+/// cover the Natural Earth datasets and most other GeoJSON exports.
+const COUNTRY_NAME_KEYS: &[&str] = &["ADMIN", "admin", "NAME", "name"];
+/// Properties holding the name of a region, in order of preference.
+const REGION_NAME_KEYS: &[&str] = &["name", "NAME", "name_en"];
+/// Points are rounded to this many decimals (about 100m) before being matched,
+/// so that the many points recorded in the same place are only tested once.
+const COORDS_DECIMALS: i32 = 3;
+
+/// The boundaries shared between requests. `None` when no boundaries are
+/// configured.
+pub type SharedBoundaries = Option<Arc<Boundaries>>;
+
+/// A named area made of one or several polygons. The first ring of each
+/// polygon is its exterior, the other ones are holes.
+struct Area {
+    name: String,
+    bbox: [f64; 4],
+    polygons: Vec<Vec<Vec<[f64; 2]>>>,
+}
+
+impl Area {
+    fn contains(&self, point: [f64; 2]) -> bool {
+        let [min_x, min_y, max_x, max_y] = self.bbox;
+        if point[0] < min_x || point[0] > max_x || point[1] < min_y || point[1] > max_y {
+            return false;
+        }
+        self.polygons.iter().any(|rings| match rings.split_first() {
+            Some((exterior, holes)) => {
+                point_in_polygon(point, exterior)
+                    && !holes.iter().any(|hole| point_in_polygon(point, hole))
+            }
+            None => false,
+        })
+    }
+}
+
+/// Country and region boundaries loaded in memory.
+pub struct Boundaries {
+    countries: Vec<Area>,
+    regions: Vec<Area>,
+}
+
+/// A country or region visited by the user.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VisitedPlace {
+    /// Name of the country or region.
+    name: String,
+    /// Country of a region.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    country: Option<String>,
+    /// First day with a point in the place.
+    first_visit: String,
+    /// Last day with a point in the place.
+    last_visit: String,
+    /// Number of days with at least one point in the place.
+    days: usize,
+}
+
+/// The visited places of a time range.
+#[derive(Serialize, Debug)]
+pub struct VisitedReport {
+    /// Visited countries.
+    countries: Vec<VisitedPlace>,
+    /// Visited regions, empty when no region boundaries are configured.
+    regions: Vec<VisitedPlace>,
+}
+
+/// Query parameters of the visited places API.
+#[derive(Deserialize, Debug)]
+pub struct VisitedQuery {
+    /// Start of the time range.
+    start: String,
+    /// End of the time range.
+    end: String,
+}
+
+fn invalid_data<E: ToString>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn parse_polygon(value: &Value) -> io::Result<Vec<Vec<[f64; 2]>>> {
+    // Positions may have a third altitude coordinate which is ignored.
+    let rings: Vec<Vec<Vec<f64>>> = serde_json::from_value(value.clone()).map_err(invalid_data)?;
+    rings
+        .into_iter()
+        .map(|ring| {
+            ring.into_iter()
+                .map(|pos| match pos[..] {
+                    [lon, lat, ..] => Ok([lon, lat]),
+                    _ => Err(invalid_data("Invalid position")),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Reads the named polygons of a GeoJSON feature collection. Features without
+/// a name or a (multi)polygon geometry are skipped.
+fn parse_areas<R: Read>(reader: R, name_keys: &[&str]) -> io::Result<Vec<Area>> {
+    let collection: Value = serde_json::from_reader(reader).map_err(invalid_data)?;
+    let features = collection["features"]
+        .as_array()
+        .ok_or_else(|| invalid_data("Not a GeoJSON feature collection"))?;
+    let mut areas = vec![];
+    for feature in features {
+        let Some(name) = name_keys
+            .iter()
+            .find_map(|key| feature["properties"][key].as_str())
+        else {
+            continue;
+        };
+        let geometry = &feature["geometry"];
+        let polygons = match geometry["type"].as_str() {
+            Some("Polygon") => vec![parse_polygon(&geometry["coordinates"])?],
+            Some("MultiPolygon") => geometry["coordinates"]
+                .as_array()
+                .ok_or_else(|| invalid_data("Invalid MultiPolygon"))?
+                .iter()
+                .map(parse_polygon)
+                .collect::<io::Result<_>>()?,
+            _ => continue,
+        };
+        let mut bbox = [f64::MAX, f64::MAX, f64::MIN, f64::MIN];
+        for point in polygons.iter().filter_map(|rings| rings.first()).flatten() {
+            bbox = [
+                bbox[0].min(point[0]),
+                bbox[1].min(point[1]),
+                bbox[2].max(point[0]),
+                bbox[3].max(point[1]),
+            ];
+        }
+        areas.push(Area {
+            name: name.to_string(),
+            bbox,
+            polygons,
+        });
+    }
+    Ok(areas)
+}
+
+impl Boundaries {
+    /// Loads the boundary files listed in the settings.
+    pub fn load(settings: &BoundariesSettings) -> io::Result<Boundaries> {
+        let open = |path: &String| File::open(path).map(BufReader::new);
+        let boundaries = Boundaries::from_readers(
+            open(&settings.countries_path)?,
+            settings.regions_path.as_ref().map(open).transpose()?,
+        )?;
+        tracing::info!(
+            "loaded {} countries and {} regions",
+            boundaries.countries.len(),
+            boundaries.regions.len()
+        );
+        Ok(boundaries)
+    }
+
+    /// Builds the boundaries from GeoJSON feature collections of countries
+    /// and optionally regions.
+    pub fn from_readers<R: Read>(countries: R, regions: Option<R>) -> io::Result<Boundaries> {
+        Ok(Boundaries {
+            countries: parse_areas(countries, COUNTRY_NAME_KEYS)?,
+            regions: regions
+                .map(|r| parse_areas(r, REGION_NAME_KEYS))
+                .transpose()?
+                .unwrap_or_default(),
+        })
+    }
+
+    /// Finds the country and region containing a `[longitude, latitude]`
+    /// point.
+    pub fn locate(&self, point: [f64; 2]) -> (Option<&str>, Option<&str>) {
+        fn find(areas: &[Area], point: [f64; 2]) -> Option<&str> {
+            areas
+                .iter()
+                .find(|area| area.contains(point))
+                .map(|area| area.name.as_str())
+        }
+        match find(&self.countries, point) {
+            Some(country) => (Some(country), find(&self.regions, point)),
+            None => (None, None),
+        }
+    }
+}
+
+/// The `(country, region)` pairs visited on each day. Country entries have an
+/// empty region.
+type DayPlaces = HashMap<Date, HashSet<(String, String)>>;
+
+fn locate_all(boundaries: &Boundaries, points: Vec<(Date, [f64; 2])>) -> DayPlaces {
+    let mut places = DayPlaces::new();
+    for (day, point) in points {
+        let day_places = places.entry(day).or_default();
+        if let (Some(country), region) = boundaries.locate(point) {
+            day_places.insert((country.to_string(), String::new()));
+            if let Some(region) = region {
+                day_places.insert((country.to_string(), region.to_string()));
+            }
+        }
+    }
+    places
+}
+
+fn format_date(date: Date) -> String {
+    date.format(format_description!("[year]-[month]-[day]"))
+        .unwrap_or_default()
+}
+
+/// Aggregates the places visited each day into a report sorted by first visit.
+fn report<I: IntoIterator<Item = (Date, String, String)>>(rows: I) -> VisitedReport {
+    let mut visits: BTreeMap<(String, String), (Date, Date, usize)> = BTreeMap::new();
+    for (day, country, region) in rows {
+        let visit = visits.entry((country, region)).or_insert((day, day, 0));
+        visit.0 = visit.0.min(day);
+        visit.1 = visit.1.max(day);
+        visit.2 += 1;
+    }
+    let (mut countries, mut regions) = (vec![], vec![]);
+    for ((country, region), (first, last, days)) in visits {
+        let (name, country, list) = if region.is_empty() {
+            (country, None, &mut countries)
+        } else {
+            (region, Some(country), &mut regions)
+        };
+        list.push(VisitedPlace {
+            name,
+            country,
+            first_visit: format_date(first),
+            last_visit: format_date(last),
+            days,
+        });
+    }
+    for list in [&mut countries, &mut regions] {
+        list.sort_by(|a, b| (&a.first_visit, &a.name).cmp(&(&b.first_visit, &b.name)));
+    }
+    VisitedReport { countries, regions }
+}
+
+/// Forgets the cached places of some days of a user, e.g. because points were
+/// added to them.
+pub(crate) async fn invalidate_days(
+    pool: &PgPool,
+    user_id: i32,
+    days: &[Date],
+) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!(
+        r#"DELETE FROM visited_cache WHERE user_identifier=$1 AND day = ANY($2)"#,
+        user_id,
+        days
+    )
+    .execute(&mut tx)
+    .await?;
+    sqlx::query!(
+        r#"DELETE FROM visited_days WHERE user_identifier=$1 AND day = ANY($2)"#,
+        user_id,
+        days
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Stores the places of past days. The current day is never cached since new
+/// points are still coming.
+async fn cache_days(
+    pool: &PgPool,
+    user_id: i32,
+    places: &DayPlaces,
+    today: Date,
+) -> sqlx::Result<()> {
+    let mut tx = pool.begin().await?;
+    for (day, day_places) in places.iter().filter(|(day, _)| **day < today) {
+        for (country, region) in day_places {
+            sqlx::query!(
+                r#"INSERT INTO visited_days (user_identifier, day, country, region)
+                   VALUES ( $1, $2, $3, $4 ) ON CONFLICT DO NOTHING"#,
+                user_id,
+                day,
+                country,
+                region
+            )
+            .execute(&mut tx)
+            .await?;
+        }
+        sqlx::query!(
+            r#"INSERT INTO visited_cache (user_identifier, day) VALUES ( $1, $2 )
+               ON CONFLICT DO NOTHING"#,
+            user_id,
+            day
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await
+}
+
+/// API method to get the countries and regions visited by the current user in
+/// a time range. The range is extended to whole days.
+pub async fn visited(
+    Query(query): Query<VisitedQuery>,
+    Extension(pool): Extension<PgPool>,
+    Extension(boundaries): Extension<SharedBoundaries>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<VisitedReport>), (StatusCode, String)> {
+    let boundaries = boundaries.ok_or((
+        StatusCode::NOT_IMPLEMENTED,
+        "Country and region boundaries are not configured".to_string(),
+    ))?;
+    let parse = |date: &str| {
+        parse_datetime(date).ok_or((StatusCode::BAD_REQUEST, format!("Invalid date {date}")))
+    };
+    let (start, end) = (parse(&query.start)?, parse(&query.end)?);
+    let internal_error = |e: sqlx::Error| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    let first_day = start.date();
+    let end_day = if end.time() == Time::MIDNIGHT {
+        end.date()
+    } else {
+        end.date().next_day().unwrap_or(end.date())
+    };
+    let user_id = current_user.user_id;
+
+    let cached_days: Vec<Date> = sqlx::query_scalar!(
+        r#"SELECT day FROM visited_cache WHERE user_identifier=$1 AND day >= $2 AND day < $3"#,
+        user_id,
+        first_day,
+        end_day
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?;
+
+    let points: Vec<(Date, [f64; 2])> = sqlx::query!(
+        r#"SELECT DATE(time_id) AS "day!",
+           ROUND(coords_x::NUMERIC, $5)::FLOAT8 AS "lon!",
+           ROUND(coords_y::NUMERIC, $5)::FLOAT8 AS "lat!"
+           FROM points WHERE user_identifier=$1 AND time_id >= $2 AND time_id < $3
+           AND deletion IS NULL AND coords_x IS NOT NULL AND coords_y IS NOT NULL
+           AND NOT (DATE(time_id) = ANY($4))
+           GROUP BY 1, 2, 3"#,
+        user_id,
+        first_day.midnight(),
+        end_day.midnight(),
+        &cached_days,
+        COORDS_DECIMALS
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .map(|row| (row.day, [row.lon, row.lat]))
+    .collect();
+
+    let places = tokio::task::spawn_blocking(move || locate_all(&boundaries, points))
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let today = OffsetDateTime::now_utc().date();
+    cache_days(&pool, user_id, &places, today)
+        .await
+        .map_err(internal_error)?;
+
+    let cached = sqlx::query!(
+        r#"SELECT day, country, region FROM visited_days
+           WHERE user_identifier=$1 AND day >= $2 AND day < $3"#,
+        user_id,
+        first_day,
+        end_day
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(internal_error)?
+    .into_iter()
+    .map(|row| (row.day, row.country, row.region));
+    let uncached = places
+        .into_iter()
+        .filter(|(day, _)| *day >= today)
+        .flat_map(|(day, day_places)| {
+            day_places
+                .into_iter()
+                .map(move |(country, region)| (day, country, region))
+        });
+    Ok((StatusCode::OK, Json(report(cached.chain(uncached)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::date;
+
+    const COUNTRIES: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"ADMIN": "Squareland"},
+         "geometry": {"type": "Polygon", "coordinates": [
+            [[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]],
+            [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]]}},
+        {"type": "Feature", "properties": {"ADMIN": "Islands"},
+         "geometry": {"type": "MultiPolygon", "coordinates": [
+            [[[20, 0, 5], [21, 0, 5], [21, 1, 5], [20, 1, 5], [20, 0, 5]]],
+            [[[30, 0], [31, 0], [31, 1], [30, 1], [30, 0]]]]}},
+        {"type": "Feature", "properties": {},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1]]]}}
+    ]}"#;
+
+    const REGIONS: &str = r#"{"type": "FeatureCollection", "features": [
+        {"type": "Feature", "properties": {"name": "West"},
+         "geometry": {"type": "Polygon", "coordinates": [[[0, 0], [5, 0], [5, 10], [0, 10]]]}}
+    ]}"#;
+
+    #[test]
+    fn should_locate_points() {
+        let boundaries =
+            Boundaries::from_readers(COUNTRIES.as_bytes(), Some(REGIONS.as_bytes())).unwrap();
+        assert_eq!(boundaries.countries.len(), 2);
+        assert_eq!(
+            boundaries.locate([1., 1.]),
+            (Some("Squareland"), Some("West"))
+        );
+        assert_eq!(boundaries.locate([8., 1.]), (Some("Squareland"), None));
+        // Points in holes are not in the area.
+        assert_eq!(boundaries.locate([5., 5.]), (None, None));
+        assert_eq!(boundaries.locate([30.5, 0.5]), (Some("Islands"), None));
+        assert_eq!(boundaries.locate([25., 0.5]), (None, None));
+    }
+
+    #[test]
+    fn should_aggregate_visits() {
+        let rows = vec![
+            (date!(2022 - 05 - 03), "France".to_string(), String::new()),
+            (date!(2022 - 05 - 01), "France".to_string(), String::new()),
+            (
+                date!(2022 - 05 - 01),
+                "France".to_string(),
+                "Bretagne".to_string(),
+            ),
+            (date!(2022 - 05 - 02), "Belgium".to_string(), String::new()),
+        ];
+        let report = report(rows);
+        assert_eq!(
+            report.countries,
+            vec![
+                VisitedPlace {
+                    name: "France".to_string(),
+                    country: None,
+                    first_visit: "2022-05-01".to_string(),
+                    last_visit: "2022-05-03".to_string(),
+                    days: 2,
+                },
+                VisitedPlace {
+                    name: "Belgium".to_string(),
+                    country: None,
+                    first_visit: "2022-05-02".to_string(),
+                    last_visit: "2022-05-02".to_string(),
+                    days: 1,
+                },
+            ]
+        );
+        assert_eq!(report.regions[0].country, Some("France".to_string()));
+        assert_eq!(report.regions[0].days, 1);
+    }
+}
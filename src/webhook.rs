@@ -0,0 +1,485 @@
+/// User configured webhooks. Events are POST-ed as JSON to every webhook of the
+/// user, signed with a per-webhook secret.
+use crate::auth::CurrentUser;
+use crate::settings::Webhooks as WebhookSettings;
+use axum::extract::Path;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlx::postgres::PgPool;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+const WEBHOOK_SECRET_LEN: usize = 32;
+/// The longest delay between two delivery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+/// Header containing the hex encoded HMAC-SHA256 signature of the request body,
+/// prefixed with `sha256=`.
+pub const SIGNATURE_HEADER: &str = "X-Roverland-Signature";
+/// Header containing the type of the delivered event.
+pub const EVENT_HEADER: &str = "X-Roverland-Event";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A webhook registered by a user.
+#[derive(Serialize, sqlx::FromRow, Debug)]
+pub struct Webhook {
+    id: i32,
+    url: String,
+    secret: String,
+}
+
+/// The body of a webhook creation request.
+#[derive(Deserialize, Debug)]
+pub struct NewWebhook {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct Delivery<'a, T: Serialize> {
+    event: &'a str,
+    data: &'a T,
+}
+
+/// Computes the signature of a webhook body with the webhook secret.
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Whether an address can be reached from the internet. The other ones, like
+/// the server itself, its local network or the metadata service of a cloud
+/// host, are refused unless the settings allow them.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || ip.is_documentation()
+                || a == 0
+                // Shared address space of the carrier-grade NATs.
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link-local addresses.
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// The address of a url given as an IP address rather than a host name.
+fn literal_ip(url: &reqwest::Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .ok()
+}
+
+/// Resolves the webhook hosts to their public addresses only, so that a host
+/// name cannot point the deliveries to an internal address.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: hyper::client::connect::dns::Name) -> reqwest::dns::Resolving {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", name.as_str()).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Checks the url of a webhook: it must be HTTP or HTTPS and, unless private
+/// targets are allowed, its host must only resolve to public addresses.
+async fn check_url(url: &str, allow_private: bool) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err("only http and https are supported".to_string());
+    }
+    let Some(host) = url.host_str() else {
+        return Err("missing host".to_string());
+    };
+    if allow_private {
+        return Ok(());
+    }
+    let addrs: Vec<IpAddr> = match literal_ip(&url) {
+        Some(ip) => vec![ip],
+        None => tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| format!("cannot resolve {host}: {e}"))?
+            .map(|addr| addr.ip())
+            .collect(),
+    };
+    if !addrs.into_iter().all(is_public) {
+        return Err("private addresses are not allowed".to_string());
+    }
+    Ok(())
+}
+
+/// The HTTP client of the deliveries. Redirects are not followed, they could
+/// lead to an internal address.
+fn build_client(settings: &WebhookSettings) -> reqwest::Client {
+    let mut client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(settings.timeout_secs))
+        .redirect(reqwest::redirect::Policy::none());
+    if !settings.allow_private_targets {
+        client = client.dns_resolver(Arc::new(PublicResolver));
+    }
+    client
+        .build()
+        .expect("Cannot build the webhook HTTP client.")
+}
+
+/// Sends webhook events in the background with retries and exponential
+/// backoff.
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    pool: PgPool,
+    settings: WebhookSettings,
+}
+
+impl WebhookSender {
+    /// Creates a new sender from a database pool and the webhook settings.
+    pub fn new(pool: &PgPool, settings: &WebhookSettings) -> WebhookSender {
+        WebhookSender {
+            client: build_client(settings),
+            pool: pool.clone(),
+            settings: settings.clone(),
+        }
+    }
+
+    /// Queues an event for delivery to all the webhooks of a user. This
+    /// returns immediately, the deliveries happen in a background task.
+    pub fn dispatch<T: Serialize>(&self, user_id: i32, event: &str, data: &T) {
+        let body = match serde_json::to_string(&Delivery { event, data }) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("cannot serialize webhook event: {e}");
+                return;
+            }
+        };
+        let sender = self.clone();
+        let event = event.to_string();
+        tokio::spawn(async move {
+            let webhooks = match sqlx::query_as!(
+                Webhook,
+                r#"SELECT id, url, secret FROM webhooks WHERE user_identifier=$1"#,
+                user_id
+            )
+            .fetch_all(&sender.pool)
+            .await
+            {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!("cannot load webhooks: {e}");
+                    return;
+                }
+            };
+            for webhook in webhooks {
+                let (client, settings) = (sender.client.clone(), sender.settings.clone());
+                let (event, body) = (event.clone(), body.clone());
+                tokio::spawn(async move {
+                    deliver_with_retries(&client, &settings, &webhook, &event, &body).await
+                });
+            }
+        });
+    }
+}
+
+async fn deliver(
+    client: &reqwest::Client,
+    settings: &WebhookSettings,
+    webhook: &Webhook,
+    event: &str,
+    body: &str,
+) -> Result<(), String> {
+    // The host names are checked by the resolver of the client.
+    let ip = reqwest::Url::parse(&webhook.url)
+        .ok()
+        .and_then(|url| literal_ip(&url));
+    if !settings.allow_private_targets && ip.is_some_and(|ip| !is_public(ip)) {
+        return Err("private addresses are not allowed".to_string());
+    }
+    let response = client
+        .post(&webhook.url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(EVENT_HEADER, event)
+        .header(SIGNATURE_HEADER, sign(&webhook.secret, body.as_bytes()))
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("receiver answered {}", response.status()))
+    }
+}
+
+async fn deliver_with_retries(
+    client: &reqwest::Client,
+    settings: &WebhookSettings,
+    webhook: &Webhook,
+    event: &str,
+    body: &str,
+) -> bool {
+    let mut backoff = Duration::from_secs(settings.initial_backoff_secs);
+    for attempt in 1..=settings.max_attempts {
+        match deliver(client, settings, webhook, event, body).await {
+            Ok(()) => {
+                tracing::debug!("delivered {event} to webhook {}", webhook.id);
+                return true;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "delivery of {event} to webhook {} failed (attempt {attempt}): {e}",
+                    webhook.id
+                );
+                if attempt < settings.max_attempts {
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+    tracing::error!("giving up delivering {event} to webhook {}", webhook.id);
+    false
+}
+
+/// API method to list the webhooks of the current user.
+pub async fn list_webhooks(
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Vec<Webhook>>), (StatusCode, String)> {
+    let webhooks = sqlx::query_as!(
+        Webhook,
+        r#"SELECT id, url, secret FROM webhooks WHERE user_identifier=$1 ORDER BY id"#,
+        current_user.user_id
+    )
+    .fetch_all(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::OK, Json(webhooks)))
+}
+
+/// API method to register a new webhook. The response contains the secret used
+/// to sign the deliveries.
+pub async fn create_webhook(
+    Json(new_webhook): Json<NewWebhook>,
+    Extension(pool): Extension<PgPool>,
+    Extension(sender): Extension<WebhookSender>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<(StatusCode, Json<Webhook>), (StatusCode, String)> {
+    if let Err(e) = check_url(&new_webhook.url, sender.settings.allow_private_targets).await {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid webhook url {}: {e}", new_webhook.url),
+        ));
+    }
+    let secret: String = {
+        let mut rng = rand::thread_rng();
+        (&mut rng)
+            .sample_iter(rand::distributions::Alphanumeric)
+            .take(WEBHOOK_SECRET_LEN)
+            .map(char::from)
+            .collect()
+    };
+    let webhook = sqlx::query_as!(
+        Webhook,
+        r#"INSERT INTO webhooks (user_identifier, url, secret) VALUES ( $1, $2, $3 )
+           RETURNING id, url, secret"#,
+        current_user.user_id,
+        new_webhook.url,
+        secret
+    )
+    .fetch_one(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(webhook)))
+}
+
+/// API method to remove a webhook of the current user.
+pub async fn delete_webhook(
+    Path(webhook_id): Path<i32>,
+    Extension(pool): Extension<PgPool>,
+    Extension(current_user): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let res = sqlx::query!(
+        r#"DELETE FROM webhooks WHERE id=$1 AND user_identifier=$2"#,
+        webhook_id,
+        current_user.user_id
+    )
+    .execute(&pool)
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    if res.rows_affected() == 0 {
+        Err((StatusCode::NOT_FOUND, "No such webhook".to_string()))
+    } else {
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::HeaderMap, routing::post, Router};
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Starts a local receiver failing the first `failures` requests. It
+    /// returns its address and the number of received requests.
+    fn spawn_receiver(failures: usize, secret: &'static str) -> (SocketAddr, Arc<AtomicUsize>) {
+        let count = Arc::new(AtomicUsize::new(0));
+        let count_clone = count.clone();
+        let router = Router::new().route(
+            "/hook",
+            post(move |headers: HeaderMap, body: String| {
+                let count = count_clone.clone();
+                async move {
+                    let signature = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap();
+                    assert_eq!(signature, sign(secret, body.as_bytes()));
+                    assert_eq!(headers.get(EVENT_HEADER).unwrap(), "geofence.enter");
+                    if count.fetch_add(1, Ordering::SeqCst) < failures {
+                        StatusCode::INTERNAL_SERVER_ERROR
+                    } else {
+                        StatusCode::OK
+                    }
+                }
+            }),
+        );
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(router.into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, count)
+    }
+
+    #[test]
+    fn should_sign_body() {
+        // Reference value from RFC 4231, test case 2.
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn should_refuse_private_targets() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.10",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        assert!(is_public("93.184.216.34".parse().unwrap()));
+        assert!(is_public("2606:2800:220:1::".parse().unwrap()));
+        assert!(check_url("http://127.0.0.1:18032/hook", false)
+            .await
+            .is_err());
+        assert!(check_url("http://[::1]/hook", false).await.is_err());
+        assert!(check_url("http://localhost/hook", false).await.is_err());
+        assert!(check_url("ftp://93.184.216.34/hook", false).await.is_err());
+        assert!(check_url("https://93.184.216.34/hook", false).await.is_ok());
+        assert!(check_url("http://127.0.0.1:18032/hook", true).await.is_ok());
+
+        // Webhooks stored before the check are not delivered either.
+        let (addr, count) = spawn_receiver(0, "secret");
+        let settings = WebhookSettings {
+            max_attempts: 1,
+            initial_backoff_secs: 0,
+            timeout_secs: 5,
+            allow_private_targets: false,
+        };
+        let client = build_client(&settings);
+        for url in [
+            format!("http://{addr}/hook"),
+            format!("http://localhost:{}/hook", addr.port()),
+        ] {
+            let webhook = Webhook {
+                id: 1,
+                url,
+                secret: "secret".to_string(),
+            };
+            assert!(
+                !deliver_with_retries(&client, &settings, &webhook, "geofence.enter", "{}").await
+            );
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn should_deliver_signed_events_with_retries() {
+        let (addr, count) = spawn_receiver(2, "secret");
+        let settings = WebhookSettings {
+            max_attempts: 3,
+            initial_backoff_secs: 0,
+            timeout_secs: 5,
+            allow_private_targets: true,
+        };
+        let webhook = Webhook {
+            id: 1,
+            url: format!("http://{addr}/hook"),
+            secret: "secret".to_string(),
+        };
+        let body = r#"{"event":"geofence.enter","data":{}}"#;
+        let client = reqwest::Client::new();
+        assert!(deliver_with_retries(&client, &settings, &webhook, "geofence.enter", body).await);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn should_give_up_after_max_attempts() {
+        let (addr, count) = spawn_receiver(10, "secret");
+        let settings = WebhookSettings {
+            max_attempts: 2,
+            initial_backoff_secs: 0,
+            timeout_secs: 5,
+            allow_private_targets: true,
+        };
+        let webhook = Webhook {
+            id: 1,
+            url: format!("http://{addr}/hook"),
+            secret: "secret".to_string(),
+        };
+        let client = reqwest::Client::new();
+        assert!(!deliver_with_retries(&client, &settings, &webhook, "geofence.enter", "{}").await);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+}
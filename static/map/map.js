@@ -14,19 +14,48 @@ let dateStart = moment().startOf('day');
 let dateEnd = dateStart.clone().add(1, "days");
 let overlayLayer = null;
 let available_dates = null;
+// Days with fewer points than this are flagged as sparse in the calendar.
+const SPARSE_DAY_POINTS = 20;
 
 async function fetchAvailableDates() {
-    const response = await fetch(baseUrl + "/api/available");
+    const response = await fetch(baseUrl + "/api/available?details=true");
     const content = await response.json();
-    let available_dates = content.map((strDate) => moment(strDate));
-    available_dates.sort((moment1, moment2) => moment1 - moment2);
+    let available_dates = content.map((summary) => {
+        summary.moment = moment(summary.day);
+        return summary;
+    });
+    available_dates.sort((day1, day2) => day1.moment - day2.moment);
     return available_dates;
 }
 
+function daySummaryText(summary) {
+    let text = summary.points + " points, " + (summary.distance / 1000).toFixed(1) + " km";
+    text += "\n" + moment.utc(summary.first).format("HH:mm") + " - " + moment.utc(summary.last).format("HH:mm");
+    if (summary.devices.length > 0) {
+        text += "\n" + summary.devices.join(", ");
+    }
+    if (summary.points < SPARSE_DAY_POINTS) {
+        text += "\nFew points recorded on this day";
+    }
+    return text;
+}
+
+function updateSparseWarning(dateStart, dateEnd) {
+    let warning = document.getElementById("sparse-warning");
+    if (!warning || !available_dates) {
+        return;
+    }
+    let sparse = available_dates.filter((summary) => summary.moment.isBetween(dateStart, dateEnd, "day", "[)") &&
+        summary.points < SPARSE_DAY_POINTS);
+    warning.innerText = sparse.length > 0 ?
+        "Few points on " + sparse.map((summary) => summary.day).join(", ") : "";
+}
+
 
 fetchAvailableDates().then((val) => {
     available_dates = val;
     colorCalendar(available_dates);
+    updateSparseWarning(dateStart, dateEnd);
 });
 
 
@@ -129,6 +158,58 @@ L.control.commandNext = function(options) {
 };
 L.control.commandNext({}).addTo(map);
 
+// Follow mode: new points are streamed from the server and the map is
+// centered on the last one.
+let liveSource = null;
+let liveLayer = null;
+
+function toggleFollow(controlUI) {
+    if (liveSource) {
+        liveSource.close();
+        liveSource = null;
+        controlUI.innerHTML = "Follow";
+        return;
+    }
+    if (!liveLayer) {
+        liveLayer = L.featureGroup().addTo(map);
+    }
+    liveSource = new EventSource(baseUrl + "/api/live");
+    liveSource.addEventListener("point", (event) => {
+        let point = JSON.parse(event.data);
+        let latLng = [point.coordinates[1], point.coordinates[0]];
+        L.circleMarker(latLng, {
+            radius: 4,
+            fillColor: "rgb(0, 200, 120)",
+            weight: 0,
+            fillOpacity: 0.8,
+        }).bindPopup("<p><b>" + point.device + "</b></p><p>" + moment(point.time).format() + "</p>").addTo(liveLayer);
+        map.panTo(latLng);
+    });
+    liveSource.onerror = () => console.error("Live stream interrupted, reconnecting.");
+    controlUI.innerHTML = "Stop following";
+}
+
+L.Control.Follow = L.Control.extend({
+    options: {
+        position: 'topleft',
+    },
+
+    onAdd: function(map) {
+        var controlDiv = L.DomUtil.create('div', 'leaflet-control-command');
+        var controlUI = L.DomUtil.create('div', 'leaflet-control-command-interior', controlDiv);
+        controlUI.innerHTML = "Follow";
+        L.DomEvent
+            .addListener(controlDiv, 'click', L.DomEvent.stopPropagation)
+            .addListener(controlDiv, 'click', L.DomEvent.preventDefault)
+            .addListener(controlDiv, 'click', () => toggleFollow(controlUI));
+        return controlDiv;
+    }
+});
+L.control.follow = function(options) {
+    return new L.Control.Follow(options);
+};
+L.control.follow({}).addTo(map);
+
 L.Control.DatePicker = L.Control.extend({
     options: {
         position: 'topleft',
@@ -146,6 +227,7 @@ L.Control.DatePicker = L.Control.extend({
             <center>
               <input type="text" name="daterange-picker" class="form-control">
             </center>
+            <p id="sparse-warning" style="color: darkorange;"></p>
           </div>`;
 
         return controlDiv;
@@ -192,17 +274,27 @@ function colorCalendar(dates) {
     let current_month_days = getDatePickerMonthAndDates(current_month);
     let next_month_days = getDatePickerMonthAndDates(next_month);
 
-    // available and days_moment are two lists of moments assumed to be sorted.
-    if (!(current_month_days instanceof Error || next_month_days instanceof Error)) {
+    // dates and days_moment are two lists assumed to be sorted by day.
+    if (!(current_month_days instanceof Error || next_month_days instanceof Error) && dates) {
         const days_moment = current_month_days.concat(next_month_days);
-        var index_avail = available_dates.length - 1;
+        const max_points = Math.max(...dates.map((summary) => summary.points));
+        var index_avail = dates.length - 1;
         var index_picker = days_moment.length - 1;
         while (index_avail >= 0 && index_picker >= 0) {
-            if (isSameDay(days_moment[index_picker].moment_day, available_dates[index_avail])) {
-                days_moment[index_picker].dom_day.style.backgroundColor = "red";
+            let summary = dates[index_avail];
+            if (isSameDay(days_moment[index_picker].moment_day, summary.moment)) {
+                let dom_day = days_moment[index_picker].dom_day;
+                if (summary.points < SPARSE_DAY_POINTS) {
+                    dom_day.style.backgroundColor = "orange";
+                } else {
+                    // Busier days are more opaque.
+                    let alpha = 0.3 + 0.7 * Math.log(summary.points) / Math.log(max_points);
+                    dom_day.style.backgroundColor = "rgba(255, 0, 0, " + alpha + ")";
+                }
+                dom_day.title = daySummaryText(summary);
                 index_picker--;
                 index_avail--;
-            } else if (days_moment[index_picker].moment_day.isAfter(available_dates[index_avail])) {
+            } else if (days_moment[index_picker].moment_day.isAfter(summary.moment)) {
                 index_picker--;
             } else {
                 index_avail--;
@@ -324,6 +416,7 @@ function updateData(dateStart, dateEnd) {
     if (overlayLayer) {
         map.removeLayer(overlayLayer);
     }
+    updateSparseWarning(dateStart, dateEnd);
     fetchDataJSON(dateStart, dateEnd)
         .then(drawGeoJSON)
         .catch(err => console.error(`Error fetching data: ${err.message}`));
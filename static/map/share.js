@@ -0,0 +1,80 @@
+var getUrl = window.location;
+var baseUrl = getUrl.protocol + "//" + getUrl.host;
+var mapDiv = document.getElementById('map');
+var token = mapDiv.dataset.token;
+var map = L.map('map').setView([39.74739, -105], 13);
+// Live links are refreshed every 30 seconds.
+const REFRESH_MS = 30000;
+
+L.tileLayer('https://tile.openstreetmap.org/{z}/{x}/{y}.png', {
+    maxZoom: 18,
+    attribution: 'Map data &copy; <a href="https://www.openstreetmap.org/copyright">OpenStreetMap</a> contributors',
+}).addTo(map);
+
+let overlayLayer = null;
+let fitted = false;
+
+L.Control.Expiry = L.Control.extend({
+    options: {
+        position: 'topright',
+    },
+    onAdd: function(map) {
+        var controlDiv = L.DomUtil.create('div');
+        controlDiv.id = "share-expiry";
+        controlDiv.style.backgroundColor = "white";
+        controlDiv.style.padding = "3px";
+        return controlDiv;
+    }
+});
+new L.Control.Expiry({}).addTo(map);
+
+function drawFeed(feed) {
+    if (overlayLayer) {
+        map.removeLayer(overlayLayer);
+    }
+    document.getElementById("share-expiry").innerText = "Link valid until " + moment(feed.expires_at).format("LLL");
+    let coords = feed.points.map((point) => [point.coordinates[1], point.coordinates[0]]);
+    if (coords.length == 0) {
+        return;
+    }
+    let last = feed.points[feed.points.length - 1];
+    overlayLayer = L.featureGroup([
+        L.polyline(coords, {
+            color: "rgb(243, 102, 102)",
+            weight: 3
+        }),
+        L.circleMarker(coords[coords.length - 1], {
+            radius: 6,
+            fillColor: "rgb(0, 200, 120)",
+            weight: 0,
+            fillOpacity: 0.9,
+        }).bindPopup("<p>" + moment(last.time).format("LLL") + "</p>"),
+    ]).addTo(map);
+    if (feed.scope == "live" && fitted) {
+        map.panTo(coords[coords.length - 1]);
+    } else {
+        map.fitBounds(overlayLayer.getBounds());
+        fitted = true;
+    }
+}
+
+async function updateFeed() {
+    const response = await fetch(baseUrl + "/share/" + token + "/feed");
+    if (!response.ok) {
+        document.getElementById("share-expiry").innerText = "This link has expired.";
+        return null;
+    }
+    const feed = await response.json();
+    drawFeed(feed);
+    return feed;
+}
+
+updateFeed().then((feed) => {
+    if (feed && feed.scope == "live") {
+        let timer = setInterval(() => updateFeed().then((feed) => {
+            if (!feed) {
+                clearInterval(timer);
+            }
+        }), REFRESH_MS);
+    }
+}).catch(err => console.error(`Error fetching data: ${err.message}`));